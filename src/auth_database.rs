@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use crate::map_database::{DBError, WorldDatabase, verify_columns};
+
+
+fn verify_database(conn: &sqlite::Connection) -> Result<(), DBError> {
+	verify_columns(conn, "auth", &["id", "name", "last_login"])?;
+	verify_columns(conn, "user_privileges", &["id", "privilege"])?;
+	Ok(())
+}
+
+
+/// A player account.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+	pub id: i64,
+	pub name: String,
+	/// Unix timestamp of the player's last login, or -1 if unknown.
+	pub last_login: i64,
+	/// Names of the player's privileges, sorted.
+	pub privs: Vec<String>,
+}
+
+
+/// A Minetest authentication database (auth.sqlite), as used by the
+/// `sqlite3` auth backend.
+pub struct AuthDatabase<'a> {
+	db: WorldDatabase<'a>,
+}
+
+impl<'a> AuthDatabase<'a> {
+	pub fn new(conn: &'a sqlite::Connection) -> Result<Self, DBError> {
+		Ok(Self {db: WorldDatabase::new(conn, verify_database)?})
+	}
+
+	pub fn is_in_transaction(&self) -> bool {
+		self.db.is_in_transaction()
+	}
+
+	pub fn commit_if_needed(&mut self) -> Result<(), DBError> {
+		self.db.commit_if_needed()
+	}
+
+	/// Get all accounts, sorted by name.
+	pub fn get_accounts(&mut self) -> Result<Vec<Account>, DBError> {
+		let conn = self.db.conn()?;
+		let mut privs: HashMap<i64, Vec<String>> = HashMap::new();
+		let mut stmt = conn.prepare(
+			"SELECT id, privilege FROM user_privileges ORDER BY privilege")?;
+		while let sqlite::State::Row = stmt.next()? {
+			privs.entry(stmt.read(0)?).or_default().push(stmt.read(1)?);
+		}
+
+		let mut stmt = conn.prepare(
+			"SELECT id, name, last_login FROM auth ORDER BY name")?;
+		let mut accounts = Vec::new();
+		while let sqlite::State::Row = stmt.next()? {
+			let id = stmt.read(0)?;
+			accounts.push(Account {
+				id,
+				name: stmt.read(1)?,
+				last_login: stmt.read::<Option<i64>>(2)?.unwrap_or(-1),
+				privs: privs.remove(&id).unwrap_or_default(),
+			});
+		}
+		Ok(accounts)
+	}
+
+	/// Get the ID of the account named `name`, if it exists.
+	pub fn get_id(&mut self, name: &str) -> Result<Option<i64>, DBError> {
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare("SELECT id FROM auth WHERE name = ?")?;
+		stmt.bind(1, name)?;
+		Ok(match stmt.next()? {
+			sqlite::State::Row => Some(stmt.read(0)?),
+			sqlite::State::Done => None
+		})
+	}
+
+	/// Delete an account and its privileges.
+	pub fn delete_account(&mut self, id: i64) -> Result<(), DBError> {
+		let conn = self.db.conn()?;
+		for query in &["DELETE FROM user_privileges WHERE id = ?",
+			"DELETE FROM auth WHERE id = ?"]
+		{
+			let mut stmt = conn.prepare(query)?;
+			stmt.bind(1, id)?;
+			stmt.next()?;
+		}
+		Ok(())
+	}
+
+	/// Give an account a privilege. Returns `false` if the account already
+	/// had it.
+	pub fn add_privilege(&mut self, id: i64, privilege: &str)
+		-> Result<bool, DBError>
+	{
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare(
+			"INSERT OR IGNORE INTO user_privileges (id, privilege) \
+			VALUES (?, ?)")?;
+		stmt.bind(1, id)?;
+		stmt.bind(2, privilege)?;
+		stmt.next()?;
+		Ok(conn.change_count() > 0)
+	}
+
+	/// Take a privilege from an account. Returns `false` if the account
+	/// didn't have it.
+	pub fn remove_privilege(&mut self, id: i64, privilege: &str)
+		-> Result<bool, DBError>
+	{
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare(
+			"DELETE FROM user_privileges WHERE id = ? AND privilege = ?")?;
+		stmt.bind(1, id)?;
+		stmt.bind(2, privilege)?;
+		stmt.next()?;
+		Ok(conn.change_count() > 0)
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_auth_database() {
+		let conn = sqlite::open(":memory:").unwrap();
+		conn.execute("CREATE TABLE auth (\
+				id INTEGER PRIMARY KEY AUTOINCREMENT, name VARCHAR(32) UNIQUE, \
+				password VARCHAR(512), last_login INTEGER);
+			CREATE TABLE user_privileges (id INTEGER, privilege VARCHAR(32), \
+				PRIMARY KEY (id, privilege));
+			INSERT INTO auth VALUES \
+				(1, 'bob', '#1#salt#verifier', 1600000000), \
+				(2, 'alice', '', NULL);
+			INSERT INTO user_privileges VALUES (1, 'shout'), (1, 'interact'), \
+				(2, 'fly');")
+			.unwrap();
+		let mut db = AuthDatabase::new(&conn).unwrap();
+
+		assert_eq!(db.get_accounts().unwrap(), [
+			Account {
+				id: 2,
+				name: "alice".to_string(),
+				last_login: -1,
+				privs: vec!["fly".to_string()],
+			},
+			Account {
+				id: 1,
+				name: "bob".to_string(),
+				last_login: 1_600_000_000,
+				privs: vec!["interact".to_string(), "shout".to_string()],
+			},
+		]);
+		assert_eq!(db.get_id("bob").unwrap(), Some(1));
+		assert_eq!(db.get_id("carol").unwrap(), None);
+
+		assert!(db.add_privilege(1, "fly").unwrap());
+		assert!(!db.add_privilege(1, "fly").unwrap());
+		assert!(db.remove_privilege(1, "shout").unwrap());
+		assert!(!db.remove_privilege(1, "shout").unwrap());
+		db.delete_account(2).unwrap();
+		db.commit_if_needed().unwrap();
+
+		let accounts = db.get_accounts().unwrap();
+		assert_eq!(accounts.len(), 1);
+		assert_eq!(accounts[0].privs, ["fly", "interact"]);
+		let mut stmt = conn.prepare("SELECT COUNT(*) FROM user_privileges")
+			.unwrap();
+		stmt.next().unwrap();
+		assert_eq!(stmt.read::<i64>(0).unwrap(), 2);
+	}
+}
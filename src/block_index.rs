@@ -0,0 +1,162 @@
+use crate::map_block::read_node_names;
+use crate::map_database::{DBError, WorldDatabase};
+
+
+/// 64-bit FNV-1a hash, used to tell whether a mapblock has changed since it
+/// was indexed.
+fn hash_block(data: &[u8]) -> u64 {
+	const OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+	const PRIME: u64 = 0x0000_0100_0000_01B3;
+
+	data.iter().fold(OFFSET_BASIS,
+		|hash, &b| (hash ^ b as u64).wrapping_mul(PRIME))
+}
+
+
+fn create_tables(conn: &sqlite::Connection) -> Result<(), DBError> {
+	conn.execute("CREATE TABLE IF NOT EXISTS blocks (\
+		pos INTEGER PRIMARY KEY, hash INTEGER NOT NULL, names BLOB NOT NULL)")?;
+	Ok(())
+}
+
+
+/// A persistent index of the node names in each mapblock, stored in a
+/// separate database next to the map.
+///
+/// Version 29 mapblocks are compressed as a whole, so their node names can't
+/// be searched for without decompressing them. Each entry stores the names
+/// along with a hash of the mapblock's raw data, so a mapblock which hasn't
+/// changed since it was indexed can be skipped without decompressing it. If
+/// the hash doesn't match, the entry is stale and is replaced.
+pub struct BlockIndex<'a> {
+	db: WorldDatabase<'a>,
+	stmt_get: sqlite::Statement<'a>,
+	stmt_set: sqlite::Statement<'a>,
+}
+
+impl<'a> BlockIndex<'a> {
+	/// Open an index, creating its table if needed.
+	pub fn new(conn: &'a sqlite::Connection) -> Result<Self, DBError> {
+		let db = WorldDatabase::new(conn, create_tables)?;
+		let stmt_get = conn.prepare(
+			"SELECT hash, names FROM blocks WHERE pos = ?")?;
+		let stmt_set = conn.prepare("INSERT OR REPLACE INTO blocks \
+			(pos, hash, names) VALUES (?, ?, ?)")?;
+		Ok(Self {db, stmt_get, stmt_set})
+	}
+
+	fn get_entry(&mut self, key: i64)
+		-> Result<Option<(u64, Vec<u8>)>, DBError>
+	{
+		self.db.conn()?;
+		self.stmt_get.bind(1, key)?;
+		let entry = match self.stmt_get.next()? {
+			sqlite::State::Row => Some((
+				self.stmt_get.read::<i64>(0)? as u64,
+				self.stmt_get.read::<Vec<u8>>(1)?
+			)),
+			sqlite::State::Done => None
+		};
+		self.stmt_get.reset()?;
+		Ok(entry)
+	}
+
+	fn set_entry(&mut self, key: i64, hash: u64, names: &[u8])
+		-> Result<(), DBError>
+	{
+		self.db.conn()?;
+		self.stmt_set.bind(1, key)?;
+		self.stmt_set.bind(2, hash as i64)?;
+		self.stmt_set.bind(3, names)?;
+		self.stmt_set.next()?;
+		self.stmt_set.reset()?;
+		Ok(())
+	}
+
+	/// Get the names of all nodes in the mapblock at `key` with the raw data
+	/// `data`, indexing it if it has no up-to-date entry. Returns `None` if
+	/// the mapblock can't be indexed, e.g. if it is older than version 29.
+	pub fn get_node_names(&mut self, key: i64, data: &[u8])
+		-> Result<Option<Vec<Vec<u8>>>, DBError>
+	{
+		if data.first() != Some(&29) {
+			return Ok(None);
+		}
+		let hash = hash_block(data);
+		if let Some((old_hash, joined)) = self.get_entry(key)? {
+			if old_hash == hash {
+				return Ok(Some(split_names(&joined)));
+			}
+		}
+
+		let names = match read_node_names(data) {
+			Ok(n) => n,
+			Err(_) => return Ok(None)
+		};
+		self.set_entry(key, hash, &names.join(&b'\n'))?;
+		Ok(Some(names))
+	}
+
+	pub fn commit_if_needed(&mut self) -> Result<(), DBError> {
+		self.db.commit_if_needed()
+	}
+}
+
+
+fn split_names(joined: &[u8]) -> Vec<Vec<u8>> {
+	if joined.is_empty() {
+		Vec::new()
+	} else {
+		joined.split(|&b| b == b'\n').map(|n| n.to_vec()).collect()
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::map_block::{MapBlock, CompressionLevel};
+	use crate::utils::read_test_file;
+
+	#[test]
+	fn test_block_index() {
+		let v29 = read_test_file("mapblock_v29.bin");
+		let v28 = read_test_file("mapblock_v28.bin");
+		let names = |n: &[&[u8]]| n.iter().map(|n| n.to_vec())
+			.collect::<Vec<_>>();
+		let expected = names(&[b"test_mod:metadata", b"air",
+			b"test_mod:timer"]);
+
+		let conn = sqlite::open(":memory:").unwrap();
+		let mut index = BlockIndex::new(&conn).unwrap();
+
+		assert_eq!(index.get_node_names(0, &v29).unwrap(),
+			Some(expected.clone()));
+		assert_eq!(index.get_entry(0).unwrap().unwrap().0, hash_block(&v29));
+		// Up-to-date entries are used as they are.
+		index.set_entry(0, hash_block(&v29), b"test_mod:cached").unwrap();
+		assert_eq!(index.get_node_names(0, &v29).unwrap(),
+			Some(names(&[b"test_mod:cached"])));
+
+		// Stale entries are replaced when the mapblock has changed.
+		let mut block = MapBlock::deserialize(&v29).unwrap();
+		let id = block.nimap.get_id(b"test_mod:metadata").unwrap();
+		block.nimap.0.insert(id, b"test_mod:changed".to_vec());
+		let changed = block.serialize(CompressionLevel::Default);
+		let new_names: Vec<_> = block.nimap.0.values().cloned().collect();
+		assert!(new_names.contains(&b"test_mod:changed".to_vec()));
+		assert_eq!(index.get_node_names(0, &changed).unwrap(),
+			Some(new_names.clone()));
+		assert_eq!(index.get_entry(0).unwrap().unwrap(),
+			(hash_block(&changed), new_names.join(&b'\n')));
+
+		// Older mapblocks aren't indexed.
+		assert_eq!(index.get_node_names(1, &v28).unwrap(), None);
+		assert_eq!(index.get_entry(1).unwrap(), None);
+
+		index.commit_if_needed().unwrap();
+		let mut index = BlockIndex::new(&conn).unwrap();
+		assert_eq!(index.get_entry(0).unwrap().unwrap().0,
+			hash_block(&changed));
+	}
+}
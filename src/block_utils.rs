@@ -139,3 +139,17 @@ pub fn clean_name_id_map(block: &mut MapBlock) {
 		*id = map[*id as usize];
 	}
 }
+
+
+/// Returns `true` if every node in the mapblock has one of the given names.
+pub fn block_contains_only(block: &MapBlock, names: &[Vec<u8>]) -> bool {
+	let id_count = block.nimap.get_max_id().map_or(0, |id| id as usize + 1);
+
+	let mut allowed = vec![false; id_count];
+	for (&id, name) in &block.nimap.0 {
+		allowed[id as usize] = names.contains(name);
+	}
+
+	block.node_data.nodes.iter()
+		.all(|&id| allowed.get(id as usize).copied().unwrap_or(false))
+}
@@ -1,13 +1,22 @@
+use std::collections::BTreeMap;
 use std::io::prelude::*;
+use std::sync::mpsc;
 use std::time::{Duration, Instant};
 
 use clap::{App, Arg, SubCommand, AppSettings, crate_version, crate_authors};
 use anyhow::Context;
+use serde_json::json;
 
-use crate::spatial::{Vec3, Area};
-use crate::instance::{LogType, ArgType, InstArgs};
-use crate::commands::{get_commands};
-use crate::utils::fmt_duration;
+use mapeditr::spatial::{Vec3, Area};
+use mapeditr::map_block::CompressionLevel;
+use mapeditr::instance::{LogType, ArgType, InstArgs, InstState, ServerEvent,
+	StatusClient, spawn_compute_thread};
+use mapeditr::commands::{get_commands, COMMAND_GROUPS};
+use mapeditr::utils::{fmt_duration, parse_date};
+
+
+const TICK: Duration = Duration::from_millis(25);
+const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
 
 
 fn arg_to_pos(p: clap::Values) -> anyhow::Result<Vec3> {
@@ -101,36 +110,178 @@ fn to_cmd_line_args<'a>(tup: &(ArgType, &'a str))
 		ArgType::DeleteMeta =>
 			Arg::with_name("delete_meta")
 				.long("deletemeta"),
-		ArgType::Key =>
-			Arg::with_name("key")
-				.takes_value(true)
-				.required(true),
+		ArgType::Key(req) => {
+			let a = Arg::with_name("key").takes_value(true);
+			if req {
+				a.required(true)
+			} else {
+				a.long("key")
+			}
+		},
+		ArgType::NewKey =>
+			Arg::with_name("new_key")
+				.long("newkey")
+				.takes_value(true),
 		ArgType::Value =>
 			Arg::with_name("value")
 				.takes_value(true),
 		ArgType::Param2 =>
 			Arg::with_name("param2")
 				.required(true),
+		ArgType::Timestamp =>
+			Arg::with_name("timestamp")
+				.takes_value(true)
+				.required(true),
+		ArgType::AllowMeta =>
+			Arg::with_name("allow_meta")
+				.long("allowmeta"),
+		ArgType::List =>
+			Arg::with_name("list")
+				.long("list"),
+		ArgType::BlockVersion =>
+			Arg::with_name("block_version")
+				.takes_value(true)
+				.required(true),
+		ArgType::LuaScript =>
+			Arg::with_name("lua_script")
+				.takes_value(true)
+				.required(true),
+		ArgType::Count =>
+			Arg::with_name("count")
+				.long("count")
+				.takes_value(true)
+				.value_name("count"),
+		ArgType::MaxCount =>
+			Arg::with_name("max_count")
+				.long("maxcount")
+				.takes_value(true)
+				.value_name("count"),
+		ArgType::Wear =>
+			Arg::with_name("wear")
+				.long("wear")
+				.takes_value(true),
+		ArgType::ListName =>
+			Arg::with_name("list_name")
+				.takes_value(true)
+				.required(true),
+		ArgType::Size =>
+			Arg::with_name("size")
+				.long("size")
+				.takes_value(true),
+		ArgType::NewName =>
+			Arg::with_name("new_name")
+				.long("newname")
+				.takes_value(true),
+		ArgType::Overflow =>
+			Arg::with_name("overflow")
+				.long("overflow")
+				.takes_value(true)
+				.value_name("list"),
+		ArgType::JsonFile =>
+			Arg::with_name("json_file")
+				.takes_value(true)
+				.required(true),
+		ArgType::Pattern =>
+			Arg::with_name("pattern")
+				.takes_value(true)
+				.required(true),
+		ArgType::Replacement =>
+			Arg::with_name("replacement")
+				.takes_value(true)
+				.required(true),
+		ArgType::Regex =>
+			Arg::with_name("regex")
+				.long("regex"),
+		ArgType::Player =>
+			Arg::with_name("player")
+				.takes_value(true)
+				.required(true),
+		ArgType::NewPlayer =>
+			Arg::with_name("new_player")
+				.takes_value(true)
+				.required(true),
+		ArgType::Players =>
+			Arg::with_name("players")
+				.long("players")
+				.min_values(1),
+		ArgType::Pos =>
+			Arg::with_name("pos")
+				.allow_hyphen_values(true)
+				.number_of_values(3)
+				.value_names(&["x", "y", "z"])
+				.required(true),
+		ArgType::Before(req) =>
+			Arg::with_name("before")
+				.long("before")
+				.takes_value(true)
+				.value_name("date")
+				.required(req),
+		ArgType::IncludeUnknown =>
+			Arg::with_name("include_unknown")
+				.long("unknown"),
+		ArgType::Privs =>
+			Arg::with_name("privs")
+				.min_values(1)
+				.required(true),
+		ArgType::ModName(req) => {
+			let a = Arg::with_name("mod_name");
+			if req {
+				a.required(true)
+			} else {
+				a.long("mod").takes_value(true).value_name("mod")
+			}
+		},
+		ArgType::NewModName =>
+			Arg::with_name("new_mod_name")
+				.takes_value(true)
+				.required(true),
 	}.help(help_msg);
 
 	vec![arg]
 }
 
 
-fn parse_cmd_line_args() -> anyhow::Result<InstArgs> {
-	/* Create the clap app */
+fn get_app() -> App<'static, 'static> {
 	let commands = get_commands();
 
-	let app_commands = commands.iter().map(|(cmd_name, cmd)| {
+	let mut app_commands = Vec::new();
+	let mut groups: BTreeMap<&str, Vec<App>> = BTreeMap::new();
+
+	for (cmd_name, cmd) in &commands {
 		let args: Vec<_> = cmd.args.iter().flat_map(to_cmd_line_args)
 			.collect();
-		SubCommand::with_name(cmd_name)
+		// Grouped commands, e.g. "players list", are nested subcommands.
+		let (group, name) = match cmd_name.split_once(' ') {
+			Some((g, n)) => (Some(g), n),
+			None => (None, *cmd_name)
+		};
+		let sub = SubCommand::with_name(name)
 			.about(cmd.help)
 			.args(&args)
-			.after_help("For additional information, see the manual.")
-	});
+			.setting(AppSettings::AllowNegativeNumbers)
+			.after_help("For additional information, see the manual.");
+		match group {
+			Some(g) => groups.entry(g).or_default().push(sub),
+			None => app_commands.push(sub)
+		}
+	}
 
-	let app = App::new("MapEditr")
+	for &(group, help) in COMMAND_GROUPS {
+		app_commands.push(SubCommand::with_name(group)
+			.about(help)
+			.setting(AppSettings::SubcommandRequired)
+			.subcommands(groups.remove(group).unwrap_or_default()));
+	}
+
+	let run_command = SubCommand::with_name("run")
+		.about("Run a script of commands in a single transaction.")
+		.arg(Arg::with_name("script")
+			.required(true)
+			.help("Path to a text file containing one command per line")
+		)
+		.after_help("For additional information, see the manual.");
+
+	App::new("MapEditr")
 		.about("Edits Minetest worlds/map databases.")
 		.after_help(
 			"For command-specific help, run: mapeditr <SUBCOMMAND> -h\n\
@@ -143,17 +294,128 @@ fn parse_cmd_line_args() -> anyhow::Result<InstArgs> {
 			.global(true)
 			.help("Skip the default confirmation prompt.")
 		)
+		.arg(Arg::with_name("compression")
+			.long("compression")
+			.global(true)
+			.takes_value(true)
+			.possible_values(&["fast", "default", "best"])
+			.help("Compression level to use for modified mapblocks.")
+		)
+		.arg(Arg::with_name("threads")
+			.long("threads")
+			.global(true)
+			.takes_value(true)
+			.help("Number of threads to process mapblocks with. Defaults to \
+				the number of CPU cores.")
+		)
+		.arg(Arg::with_name("index")
+			.long("index")
+			.global(true)
+			.help("Use a node name index to speed up repeated commands that \
+				search for certain nodes.")
+		)
+		.arg(Arg::with_name("json")
+			.long("json")
+			.global(true)
+			.help("Print progress and log messages as JSON objects, one per \
+				line.")
+		)
 		.arg(Arg::with_name("map")
 			.required(true)
 			.help("Path to world directory or map database to edit")
 		)
 		.setting(AppSettings::SubcommandRequired)
-		.subcommands(app_commands);
+		.subcommands(app_commands)
+		.subcommand(run_command)
+}
+
+
+/// Split a line into arguments like a shell would. Arguments are separated by
+/// whitespace, and can be quoted with single or double quotes. Outside of
+/// single quotes, a backslash escapes the next character.
+fn split_line(line: &str) -> anyhow::Result<Vec<String>> {
+	let mut args = Vec::new();
+	let mut cur: Option<String> = None;
+	let mut quote = None;
+	let mut chars = line.chars();
+
+	while let Some(c) = chars.next() {
+		match (quote, c) {
+			(Some(q), _) if c == q => quote = None,
+			(Some('\''), _) => cur.get_or_insert_with(String::new).push(c),
+			(_, '\\') => {
+				let escaped = chars.next()
+					.context("Line ends with a backslash.")?;
+				cur.get_or_insert_with(String::new).push(escaped);
+			},
+			(Some(_), _) => cur.get_or_insert_with(String::new).push(c),
+			(None, '\'') | (None, '"') => {
+				cur.get_or_insert_with(String::new);
+				quote = Some(c);
+			},
+			(None, _) if c.is_whitespace() => args.extend(cur.take()),
+			(None, _) => cur.get_or_insert_with(String::new).push(c),
+		}
+	}
+
+	anyhow::ensure!(quote.is_none(), "Unterminated quote.");
+	args.extend(cur);
+	Ok(args)
+}
+
+
+/// Parse each command in a script file, as if it were given on the command
+/// line after the map path.
+fn parse_script(path: &str, map_path: &str)
+	-> anyhow::Result<Vec<(usize, InstArgs)>>
+{
+	let text = std::fs::read_to_string(path)
+		.context("Failed to read script file.")?;
+	let mut script = Vec::new();
+
+	for (i, line) in text.lines().enumerate() {
+		let line_num = i + 1;
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let with_line = |e: anyhow::Error|
+			anyhow::anyhow!("Line {}: {}", line_num, e);
+
+		let tokens = split_line(line).map_err(with_line)?;
+		if let "run" | "vacuum" = tokens[0].as_str() {
+			return Err(with_line(anyhow::anyhow!(
+				"{} cannot be used in a script.", tokens[0])));
+		}
+
+		let mut argv = vec!["mapeditr".to_string(), map_path.to_string()];
+		argv.extend(tokens);
+		let matches = get_app().setting(AppSettings::ColorNever)
+			.get_matches_from_safe(argv)
+			.map_err(|e| {
+				let msg = e.message.lines().next().unwrap_or_default();
+				with_line(anyhow::anyhow!(
+					msg.trim_start_matches("error: ").to_string()))
+			})?;
+		let args = parse_cmd_line_args(&matches).map_err(with_line)?;
+		script.push((line_num, args));
+	}
 
-	/* Parse the arguments */
-	let matches = app.get_matches();
-	let sub_name = matches.subcommand_name().unwrap().to_string();
-	let sub_matches = matches.subcommand_matches(&sub_name).unwrap();
+	anyhow::ensure!(!script.is_empty(), "Script contains no commands.");
+	Ok(script)
+}
+
+
+fn parse_cmd_line_args(matches: &clap::ArgMatches)
+	-> anyhow::Result<InstArgs>
+{
+	let mut sub_name = matches.subcommand_name().unwrap().to_string();
+	let mut sub_matches = matches.subcommand_matches(&sub_name).unwrap();
+	if let (name, Some(group_matches)) = sub_matches.subcommand() {
+		// Command within a group.
+		sub_name = format!("{} {}", sub_name, name);
+		sub_matches = group_matches;
+	}
 
 	Ok(InstArgs {
 		do_confirmation: !matches.is_present("yes"),
@@ -186,9 +448,65 @@ fn parse_cmd_line_args() -> anyhow::Result<InstArgs> {
 		delete: sub_matches.is_present("delete"),
 		delete_meta: sub_matches.is_present("delete_meta"),
 		key: sub_matches.value_of("key").map(str::to_string),
+		new_key: sub_matches.value_of("new_key").map(str::to_string),
 		value: sub_matches.value_of("value").map(str::to_string),
 		param2: sub_matches.value_of("param2_val").map(|val| val.parse())
 			.transpose().context("Invalid param2 value.")?,
+		timestamp: sub_matches.value_of("timestamp").map(|val| val.parse())
+			.transpose().context("Invalid timestamp value.")?,
+		allow_meta: sub_matches.is_present("allow_meta"),
+		list: sub_matches.is_present("list"),
+		block_version: sub_matches.value_of("block_version")
+			.map(|val| val.parse()).transpose()
+			.context("Invalid mapblock version.")?,
+		lua_script: sub_matches.value_of("lua_script").map(str::to_string),
+		count: sub_matches.value_of("count").map(|val| val.parse())
+			.transpose().context("Invalid item count.")?,
+		max_count: sub_matches.value_of("max_count").map(|val| val.parse())
+			.transpose().context("Invalid maximum item count.")?,
+		wear: sub_matches.value_of("wear").map(|val| val.parse())
+			.transpose().context("Invalid wear value.")?,
+		list_name: sub_matches.value_of("list_name").map(str::to_string),
+		size: sub_matches.value_of("size").map(|val| val.parse())
+			.transpose().context("Invalid list size.")?,
+		new_name: sub_matches.value_of("new_name").map(str::to_string),
+		overflow: sub_matches.value_of("overflow").map(str::to_string),
+		json_file: sub_matches.value_of("json_file").map(str::to_string),
+		pattern: sub_matches.value_of("pattern").map(str::to_string),
+		replacement: sub_matches.value_of("replacement").map(str::to_string),
+		regex: sub_matches.is_present("regex"),
+		player: sub_matches.value_of("player").map(str::to_string),
+		new_player: sub_matches.value_of("new_player").map(str::to_string),
+		players: sub_matches.values_of("players").iter_mut().flatten()
+			.map(str::to_string).collect(),
+		pos: sub_matches.values_of("pos").map(arg_to_pos).transpose()
+			.context("Invalid position.")?,
+		before: sub_matches.value_of("before")
+			.map(|val| parse_date(val)
+				.context("Invalid date; expected YYYY-MM-DD."))
+			.transpose()?,
+		include_unknown: sub_matches.is_present("include_unknown"),
+		privs: sub_matches.values_of("privs").iter_mut().flatten()
+			.map(str::to_string).collect(),
+		mod_name: sub_matches.value_of("mod_name").map(str::to_string),
+		new_mod_name: sub_matches.value_of("new_mod_name")
+			.map(str::to_string),
+		compression: match matches.value_of("compression") {
+			Some("fast") => CompressionLevel::Fast,
+			Some("best") => CompressionLevel::Best,
+			_ => CompressionLevel::Default,
+		},
+		threads: match matches.value_of("threads") {
+			Some(val) => val.parse().context("Invalid thread count.")?,
+			None => std::thread::available_parallelism()
+				.map_or(1, |n| n.get()),
+		},
+		use_index: matches.is_present("index"),
+		script: match sub_matches.value_of("script") {
+			Some(path) => parse_script(path,
+				matches.value_of("map").unwrap())?,
+			None => Vec::new()
+		},
 	})
 }
 
@@ -256,22 +574,173 @@ fn get_confirmation() -> bool {
 }
 
 
+fn state_name(state: InstState) -> &'static str {
+	match state {
+		InstState::Ignore => "idle",
+		InstState::Querying => "querying",
+		InstState::Editing => "editing",
+	}
+}
+
+
+fn print_json<W: Write>(out: &mut W, value: serde_json::Value) {
+	writeln!(out, "{}", value).unwrap();
+	out.flush().unwrap();
+}
+
+
+fn print_json_progress<W: Write>(out: &mut W, status: &StatusClient,
+	state: InstState)
+{
+	let s = status.get_status();
+	print_json(out, json!({
+		"type": "progress",
+		"state": state_name(state),
+		"blocks_total": s.blocks_total,
+		"blocks_done": s.blocks_done,
+		"blocks_failed": s.blocks_failed,
+		"show_progress": s.show_progress,
+	}));
+}
+
+
+/// Read a confirmation response of the form `{"confirm": true}` from stdin.
+/// Anything else counts as a refusal.
+fn get_json_confirmation() -> bool {
+	let mut line = String::new();
+	std::io::stdin().read_line(&mut line).unwrap();
+	serde_json::from_str::<serde_json::Value>(&line).ok()
+		.and_then(|v| v.get("confirm").and_then(|c| c.as_bool()))
+		.unwrap_or(false)
+}
+
+
+/// Print an error which occurred before the command could start.
+fn print_json_error<W: Write>(out: &mut W, msg: &str) {
+	print_json(out, json!({
+		"type": "log",
+		"level": LogType::Error.to_string(),
+		"message": msg,
+	}));
+	print_json(out, json!({"type": "finished", "success": false}));
+}
+
+
+/// Like `run_human_loop`, but prints every event as one JSON object per line.
+fn run_json_loop<W: Write>(
+	handle: std::thread::JoinHandle<()>,
+	status: StatusClient,
+	out: &mut W
+) {
+	let mut last_update = Instant::now();
+	let mut cur_state = InstState::Ignore;
+	let mut had_error = false;
+
+	loop {
+		let now = Instant::now();
+
+		match status.receiver().recv_timeout(TICK) {
+			Ok(event) => match event {
+				ServerEvent::Log(log_type, msg) => {
+					if let LogType::Error = log_type {
+						had_error = true;
+					}
+					print_json(out, json!({
+						"type": "log",
+						"level": log_type.to_string(),
+						"message": msg,
+					}));
+				},
+				ServerEvent::NewState(new_state) => {
+					// Report final progress of the previous stage.
+					if cur_state != InstState::Ignore {
+						print_json_progress(out, &status, cur_state);
+					}
+					cur_state = new_state;
+					print_json(out, json!({
+						"type": "state",
+						"state": state_name(new_state),
+					}));
+					last_update = now;
+				},
+				ServerEvent::ConfirmRequest => {
+					print_json(out, json!({"type": "confirm_request"}));
+					status.send_confirmation(get_json_confirmation());
+				},
+			},
+			Err(err) => {
+				if err == mpsc::RecvTimeoutError::Disconnected {
+					break;
+				}
+			}
+		}
+
+		if cur_state != InstState::Ignore
+			&& now >= last_update + UPDATE_INTERVAL
+		{
+			print_json_progress(out, &status, cur_state);
+			last_update = now;
+		}
+	}
+
+	let success = handle.join().is_ok() && !had_error;
+	let s = status.get_status();
+	print_json(out, json!({
+		"type": "finished",
+		"success": success,
+		"blocks_total": s.blocks_total,
+		"blocks_done": s.blocks_done,
+		"blocks_failed": s.blocks_failed,
+	}));
+}
+
+
 pub fn run_cmd_line() {
-	use std::sync::mpsc;
-	use crate::instance::{InstState, ServerEvent, spawn_compute_thread};
+	// The arguments haven't been parsed yet, so look for the flag directly.
+	let json_requested = std::env::args().any(|a| a == "--json");
+	let mut app = get_app();
+	if json_requested {
+		app = app.global_setting(AppSettings::ColorNever);
+	}
 
-	let args = match parse_cmd_line_args() {
+	let matches = match app.get_matches_safe() {
+		Ok(m) => m,
+		Err(e) => {
+			// Help and version info are printed normally, even in JSON mode.
+			let is_info = matches!(e.kind, clap::ErrorKind::HelpDisplayed
+				| clap::ErrorKind::VersionDisplayed);
+			if json_requested && !is_info {
+				let msg = e.message.trim_start_matches("error: ");
+				print_json_error(&mut std::io::stdout(), msg);
+				std::process::exit(1);
+			}
+			e.exit();
+		}
+	};
+	let json = matches.is_present("json");
+
+	let args = match parse_cmd_line_args(&matches) {
 		Ok(a) => a,
 		Err(e) => {
-			print_log(LogType::Error, e.to_string());
+			if json {
+				print_json_error(&mut std::io::stdout(), &e.to_string());
+			} else {
+				print_log(LogType::Error, e.to_string());
+			}
 			return;
 		}
 	};
 	let (handle, status) = spawn_compute_thread(args);
 
-	const TICK: Duration = Duration::from_millis(25);
-	const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+	if json {
+		run_json_loop(handle, status, &mut std::io::stdout());
+	} else {
+		run_human_loop(handle, status);
+	}
+}
+
 
+fn run_human_loop(handle: std::thread::JoinHandle<()>, status: StatusClient) {
 	let mut last_update = Instant::now();
 	let mut querying_start = last_update;
 	let mut editing_start = last_update;
@@ -356,3 +825,99 @@ pub fn run_cmd_line() {
 
 	let _ = handle.join();
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use mapeditr::instance::{Progress, status_link};
+
+	#[test]
+	fn test_split_line() {
+		let pairs: &[(&str, &[&str])] = &[
+			("", &[]),
+			("  fill   air --p1 1 2 3 ",
+				&["fill", "air", "--p1", "1", "2", "3"]),
+			(r#"setmetavar infotext "Hello, world""#,
+				&["setmetavar", "infotext", "Hello, world"]),
+			(r#"a 'b \ "c"' "d \" \\" e\ f"#,
+				&["a", r#"b \ "c""#, r#"d " \"#, "e f"]),
+			(r#"a "" ''"#, &["a", "", ""]),
+			(r#"a"b"'c'"#, &["abc"]),
+		];
+		for &(line, expected) in pairs {
+			assert_eq!(split_line(line).unwrap(), expected);
+		}
+
+		assert!(split_line("a \"b").is_err());
+		assert!(split_line("a 'b").is_err());
+		assert!(split_line("a b\\").is_err());
+	}
+
+	#[test]
+	fn test_grouped_commands() {
+		let parse = |argv: &[&str]| get_app().get_matches_from_safe(argv)
+			.map(|m| parse_cmd_line_args(&m).unwrap());
+
+		let args = parse(&["mapeditr", "world", "playerdata", "teleport",
+			"alice", "0", "10", "0"]).unwrap();
+		assert_eq!(args.command, "playerdata teleport");
+		assert_eq!(args.player.as_deref(), Some("alice"));
+		assert_eq!(parse(&["mapeditr", "world", "players", "list"]).unwrap()
+			.command, "players list");
+		assert!(parse(&["mapeditr", "world", "playerdata"]).is_err());
+		assert!(parse(&["mapeditr", "world", "teleportplayer", "alice", "0",
+			"10", "0"]).is_err());
+	}
+	#[test]
+	fn test_json_output() {
+		let (server, client) = status_link();
+		let handle = std::thread::spawn(move || {
+			server.set_state(InstState::Querying);
+			server.set_total(2);
+			server.set_state(InstState::Editing);
+			server.inc_done();
+			server.inc_failed();
+			server.inc_done();
+			server.set_state(InstState::Ignore);
+			server.log(LogType::Info, "Done.");
+		});
+
+		let mut out = Vec::new();
+		run_json_loop(handle, client, &mut out);
+		let events: Vec<serde_json::Value> = std::str::from_utf8(&out)
+			.unwrap().lines()
+			.map(|l| serde_json::from_str(l).unwrap())
+			.collect();
+
+		let types: Vec<_> = events.iter()
+			.map(|e| e["type"].as_str().unwrap()).collect();
+		assert_eq!(types, ["state", "progress", "state", "progress", "state",
+			"log", "finished"]);
+		assert_eq!(events[0]["state"], "querying");
+		assert_eq!(events[3], json!({
+			"type": "progress",
+			"state": "editing",
+			"blocks_total": 2,
+			"blocks_done": 2,
+			"blocks_failed": 1,
+			"show_progress": true,
+		}));
+		assert_eq!(events[5], json!({
+			"type": "log", "level": "info", "message": "Done."}));
+		assert_eq!(events[6], json!({
+			"type": "finished",
+			"success": true,
+			"blocks_total": 2,
+			"blocks_done": 2,
+			"blocks_failed": 1,
+		}));
+
+		let mut out = Vec::new();
+		print_json_error(&mut out, "Bad argument.");
+		assert_eq!(std::str::from_utf8(&out).unwrap(),
+			"{\"level\":\"error\",\"message\":\"Bad argument.\",\
+				\"type\":\"log\"}\n\
+			{\"success\":false,\"type\":\"finished\"}\n");
+	}
+}
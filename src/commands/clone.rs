@@ -1,30 +1,15 @@
-use super::{Command, ArgResult, BLOCK_CACHE_SIZE};
+use super::{Command, CommandResult, Summary, BLOCK_CACHE_SIZE,
+	check_area, check_offset};
 
 use crate::{unwrap_or, opt_unwrap_or};
-use crate::spatial::{Vec3, Area, MAP_LIMIT};
+use crate::spatial::{Vec3, Area};
 use crate::map_database::MapDatabase;
 use crate::map_block::{MapBlock, MapBlockError, is_valid_generated};
 use crate::block_utils::{merge_blocks, merge_metadata, clean_name_id_map};
-use crate::instance::{ArgType, InstBundle, InstArgs};
+use crate::instance::{ArgType, InstBundle, EditContext};
 use crate::utils::{CacheMap, query_keys};
 
 
-fn verify_args(args: &InstArgs) -> ArgResult {
-	let map_area = Area::new(
-		Vec3::new(-MAP_LIMIT, -MAP_LIMIT, -MAP_LIMIT),
-		Vec3::new(MAP_LIMIT, MAP_LIMIT, MAP_LIMIT)
-	);
-
-	if map_area.intersection(args.area.unwrap() + args.offset.unwrap())
-		.is_none()
-	{
-		return ArgResult::error("Destination area is outside map bounds.");
-	}
-
-	ArgResult::Ok
-}
-
-
 type BlockResult = Option<Result<MapBlock, MapBlockError>>;
 
 fn get_cached(
@@ -45,11 +30,16 @@ fn get_cached(
 }
 
 
-fn clone(inst: &mut InstBundle) {
-	let src_area = inst.args.area.unwrap();
-	let offset = inst.args.offset.unwrap();
+/// Copy the contents of `src_area` to the same area shifted by `offset`.
+/// Returns the number of mapblocks written.
+pub fn clone(ctx: &mut EditContext, src_area: Area, offset: Vec3)
+	-> CommandResult
+{
+	check_area(Some(src_area), false)?;
+	check_offset(Some(src_area), offset)?;
+
 	let dst_area = src_area + offset;
-	let mut dst_keys = query_keys(&mut inst.db, &inst.status,
+	let mut dst_keys = query_keys(&mut ctx.db, ctx.status,
 		&[], Some(dst_area), false, true);
 
 	// Sort blocks according to offset such that we don't read blocks that
@@ -63,17 +53,18 @@ fn clone(inst: &mut InstBundle) {
 	});
 
 	let mut block_cache = CacheMap::with_capacity(BLOCK_CACHE_SIZE);
-	inst.status.begin_editing();
+	let mut count = 0;
+	ctx.status.begin_editing();
 
 	for dst_key in dst_keys {
-		inst.status.inc_done();
+		ctx.status.inc_done();
 
 		let mut dst_block = unwrap_or!(
 			opt_unwrap_or!(
-				get_cached(&mut inst.db, &mut block_cache, dst_key),
+				get_cached(&mut ctx.db, &mut block_cache, dst_key),
 				continue
 			),
-			{ inst.status.inc_failed(); continue; }
+			{ ctx.status.inc_failed(); continue; }
 		);
 
 		let dst_pos = Vec3::from_block_key(dst_key);
@@ -88,7 +79,7 @@ fn clone(inst: &mut InstBundle) {
 			let src_key = src_pos.to_block_key();
 			// Continue if a None or Some(Err) value is retrieved.
 			let src_block = opt_unwrap_or!(
-				get_cached(&mut inst.db, &mut block_cache, src_key)
+				get_cached(&mut ctx.db, &mut block_cache, src_key)
 					.map(|res| res.ok()).flatten(),
 				continue
 			);
@@ -106,17 +97,27 @@ fn clone(inst: &mut InstBundle) {
 		}
 
 		clean_name_id_map(&mut dst_block);
-		inst.db.set_block(dst_key, &dst_block.serialize()).unwrap();
+		ctx.db.set_block(dst_key, &dst_block.serialize(ctx.compression))?;
+		count += 1;
 	}
 
-	inst.status.end_editing();
+	ctx.status.end_editing();
+	Ok(Summary::new(count))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	clone(&mut inst.ctx, inst.args.area.unwrap(), inst.args.offset.unwrap())
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: clone,
-		verify_args: Some(verify_args),
+		func: run,
+		verify_args: None,
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
 			(ArgType::Area(true), "Area to clone"),
 			(ArgType::Offset(true), "Vector to shift the area's contents by")
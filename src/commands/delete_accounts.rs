@@ -0,0 +1,188 @@
+use super::{Command, WorldDb, CommandError, CommandResult, Summary};
+
+use crate::instance::{ArgType, InstBundle, Progress};
+use crate::auth_database::{AuthDatabase, Account};
+use crate::player_database::PlayerDatabase;
+use crate::map_database::DBError;
+use crate::utils::fmt_big_num;
+
+
+/// Find the accounts which would be deleted by `delete_accounts`, warning
+/// about any inactive accounts which are skipped.
+pub(super) fn find_inactive(
+	adb: &mut AuthDatabase,
+	status: &dyn Progress,
+	before: i64,
+	include_unknown: bool
+) -> Result<Vec<Account>, DBError> {
+	let mut inactive = Vec::new();
+	let mut admins = 0;
+	let mut unknown = 0;
+
+	for account in adb.get_accounts()? {
+		if account.last_login >= before {
+			continue;
+		}
+		if account.last_login < 0 && !include_unknown {
+			unknown += 1;
+			continue;
+		}
+		if account.privs.iter().any(|p| p == "server") {
+			admins += 1;
+			continue;
+		}
+		inactive.push(account);
+	}
+
+	if admins > 0 {
+		status.log_warning(format!(
+			"Skipped {} inactive accounts with the server privilege.",
+			fmt_big_num(admins)));
+	}
+	if unknown > 0 {
+		status.log_warning(format!(
+			"Skipped {} accounts with an unknown last login time.",
+			fmt_big_num(unknown)));
+	}
+	Ok(inactive)
+}
+
+
+fn do_delete(
+	adb: &mut AuthDatabase,
+	mut pdb: Option<&mut PlayerDatabase>,
+	status: &dyn Progress,
+	before: i64,
+	include_unknown: bool
+) -> Result<u64, DBError> {
+	if pdb.is_none() {
+		status.log_warning("players.sqlite was not found in the world \
+			directory; player data will not be deleted.");
+	}
+	let inactive = find_inactive(adb, status, before, include_unknown)?;
+
+	for account in &inactive {
+		adb.delete_account(account.id)?;
+		if let Some(pdb) = &mut pdb {
+			pdb.delete_player(&account.name)?;
+		}
+	}
+	status.log_info(format!("Deleted {} accounts.",
+		fmt_big_num(inactive.len() as u64)));
+	Ok(inactive.len() as u64)
+}
+
+
+/// Delete player accounts which haven't logged in since the Unix timestamp
+/// `before`, along with the players' saved data in `pdb`, if given. Accounts
+/// with an unknown last login time are only deleted if `include_unknown` is
+/// set, and accounts with the `server` privilege are never deleted.
+pub fn delete_accounts(
+	adb: &mut AuthDatabase,
+	pdb: Option<&mut PlayerDatabase>,
+	status: &dyn Progress,
+	before: i64,
+	include_unknown: bool
+) -> CommandResult {
+	do_delete(adb, pdb, status, before, include_unknown)
+		.map(Summary::new)
+		.map_err(|e| CommandError::Failed(
+			format!("Failed to delete accounts: {}", e)))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	delete_accounts(inst.adb.as_mut().unwrap(), inst.pdb.as_mut(),
+		inst.ctx.status, args.before.unwrap(), args.include_unknown)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[WorldDb::Auth],
+		optional_dbs: &[WorldDb::Players],
+		read_only: false,
+		args: vec![
+			(ArgType::Before(true),
+				"Delete accounts which haven't logged in since this date \
+				(YYYY-MM-DD)."),
+			(ArgType::IncludeUnknown,
+				"Also delete accounts with an unknown last login time."),
+		],
+		help: "Delete accounts of inactive players."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::TestProgress;
+
+	#[test]
+	fn test_delete_accounts() {
+		let auth_conn = sqlite::open(":memory:").unwrap();
+		auth_conn.execute("CREATE TABLE auth (id INTEGER PRIMARY KEY, \
+				name VARCHAR(32), last_login INTEGER);
+			CREATE TABLE user_privileges (id INTEGER, privilege VARCHAR(32));
+			INSERT INTO auth VALUES (1, 'admin', 1500000000), \
+				(2, 'alice', 1500000000), (3, 'bob', NULL), \
+				(4, 'carol', 1700000000);
+			INSERT INTO user_privileges VALUES (1, 'server'), (2, 'fly');")
+			.unwrap();
+		let player_conn = sqlite::open(":memory:").unwrap();
+		player_conn.execute("CREATE TABLE player (name, posX, posY, posZ, \
+				hp, breath, modification_date);
+			CREATE TABLE player_inventories (player);
+			CREATE TABLE player_inventory_items (player, inv_id, slot_id, \
+				item);
+			CREATE TABLE player_metadata (player);
+			INSERT INTO player VALUES ('alice', 0, 0, 0, 20, 10, ''), \
+				('bob', 0, 0, 0, 20, 10, ''), ('carol', 0, 0, 0, 20, 10, '');
+			INSERT INTO player_inventory_items VALUES \
+				('alice', 0, 0, 'default:dirt'), ('carol', 0, 0, '');")
+			.unwrap();
+		let mut adb = AuthDatabase::new(&auth_conn).unwrap();
+		let mut pdb = PlayerDatabase::new(&player_conn).unwrap();
+		let progress = TestProgress::default();
+		let names = |adb: &mut AuthDatabase| adb.get_accounts().unwrap()
+			.into_iter().map(|a| a.name).collect::<Vec<_>>();
+		let before = 1_600_000_000;
+
+		let inactive = find_inactive(&mut adb, &progress, before, false)
+			.unwrap();
+		assert_eq!(inactive.len(), 1);
+		assert_eq!(inactive[0].name, "alice");
+		assert_eq!(names(&mut adb), ["admin", "alice", "bob", "carol"]);
+
+		let res = delete_accounts(&mut adb, Some(&mut pdb), &progress, before,
+			false).unwrap();
+		assert_eq!(res.count, 1);
+		assert_eq!(names(&mut adb), ["admin", "bob", "carol"]);
+		let players: Vec<_> = pdb.get_players().unwrap().into_iter()
+			.map(|p| p.name).collect();
+		assert_eq!(players, ["bob", "carol"]);
+		assert_eq!(pdb.get_items().unwrap().len(), 1);
+		assert!(progress.logs.lock().unwrap().contains(&"warning: Skipped 1 \
+			accounts with an unknown last login time.".to_string()));
+
+		let res = delete_accounts(&mut adb, Some(&mut pdb), &progress, before,
+			true).unwrap();
+		assert_eq!(res.count, 1);
+		assert_eq!(names(&mut adb), ["admin", "carol"]);
+		assert_eq!(pdb.get_players().unwrap().len(), 1);
+
+		// Accounts can be deleted without a player database.
+		let res = delete_accounts(&mut adb, None, &progress, 1_800_000_000,
+			false).unwrap();
+		assert_eq!(res.count, 1);
+		assert_eq!(names(&mut adb), ["admin"]);
+		assert_eq!(pdb.get_players().unwrap().len(), 1);
+		assert!(progress.logs.lock().unwrap().contains(&"warning: \
+			players.sqlite was not found in the world directory; player data \
+			will not be deleted.".to_string()));
+	}
+}
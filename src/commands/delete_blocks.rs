@@ -1,32 +1,156 @@
-use super::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use crate::instance::{ArgType, InstBundle};
-use crate::utils::query_keys;
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	ensure_arg, check_area, check_names};
 
+use crate::unwrap_or;
+use crate::spatial::Area;
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
+use crate::map_block::MapBlock;
+use crate::block_utils::block_contains_only;
+use crate::utils::{query_keys, query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
 
-fn delete_blocks(inst: &mut InstBundle) {
-	let keys = query_keys(&mut inst.db, &inst.status,
-		&[], inst.args.area, inst.args.invert, false);
-	inst.status.begin_editing();
 
-	for key in keys {
-		inst.status.inc_done();
-		inst.db.delete_block(key).unwrap();
+fn check_args(area: Option<Area>, nodes: &[String])
+	-> Result<(), CommandError>
+{
+	ensure_arg(area.is_some() || !nodes.is_empty(),
+		"An area and/or list of nodes is required.")
+}
+
+
+fn verify_args(args: &InstArgs) -> ArgResult {
+	check_args(args.area, &args.nodes).into()
+}
+
+
+/// Delete all mapblocks inside or outside an area. If `nodes` is not empty,
+/// only mapblocks consisting entirely of these nodes are deleted. Returns the
+/// number of mapblocks deleted.
+pub fn delete_blocks(
+	ctx: &mut EditContext,
+	area: Option<Area>,
+	invert: bool,
+	nodes: &[String]
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(nodes, "node")?;
+	check_args(area, nodes)?;
+
+	let natural_nodes: Vec<_> = nodes.iter().map(to_bytes).collect();
+
+	if natural_nodes.is_empty() {
+		let keys = query_keys(&mut ctx.db, ctx.status,
+			&[], area, invert, false);
+		ctx.status.begin_editing();
+		let count = keys.len() as u64;
+		for key in keys {
+			ctx.status.inc_done();
+			ctx.db.delete_block(key)?;
+		}
+
+		ctx.status.end_editing();
+		ctx.status.log_info(
+			format!("Deleted {} mapblocks.", fmt_big_num(count)));
+		return Ok(Summary::new(count));
 	}
 
-	inst.status.end_editing();
+	let blocks = query_blocks(&ctx.db, None, ctx.status,
+		&[], area, invert, false);
+	ctx.status.begin_editing();
+	let deleted = AtomicU64::new(0);
+	let kept = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|_, data| {
+			let block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			if block_contains_only(&block, &natural_nodes) {
+				deleted.fetch_add(1, Ordering::Relaxed);
+				BlockAction::Delete
+			} else {
+				kept.fetch_add(1, Ordering::Relaxed);
+				BlockAction::Keep
+			}
+		}
+	)?;
+
+	let deleted = deleted.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("Kept {} mapblocks containing other nodes.",
+		fmt_big_num(kept.into_inner())));
+	ctx.status.log_info(format!("Deleted {} mapblocks.",
+		fmt_big_num(deleted)));
+	Ok(Summary::new(deleted))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	delete_blocks(&mut inst.ctx, args.area, args.invert, &args.nodes)
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: delete_blocks,
-		verify_args: None,
+		func: run,
+		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
-			(ArgType::Area(true), "Area containing mapblocks to delete"),
+			(ArgType::Area(false), "Area containing mapblocks to delete"),
 			(ArgType::Invert,
-				"Delete all mapblocks fully *outside* the given area.")
+				"Delete all mapblocks fully *outside* the given area."),
+			(ArgType::Nodes,
+				"Names of one or more \"natural\" nodes. If specified, only \
+				mapblocks consisting entirely of these nodes will be deleted."),
 		],
 		help: "Delete all mapblocks inside or outside an area."
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::spatial::Vec3;
+	use crate::map_database::MapDatabase;
+	use crate::utils::{read_test_file, create_test_map, TestProgress};
+
+	#[test]
+	fn test_delete_blocks() {
+		// v28 block contains only air and test_mod:timer; v29 block also
+		// contains test_mod:metadata.
+		let v28 = read_test_file("mapblock_v28.bin");
+		let v29 = read_test_file("mapblock_v29.bin");
+		let keys = [Vec3::new(0, 0, 0), Vec3::new(1, 0, 0), Vec3::new(0, 1, 0)]
+			.map(|p| p.to_block_key());
+		let area = Some(Area::new(Vec3::new(0, 0, 0), Vec3::new(15, 15, 15)));
+		let natural = ["air".to_string(), "test_mod:timer".to_string()];
+
+		let remaining = |area, invert, nodes: &[String]| {
+			let conn = create_test_map();
+			let progress = TestProgress::default();
+			let mut ctx = EditContext::new(MapDatabase::new(&conn).unwrap(),
+				&progress);
+			for (&key, data) in keys.iter().zip([&v28, &v28, &v29]) {
+				ctx.db.set_block(key, data).unwrap();
+			}
+			delete_blocks(&mut ctx, area, invert, nodes).unwrap();
+			keys.iter().filter(|&&k| ctx.db.get_block(k).is_ok())
+				.count()
+		};
+
+		// Area only
+		assert_eq!(remaining(area, false, &[]), 2);
+		assert_eq!(remaining(area, true, &[]), 1);
+		// Nodes only
+		assert_eq!(remaining(None, false, &natural), 1);
+		// Both
+		assert_eq!(remaining(area, false, &natural), 2);
+		assert_eq!(remaining(area, true, &natural), 2);
+	}
+}
@@ -1,10 +1,14 @@
-use super::{Command, ArgResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, ArgResult, CommandResult, Summary, check_area,
+	check_names};
 
 use crate::unwrap_or;
-use crate::spatial::Vec3;
-use crate::instance::{ArgType, InstArgs, InstBundle};
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
 use crate::map_block::MapBlock;
-use crate::utils::{query_keys, to_bytes, to_slice, fmt_big_num};
+use crate::utils::{query_blocks, to_bytes, to_slice, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
 
 
 fn verify_args(args: &InstArgs) -> ArgResult {
@@ -17,65 +21,91 @@ fn verify_args(args: &InstArgs) -> ArgResult {
 }
 
 
-fn delete_metadata(inst: &mut InstBundle) {
-	let node = inst.args.node.as_ref().map(to_bytes);
-
-	let keys = query_keys(&mut inst.db, &mut inst.status,
-		to_slice(&node), inst.args.area, inst.args.invert, true);
-
-	inst.status.begin_editing();
-	let mut count: u64 = 0;
-
-	for key in keys {
-		inst.status.inc_done();
-		let data = inst.db.get_block(key).unwrap();
-		let mut block = unwrap_or!(MapBlock::deserialize(&data),
-			{ inst.status.inc_failed(); continue; });
-
-		let node_id = node.as_deref().and_then(|n| block.nimap.get_id(n));
-		if node.is_some() && node_id.is_none() {
-			continue; // Block doesn't contain the required node.
-		}
+/// Delete the metadata of all nodes, or only of nodes named `node`,
+/// optionally only inside or outside an area. Returns the number of nodes
+/// whose metadata was deleted.
+pub fn delete_metadata(
+	ctx: &mut EditContext,
+	node: Option<&str>,
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(node.as_slice(), "node")?;
+
+	let node = node.map(to_bytes);
+
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		to_slice(&node), area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let count = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|key, data| {
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			let node_id = node.as_deref().and_then(|n| block.nimap.get_id(n));
+			if node.is_some() && node_id.is_none() {
+				// Block doesn't contain the required node.
+				return BlockAction::Keep;
+			}
 
-		let block_corner = Vec3::from_block_key(key) * 16;
-		let mut to_delete = Vec::with_capacity(block.metadata.len());
+			let block_corner = Vec3::from_block_key(key) * 16;
+			let mut to_delete = Vec::with_capacity(block.metadata.len());
 
-		for (&idx, _) in &block.metadata {
-			let abs_pos = Vec3::from_u16_key(idx) + block_corner;
+			for &idx in block.metadata.keys() {
+				let abs_pos = Vec3::from_u16_key(idx) + block_corner;
 
-			if let Some(a) = inst.args.area {
-				if a.contains(abs_pos) == inst.args.invert {
-					continue;
+				if let Some(a) = area {
+					if a.contains(abs_pos) == invert {
+						continue;
+					}
 				}
-			}
-			if let Some(id) = node_id {
-				if block.node_data.nodes[idx as usize] != id {
-					continue;
+				if let Some(id) = node_id {
+					if block.node_data.nodes[idx as usize] != id {
+						continue;
+					}
 				}
-			}
 
-			to_delete.push(idx);
-		}
+				to_delete.push(idx);
+			}
 
-		if !to_delete.is_empty() {
+			if to_delete.is_empty() {
+				return BlockAction::Keep;
+			}
 			for idx in &to_delete {
 				block.metadata.remove(idx);
 			}
-			count += to_delete.len() as u64;
-			inst.db.set_block(key, &block.serialize()).unwrap();
+			count.fetch_add(to_delete.len() as u64, Ordering::Relaxed);
+			BlockAction::Write(block.serialize(level))
 		}
-	}
+	)?;
+
+	let count = count.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("Deleted metadata from {} nodes.",
+		fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
+
 
-	inst.status.end_editing();
-	inst.status.log_info(
-		format!("Deleted metadata from {} nodes.", fmt_big_num(count)));
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	delete_metadata(&mut inst.ctx, args.node.as_deref(), args.area,
+		args.invert)
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: delete_metadata,
+		func: run,
 		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
 			(ArgType::Node(false), "Name of node to delete metadata from"),
 			(ArgType::Area(false), "Area in which to delete metadata"),
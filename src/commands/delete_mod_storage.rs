@@ -0,0 +1,54 @@
+use super::{Command, WorldDb, CommandError, CommandResult, Summary,
+	ensure_arg, is_valid_mod_name};
+
+use crate::instance::{ArgType, InstBundle, Progress};
+use crate::mod_storage_database::ModStorageDatabase;
+use crate::utils::{to_bytes, fmt_big_num};
+
+
+/// Delete all data stored by the mod `mod_name`, or only the entry `key` if
+/// given.
+pub fn delete_mod_storage(
+	db: &mut ModStorageDatabase,
+	status: &dyn Progress,
+	mod_name: &str,
+	key: Option<&str>
+) -> CommandResult {
+	ensure_arg(is_valid_mod_name(mod_name), "Invalid mod name.")?;
+
+	let key = key.map(to_bytes);
+	match db.delete_entries(mod_name, key.as_deref()) {
+		Ok(count) => {
+			status.log_info(format!("Deleted {} entries.",
+				fmt_big_num(count)));
+			Ok(Summary::new(count))
+		},
+		Err(e) => Err(CommandError::Failed(
+			format!("Failed to delete mod storage: {}", e))),
+	}
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	delete_mod_storage(inst.msdb.as_mut().unwrap(), inst.ctx.status,
+		args.mod_name.as_ref().unwrap(), args.key.as_deref())
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[WorldDb::ModStorage],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::ModName(true), "Name of the mod to delete data of"),
+			(ArgType::Key(false),
+				"Key of the entry to delete. If not specified, all of the \
+				mod's data is deleted."),
+		],
+		help: "Delete the stored data of a mod."
+	}
+}
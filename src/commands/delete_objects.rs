@@ -1,10 +1,14 @@
-use super::{Command, ArgResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	ensure_arg, check_area, check_names};
 
 use crate::unwrap_or;
 use crate::spatial::Area;
-use crate::instance::{ArgType, InstArgs, InstBundle};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
 use crate::map_block::{MapBlock, StaticObject, LuaEntityData};
-use crate::utils::{query_keys, to_bytes, to_slice, fmt_big_num};
+use crate::utils::{query_blocks, to_bytes, to_slice, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
 
 use memmem::{Searcher, TwoWaySearcher};
 
@@ -20,11 +24,16 @@ thread_local! {
 }
 
 
+fn check_args(object: Option<&str>, items: Option<&[String]>)
+	-> Result<(), CommandError>
+{
+	ensure_arg(object.is_none() || items.is_none(),
+		"Cannot use both --obj and --items.")
+}
+
+
 fn verify_args(args: &InstArgs) -> ArgResult {
-	if args.object.is_some() && args.items.is_some() {
-		return ArgResult::error("Cannot use both --obj and --items.");
-	}
-	ArgResult::Ok
+	check_args(args.object.as_deref(), args.items.as_deref()).into()
 }
 
 
@@ -97,56 +106,86 @@ fn can_delete(
 }
 
 
-fn delete_objects(inst: &mut InstBundle) {
-	let obj_name = if inst.args.items.is_some() {
+/// Delete objects inside or outside an area. If `object` is given, only
+/// objects with that name are deleted. If `items` is given, only item
+/// entities are deleted, and if it is not empty, only those of the listed
+/// items.
+pub fn delete_objects(
+	ctx: &mut EditContext,
+	object: Option<&str>,
+	items: Option<&[String]>,
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(object.as_slice(), "object")?;
+	check_names(items.unwrap_or_default(), "item")?;
+	check_args(object, items)?;
+
+	let obj_name = if items.is_some() {
 		Some(ITEM_ENT_NAME.to_owned())
 	} else {
-		inst.args.object.as_ref().map(to_bytes)
+		object.map(to_bytes)
 	};
 
-	let item_names: Vec<_> = inst.args.items.as_ref().unwrap_or(&Vec::new())
+	let item_names: Vec<_> = items.unwrap_or_default()
 		.iter().map(to_bytes).collect();
 
-	let keys = query_keys(&mut inst.db, &mut inst.status,
-		to_slice(&obj_name), inst.args.area, inst.args.invert, true);
-
-	inst.status.begin_editing();
-	let mut count: u64 = 0;
-	for key in keys {
-		inst.status.inc_done();
-		let data = inst.db.get_block(key).unwrap();
-		let mut block = unwrap_or!(MapBlock::deserialize(&data),
-			{ inst.status.inc_failed(); continue; });
-
-		let mut modified = false;
-		for i in (0 .. block.static_objects.len()).rev() {
-			if can_delete(
-				&block.static_objects[i],
-				&inst.args.area,
-				inst.args.invert,
-				&obj_name,
-				&item_names
-			) {
-				block.static_objects.remove(i);
-				modified = true;
-				count += 1;
+	let blocks = query_blocks(&ctx.db, None, ctx.status,
+		to_slice(&obj_name), area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let count = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|_, data| {
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			let mut deleted = 0;
+			for i in (0 .. block.static_objects.len()).rev() {
+				if can_delete(
+					&block.static_objects[i],
+					&area,
+					invert,
+					&obj_name,
+					&item_names
+				) {
+					block.static_objects.remove(i);
+					deleted += 1;
+				}
+			}
+
+			if deleted == 0 {
+				return BlockAction::Keep;
 			}
+			count.fetch_add(deleted, Ordering::Relaxed);
+			BlockAction::Write(block.serialize(level))
 		}
+	)?;
+
+	let count = count.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("Deleted {} objects.", fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
 
-		if modified {
-			inst.db.set_block(key, &block.serialize()).unwrap();
-		}
-	}
 
-	inst.status.end_editing();
-	inst.status.log_info(format!("Deleted {} objects.", fmt_big_num(count)));
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	delete_objects(&mut inst.ctx, args.object.as_deref(),
+		args.items.as_deref(), args.area, args.invert)
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: delete_objects,
+		func: run,
 		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
 			(ArgType::Object, "Name of object to delete"),
 			(ArgType::Items,
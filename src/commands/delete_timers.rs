@@ -1,71 +1,100 @@
-use super::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, CommandResult, Summary, check_area, check_names};
 
 use crate::unwrap_or;
-use crate::spatial::Vec3;
-use crate::instance::{ArgType, InstBundle};
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstBundle, EditContext};
 use crate::map_block::MapBlock;
-use crate::utils::{query_keys, to_bytes, to_slice, fmt_big_num};
+use crate::utils::{query_blocks, to_bytes, to_slice, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
 
 
-fn delete_timers(inst: &mut InstBundle) {
-	let node = inst.args.node.as_ref().map(to_bytes);
+/// Delete the node timers of all nodes, or only of nodes named `node`,
+/// optionally only inside or outside an area. Returns the number of timers
+/// deleted.
+pub fn delete_timers(
+	ctx: &mut EditContext,
+	node: Option<&str>,
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(node.as_slice(), "node")?;
 
-	let keys = query_keys(&mut inst.db, &mut inst.status,
-		to_slice(&node), inst.args.area, inst.args.invert, true);
+	let node = node.map(to_bytes);
 
-	inst.status.begin_editing();
-	let mut count: u64 = 0;
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		to_slice(&node), area, invert, true);
 
-	for key in keys {
-		inst.status.inc_done();
-		let data = inst.db.get_block(key).unwrap();
-		let mut block = unwrap_or!(MapBlock::deserialize(&data),
-			{ inst.status.inc_failed(); continue; });
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let count = AtomicU64::new(0);
 
-		let node_id = node.as_deref().and_then(|n| block.nimap.get_id(n));
-		if node.is_some() && node_id.is_none() {
-			continue; // Block doesn't contain the required node.
-		}
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|key, data| {
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			let node_id = node.as_deref().and_then(|n| block.nimap.get_id(n));
+			if node.is_some() && node_id.is_none() {
+				// Block doesn't contain the required node.
+				return BlockAction::Keep;
+			}
 
-		let block_corner = Vec3::from_block_key(key) * 16;
-		let mut modified = false;
+			let block_corner = Vec3::from_block_key(key) * 16;
+			let mut deleted = 0;
 
-		for i in (0..block.node_timers.len()).rev() {
-			let pos_idx = block.node_timers[i].pos;
-			let pos = Vec3::from_u16_key(pos_idx);
-			let abs_pos = pos + block_corner;
+			for i in (0..block.node_timers.len()).rev() {
+				let pos_idx = block.node_timers[i].pos;
+				let pos = Vec3::from_u16_key(pos_idx);
+				let abs_pos = pos + block_corner;
 
-			if let Some(a) = inst.args.area {
-				if a.contains(abs_pos) == inst.args.invert {
-					continue;
+				if let Some(a) = area {
+					if a.contains(abs_pos) == invert {
+						continue;
+					}
 				}
-			}
-			if let Some(id) = node_id {
-				if block.node_data.nodes[pos_idx as usize] != id {
-					continue;
+				if let Some(id) = node_id {
+					if block.node_data.nodes[pos_idx as usize] != id {
+						continue;
+					}
 				}
+
+				block.node_timers.remove(i);
+				deleted += 1;
 			}
 
-			block.node_timers.remove(i);
-			count += 1;
-			modified = true;
+			if deleted == 0 {
+				return BlockAction::Keep;
+			}
+			count.fetch_add(deleted, Ordering::Relaxed);
+			BlockAction::Write(block.serialize(level))
 		}
+	)?;
+
+	let count = count.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("Deleted {} node timers.",
+		fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
 
-		if modified {
-			inst.db.set_block(key, &block.serialize()).unwrap();
-		}
-	}
 
-	inst.status.end_editing();
-	inst.status.log_info(
-		format!("Deleted {} node timers.", fmt_big_num(count)));
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	delete_timers(&mut inst.ctx, args.node.as_deref(), args.area,
+		args.invert)
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: delete_timers,
+		func: run,
 		verify_args: None,
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
 			(ArgType::Node(false), "Name of node to delete node timers from"),
 			(ArgType::Area(false), "Area in which to delete node timers"),
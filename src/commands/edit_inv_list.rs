@@ -0,0 +1,429 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	ensure_arg, check_area, check_names};
+
+use crate::unwrap_or;
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
+use crate::map_block::{MapBlock, Inventory, InventoryList, ItemStack};
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
+
+
+/// Maximum number of skipped nodes to list individually.
+const MAX_REPORTED: usize = 10;
+
+
+/// Changes to make to an inventory list.
+#[derive(Clone, Debug, Default)]
+pub struct ListEdit {
+	/// Resize the list, creating it if it doesn't exist.
+	pub size: Option<u32>,
+	/// Rename the list.
+	pub new_name: Option<String>,
+	/// Delete the list.
+	pub delete: bool,
+	/// List to move items into if they would otherwise be deleted.
+	pub overflow: Option<String>,
+}
+
+
+/// Move `items` into empty slots of `list`. Returns the number of items which
+/// did not fit.
+fn move_items(list: &mut InventoryList, items: Vec<ItemStack>) -> usize {
+	if list.items.len() < list.size as usize {
+		list.items.resize(list.size as usize, ItemStack::default());
+	}
+	let mut empty_slots = list.items.iter_mut()
+		.take(list.size as usize)
+		.filter(|s| s.is_empty());
+	let mut items = items.into_iter();
+
+	for (slot, item) in empty_slots.by_ref().zip(items.by_ref()) {
+		*slot = item;
+	}
+	items.count()
+}
+
+
+/// Apply `edit` to the list named `name` in `inv`. Returns whether the
+/// inventory was modified, or an error message if the edit would delete items
+/// or cause a name conflict. `inv` is left unchanged if an error occurs.
+fn do_edit(
+	inv: &mut Inventory,
+	name: &[u8],
+	edit: &ListEdit,
+	new_name: Option<&[u8]>,
+	overflow: Option<&[u8]>
+) -> Result<bool, String> {
+	let idx = match inv.lists.iter().position(|l| l.name == name) {
+		Some(i) => i,
+		None => {
+			let new_name = new_name.unwrap_or(name);
+			if let (Some(size), false) = (edit.size, edit.delete) {
+				if inv.get_list(new_name).is_none() {
+					inv.lists.push(InventoryList::new(new_name, size));
+					return Ok(true);
+				}
+			}
+			return Ok(false);
+		}
+	};
+
+	if let Some(new) = new_name {
+		if inv.get_list(new).is_some() {
+			return Err(format!("List \"{}\" already exists.",
+				String::from_utf8_lossy(new)));
+		}
+	}
+
+	// Collect items which would be removed.
+	let keep = if edit.delete {
+		0
+	} else {
+		edit.size.map_or(usize::MAX, |s| s as usize)
+	};
+	let removed: Vec<_> = inv.lists[idx].items.iter().skip(keep)
+		.filter(|s| !s.is_empty()).cloned().collect();
+
+	let mut new_inv = inv.clone();
+	if !removed.is_empty() {
+		let count = removed.len();
+		let target = overflow.and_then(|o|
+			new_inv.lists.iter_mut().find(|l| l.name == o));
+		let target = match target {
+			Some(t) => t,
+			None => return Err(format!(
+				"{} itemstacks would be deleted.", count))
+		};
+		let unmoved = move_items(target, removed);
+		if unmoved > 0 {
+			return Err(format!("{} of {} itemstacks don't fit in list \"{}\".",
+				unmoved, count, String::from_utf8_lossy(&target.name)));
+		}
+	}
+
+	if edit.delete {
+		new_inv.lists.remove(idx);
+	} else {
+		let list = &mut new_inv.lists[idx];
+		if let Some(size) = edit.size {
+			list.size = size;
+			list.items.resize(size as usize, ItemStack::default());
+		}
+		if let Some(new) = new_name {
+			list.name = new.to_vec();
+		}
+	}
+
+	let modified = new_inv != *inv;
+	*inv = new_inv;
+	Ok(modified)
+}
+
+
+/// Add, delete, rename, or resize the inventory list `list` in node
+/// inventories. Nodes where the edit would delete items (after moving them
+/// to `edit.overflow`, if given) are skipped and reported. If `nodes` is not
+/// empty, only inventories of the listed nodes are modified.
+pub fn edit_inv_list(
+	ctx: &mut EditContext,
+	list: &str,
+	edit: &ListEdit,
+	nodes: &[String],
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(nodes, "node")?;
+	check_edit(list, edit)?;
+
+	let name = to_bytes(list);
+	let new_name = edit.new_name.as_deref().map(to_bytes);
+	let overflow = edit.overflow.as_deref().map(to_bytes);
+
+	let nodes: Vec<_> = nodes.iter().map(to_bytes).collect();
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		&nodes, area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let count = AtomicU64::new(0);
+	let skipped = Mutex::new(Vec::new());
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|key, data| {
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			let node_ids: Vec<_> = nodes.iter()
+				.filter_map(|n| block.nimap.get_id(n)).collect();
+			if !nodes.is_empty() && node_ids.is_empty() {
+				// Block doesn't contain any of the required nodes.
+				return BlockAction::Keep;
+			}
+
+			let block_corner = Vec3::from_block_key(key) * 16;
+			let mut modified = false;
+
+			for (&idx, data) in &mut block.metadata {
+				let abs_pos = Vec3::from_u16_key(idx) + block_corner;
+				if let Some(a) = area {
+					if a.contains(abs_pos) == invert {
+						continue;
+					}
+				}
+				if !node_ids.is_empty()
+					&& !node_ids.contains(&block.node_data.nodes[idx as usize])
+				{
+					continue;
+				}
+
+				let mut inv = unwrap_or!(Inventory::deserialize(&data.inv), {
+					skipped.lock().unwrap().push(
+						(abs_pos, "Inventory could not be read.".to_string()));
+					continue;
+				});
+				match do_edit(&mut inv, &name, edit, new_name.as_deref(),
+					overflow.as_deref())
+				{
+					Ok(false) => {},
+					Ok(true) => {
+						data.inv = inv.serialize();
+						count.fetch_add(1, Ordering::Relaxed);
+						modified = true;
+					},
+					Err(msg) => skipped.lock().unwrap().push((abs_pos, msg))
+				}
+			}
+
+			if modified {
+				BlockAction::Write(block.serialize(level))
+			} else {
+				BlockAction::Keep
+			}
+		}
+	)?;
+
+	ctx.status.end_editing();
+	let mut skipped = skipped.into_inner().unwrap();
+	skipped.sort_by_key(|&(pos, _)| (pos.z, pos.y, pos.x));
+	for (pos, msg) in skipped.iter().take(MAX_REPORTED) {
+		ctx.status.log_warning(format!("Skipped node at {}: {}", pos, msg));
+	}
+	if skipped.len() > MAX_REPORTED {
+		ctx.status.log_warning(format!("Skipped {} more nodes.",
+			fmt_big_num((skipped.len() - MAX_REPORTED) as u64)));
+	}
+	let count = count.into_inner();
+	ctx.status.log_info(format!("Modified inventory lists of {} nodes.",
+		fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
+
+
+fn check_edit(list: &str, edit: &ListEdit) -> Result<(), CommandError> {
+	let is_valid = |name: &str|
+		!name.is_empty() && !name.contains(char::is_whitespace);
+
+	ensure_arg(edit.size.is_some() || edit.new_name.is_some() || edit.delete,
+		"At least one of --size, --newname, or --delete is required.")?;
+	ensure_arg(!edit.delete || (edit.size.is_none() && edit.new_name.is_none()),
+		"--size and --newname cannot be used when deleting a list.")?;
+	ensure_arg(is_valid(list), "Invalid list name.")?;
+	if let Some(new) = &edit.new_name {
+		ensure_arg(is_valid(new) && new != list, "Invalid new list name.")?;
+	}
+	if let Some(overflow) = &edit.overflow {
+		ensure_arg(is_valid(overflow), "Invalid overflow list name.")?;
+		ensure_arg(overflow != list && Some(overflow) != edit.new_name.as_ref(),
+			"Overflow list must be different from the edited list.")?;
+		ensure_arg(edit.size.is_some() || edit.delete,
+			"--overflow can only be used with --size or --delete.")?;
+	}
+	Ok(())
+}
+
+
+fn get_edit(args: &InstArgs) -> ListEdit {
+	ListEdit {
+		size: args.size,
+		new_name: args.new_name.clone(),
+		delete: args.delete,
+		overflow: args.overflow.clone(),
+	}
+}
+
+
+fn verify_args(args: &InstArgs) -> ArgResult {
+	let list = args.list_name.as_deref().unwrap_or_default();
+	if let Err(e) = check_edit(list, &get_edit(args)) {
+		return ArgResult::Error(e.to_string());
+	}
+	if args.size.is_some() && args.nodes.is_empty() {
+		return ArgResult::warning("No nodes specified. The list will be \
+			added to every node inventory which doesn't have it.");
+	}
+	ArgResult::Ok
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	edit_inv_list(&mut inst.ctx, args.list_name.as_ref().unwrap(),
+		&get_edit(args), &args.nodes, args.area, args.invert)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::ListName, "Name of the inventory list to edit"),
+			(ArgType::Size,
+				"New size of the list. The list is created if it doesn't \
+				exist."),
+			(ArgType::NewName, "New name of the list"),
+			(ArgType::Delete, "Delete the list."),
+			(ArgType::Overflow,
+				"List to move items into if they would otherwise be \
+				deleted"),
+			(ArgType::Nodes,
+				"Names of one or more nodes to modify inventories of"),
+			(ArgType::Area(false), "Area in which to modify node inventories"),
+			(ArgType::Invert,
+				"Modify node inventories *outside* the given area."),
+		],
+		help: "Add, delete, rename, or resize an inventory list in certain \
+			node inventories."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn inv(data: &[u8]) -> Inventory {
+		Inventory::deserialize(data).unwrap()
+	}
+
+	#[test]
+	fn test_do_edit() {
+		let original = inv(b"\
+			List main 4\n\
+			Width 0\n\
+			Item default:stone 99\n\
+			Empty\n\
+			Item default:dirt 5\n\
+			Item default:torch 10\n\
+			EndInventoryList\n\
+			List extra 2\n\
+			Width 0\n\
+			Empty\n\
+			Item default:apple\n\
+			EndInventoryList\n\
+			EndInventory\n");
+		let edit = |inv: &mut Inventory, name: &[u8], edit: ListEdit| {
+			let new_name = edit.new_name.as_deref().map(to_bytes);
+			let overflow = edit.overflow.as_deref().map(to_bytes);
+			do_edit(inv, name, &edit, new_name.as_deref(),
+				overflow.as_deref())
+		};
+
+		// Shrinking would delete items, unless they are moved.
+		let mut i = original.clone();
+		assert!(edit(&mut i, b"main", ListEdit {
+			size: Some(2), ..Default::default()
+		}).is_err());
+		assert_eq!(i, original);
+		assert!(edit(&mut i, b"main", ListEdit {
+			size: Some(1), overflow: Some("extra".to_string()),
+			..Default::default()
+		}).is_err());
+		assert_eq!(i, original);
+		assert_eq!(edit(&mut i, b"main", ListEdit {
+			size: Some(3), overflow: Some("extra".to_string()),
+			..Default::default()
+		}), Ok(true));
+		assert_eq!(i, inv(b"\
+			List main 3\n\
+			Width 0\n\
+			Item default:stone 99\n\
+			Empty\n\
+			Item default:dirt 5\n\
+			EndInventoryList\n\
+			List extra 2\n\
+			Width 0\n\
+			Item default:torch 10\n\
+			Item default:apple\n\
+			EndInventoryList\n\
+			EndInventory\n"));
+
+		// Grow and rename.
+		let mut i = original.clone();
+		assert_eq!(edit(&mut i, b"extra", ListEdit {
+			size: Some(3), new_name: Some("fuel".to_string()),
+			..Default::default()
+		}), Ok(true));
+		let fuel = i.get_list(b"fuel").unwrap();
+		assert_eq!((fuel.size, fuel.items.len()), (3, 3));
+		assert!(i.get_list(b"extra").is_none());
+		assert!(edit(&mut i, b"main", ListEdit {
+			new_name: Some("fuel".to_string()), ..Default::default()
+		}).is_err());
+
+		// Delete, moving items.
+		let mut i = original.clone();
+		assert_eq!(edit(&mut i, b"extra", ListEdit {
+			delete: true, overflow: Some("main".to_string()),
+			..Default::default()
+		}), Ok(true));
+		assert_eq!(i.lists.len(), 1);
+		assert_eq!(i.lists[0].items[1], ItemStack::new(b"default:apple", 1));
+
+		// Add a missing list, or do nothing if not resizing.
+		let mut i = original.clone();
+		assert_eq!(edit(&mut i, b"dst", ListEdit {
+			delete: true, ..Default::default()
+		}), Ok(false));
+		assert_eq!(edit(&mut i, b"dst", ListEdit {
+			size: Some(4), ..Default::default()
+		}), Ok(true));
+		assert_eq!(i.get_list(b"dst").unwrap().items.len(), 4);
+	}
+
+	#[test]
+	fn test_unreadable_inventory() {
+		use crate::map_database::MapDatabase;
+		use crate::map_block::CompressionLevel;
+		use crate::utils::{read_test_file, create_test_map, TestProgress};
+
+		let mut block =
+			MapBlock::deserialize(&read_test_file("mapblock_v29.bin")).unwrap();
+		let (&idx, _) = block.metadata.iter().next().unwrap();
+		// The list has no size.
+		block.metadata.get_mut(&idx).unwrap().inv =
+			b"List main\nEndInventoryList\nEndInventory\n".to_vec();
+
+		let conn = create_test_map();
+		let progress = TestProgress::default();
+		let mut ctx = EditContext::new(MapDatabase::new(&conn).unwrap(),
+			&progress);
+		ctx.db.set_block(0, &block.serialize(CompressionLevel::Default))
+			.unwrap();
+		let edit = ListEdit { delete: true, ..Default::default() };
+		edit_inv_list(&mut ctx, "main", &edit, &[], None, false).unwrap();
+
+		let logs = progress.logs.lock().unwrap();
+		assert!(logs.contains(&format!(
+			"warning: Skipped node at {}: Inventory could not be read.",
+			Vec3::from_u16_key(idx))));
+	}
+}
@@ -0,0 +1,151 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_json::{json, Map, Value};
+
+use super::{Command, CommandError, CommandResult, Summary, check_area,
+	check_names};
+
+use crate::unwrap_or;
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstBundle, EditContext};
+use crate::map_block::{MapBlock, NodeMetadata};
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
+
+
+/// Convert one node's metadata to JSON, or return `None` if any of it is not
+/// valid UTF-8.
+fn meta_to_json(name: &[u8], meta: &NodeMetadata) -> Option<Value> {
+	let to_str = |s: &[u8]| std::str::from_utf8(s).ok().map(str::to_string);
+
+	let mut vars = Map::new();
+	let mut private = Vec::new();
+	for (key, (value, is_private)) in &meta.vars {
+		let key = to_str(key)?;
+		if *is_private {
+			private.push(key.clone());
+		}
+		vars.insert(key, Value::String(to_str(value)?));
+	}
+	private.sort();
+
+	Some(json!({
+		"node": to_str(name)?,
+		"vars": vars,
+		"private": private,
+		"inventory": to_str(&meta.inv)?,
+	}))
+}
+
+
+/// Export the metadata of nodes inside or outside an area as a JSON object,
+/// keyed by absolute node position (`"x,y,z"`). If `nodes` is not empty, only
+/// metadata of the listed nodes is exported.
+pub fn export_meta(
+	ctx: &mut EditContext,
+	nodes: &[String],
+	area: Option<Area>,
+	invert: bool
+) -> Result<Value, CommandError> {
+	check_area(area, invert)?;
+	check_names(nodes, "node")?;
+
+	let nodes: Vec<_> = nodes.iter().map(to_bytes).collect();
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		&nodes, area, invert, true);
+
+	ctx.status.begin_editing();
+	let output = Mutex::new(Map::new());
+	let invalid = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|key, data| {
+			let block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			let node_ids: Vec<_> = nodes.iter()
+				.filter_map(|n| block.nimap.get_id(n)).collect();
+			if !nodes.is_empty() && node_ids.is_empty() {
+				// Block doesn't contain any of the required nodes.
+				return BlockAction::Keep;
+			}
+
+			let block_corner = Vec3::from_block_key(key) * 16;
+			let mut entries = Vec::new();
+
+			for (&idx, meta) in &block.metadata {
+				let pos = Vec3::from_u16_key(idx) + block_corner;
+				if let Some(a) = area {
+					if a.contains(pos) == invert {
+						continue;
+					}
+				}
+				let id = block.node_data.nodes[idx as usize];
+				if !node_ids.is_empty() && !node_ids.contains(&id) {
+					continue;
+				}
+
+				let name = block.nimap.0.get(&id).map_or(&[][..], |n| n);
+				match meta_to_json(name, meta) {
+					Some(v) => entries.push(
+						(format!("{},{},{}", pos.x, pos.y, pos.z), v)),
+					None => { invalid.fetch_add(1, Ordering::Relaxed); }
+				}
+			}
+
+			output.lock().unwrap().extend(entries);
+			BlockAction::Keep
+		}
+	)?;
+
+	ctx.status.end_editing();
+	let invalid = invalid.into_inner();
+	if invalid > 0 {
+		ctx.status.log_warning(format!(
+			"Skipped {} nodes with metadata which is not valid UTF-8.",
+			fmt_big_num(invalid)));
+	}
+	let output = output.into_inner().unwrap();
+	ctx.status.log_info(format!("Exported metadata of {} nodes.",
+		fmt_big_num(output.len() as u64)));
+	Ok(Value::Object(output))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	let path = args.json_file.as_ref().unwrap();
+	// Create the file first, to fail before doing any work.
+	let file = std::fs::File::create(path).map_err(|e| CommandError::Failed(
+		format!("Failed to create output file: {}", e)))?;
+
+	let output = export_meta(&mut inst.ctx, &args.nodes, args.area,
+		args.invert)?;
+	let count = output.as_object().map_or(0, |o| o.len() as u64);
+	let writer = std::io::BufWriter::new(file);
+	serde_json::to_writer_pretty(writer, &output).map_err(|e|
+		CommandError::Failed(format!("Failed to write output file: {}", e)))?;
+	Ok(Summary::new(count))
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: true,
+		args: vec![
+			(ArgType::JsonFile, "Path of the JSON file to create"),
+			(ArgType::Nodes,
+				"Names of one or more nodes to export metadata of. If not \
+				specified, metadata of all nodes will be exported."),
+			(ArgType::Area(false), "Area in which to export node metadata"),
+			(ArgType::Invert,
+				"Export node metadata *outside* the given area."),
+		],
+		help: "Export node metadata to a JSON file."
+	}
+}
@@ -0,0 +1,125 @@
+use serde_json::{Map, Value};
+
+use super::{Command, WorldDb, CommandError, CommandResult, Summary,
+	ensure_arg, is_valid_mod_name};
+
+use crate::instance::{ArgType, InstBundle, Progress};
+use crate::mod_storage_database::{ModStorageDatabase, StorageEntry};
+use crate::utils::fmt_big_num;
+
+
+/// Convert entries to a JSON object of the form `{mod: {key: value}}`.
+/// Entries which are not valid UTF-8 are skipped; returns the number of
+/// skipped entries along with the object.
+fn entries_to_json(entries: Vec<StorageEntry>) -> (Map<String, Value>, u64) {
+	let mut output = Map::new();
+	let mut invalid = 0;
+
+	for e in entries {
+		let (key, value) = match (String::from_utf8(e.key),
+			String::from_utf8(e.value))
+		{
+			(Ok(k), Ok(v)) => (k, v),
+			_ => {
+				invalid += 1;
+				continue;
+			}
+		};
+		if let Value::Object(obj) = output.entry(e.mod_name)
+			.or_insert_with(|| Value::Object(Map::new()))
+		{
+			obj.insert(key, Value::String(value));
+		}
+	}
+	(output, invalid)
+}
+
+
+/// Export the data stored by the mod `mod_name`, or by all mods if `None`,
+/// as a JSON object of the form `{mod: {key: value}}`.
+// `Option::is_none_or` needs Rust 1.82.
+#[allow(clippy::unnecessary_map_or)]
+pub fn export_mod_storage(
+	db: &mut ModStorageDatabase,
+	status: &dyn Progress,
+	mod_name: Option<&str>
+) -> Result<Value, CommandError> {
+	ensure_arg(mod_name.map_or(true, is_valid_mod_name),
+		"Invalid mod name.")?;
+	let entries = db.get_entries(mod_name).map_err(|e| CommandError::Failed(
+		format!("Failed to read mod storage: {}", e)))?;
+	let count = entries.len() as u64;
+	let (output, invalid) = entries_to_json(entries);
+
+	if invalid > 0 {
+		status.log_warning(format!(
+			"Skipped {} entries which are not valid UTF-8.",
+			fmt_big_num(invalid)));
+	}
+	status.log_info(format!("Exported {} entries of {} mods.",
+		fmt_big_num(count - invalid), fmt_big_num(output.len() as u64)));
+	Ok(Value::Object(output))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	let path = args.json_file.as_ref().unwrap();
+	// Create the file first, to fail before doing any work.
+	let file = std::fs::File::create(path).map_err(|e| CommandError::Failed(
+		format!("Failed to create output file: {}", e)))?;
+
+	let output = export_mod_storage(inst.msdb.as_mut().unwrap(),
+		inst.ctx.status, args.mod_name.as_deref())?;
+	let count = output.as_object().map_or(0, |mods| mods.values()
+		.filter_map(Value::as_object).map(|m| m.len() as u64).sum());
+	let writer = std::io::BufWriter::new(file);
+	serde_json::to_writer_pretty(writer, &output).map_err(|e|
+		CommandError::Failed(format!("Failed to write output file: {}", e)))?;
+	Ok(Summary::new(count))
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[WorldDb::ModStorage],
+		optional_dbs: &[],
+		read_only: true,
+		args: vec![
+			(ArgType::JsonFile, "Path of the JSON file to create"),
+			(ArgType::ModName(false),
+				"Name of the mod to export data of. If not specified, data \
+				of all mods will be exported."),
+		],
+		help: "Export mod storage data to a JSON file."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[test]
+	fn test_entries_to_json() {
+		let entry = |m: &str, k: &[u8], v: &[u8]| StorageEntry {
+			mod_name: m.to_string(),
+			key: k.to_vec(),
+			value: v.to_vec(),
+		};
+		let (output, invalid) = entries_to_json(vec![
+			entry("mod_a", b"x", b"1"),
+			entry("mod_a", b"y", b"{\"a\": 2}"),
+			entry("mod_a", b"bad", b"\xFF"),
+			entry("mod_b", b"x", b""),
+		]);
+		assert_eq!(invalid, 1);
+		assert_eq!(Value::Object(output), json!({
+			"mod_a": {"x": "1", "y": "{\"a\": 2}"},
+			"mod_b": {"x": ""},
+		}));
+	}
+}
@@ -1,11 +1,14 @@
-use super::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, CommandResult, Summary, check_area, check_names};
 
 use crate::unwrap_or;
 use crate::spatial::{Vec3, Area, InverseBlockIterator};
-use crate::instance::{ArgType, InstBundle};
+use crate::instance::{ArgType, InstBundle, EditContext};
 use crate::map_block::MapBlock;
 use crate::block_utils::clean_name_id_map;
-use crate::utils::{query_keys, to_bytes, fmt_big_num};
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
 
 
 fn fill_area(block: &mut MapBlock, id: u16, area: Area, invert: bool) {
@@ -27,54 +30,73 @@ fn fill_area(block: &mut MapBlock, id: u16, area: Area, invert: bool) {
 }
 
 
-fn fill(inst: &mut InstBundle) {
-	let area = inst.args.area.unwrap();
-	let node = to_bytes(inst.args.new_node.as_ref().unwrap());
-
-	let keys = query_keys(&mut inst.db, &mut inst.status,
-		&[], Some(area), inst.args.invert, true);
-
-	inst.status.begin_editing();
-
-	let mut count: u64 = 0;
-	for key in keys {
-		inst.status.inc_done();
-
-		let pos = Vec3::from_block_key(key);
-		let data = inst.db.get_block(key).unwrap();
-		let mut block = unwrap_or!(MapBlock::deserialize(&data),
-			{ inst.status.inc_failed(); continue; });
-
-		if area.contains_block(pos) != area.touches_block(pos) {
-			// Fill part of block
-			let block_part = area.rel_block_overlap(pos).unwrap();
-			let fill_id = block.nimap.get_id(&node).unwrap_or_else(|| {
-				let next = block.nimap.get_max_id().unwrap() + 1;
-				block.nimap.0.insert(next, node.to_vec());
-				next
-			});
-			fill_area(&mut block, fill_id, block_part, inst.args.invert);
-			clean_name_id_map(&mut block);
-			count += block_part.volume();
-		} else { // Fill entire block
-			block.node_data.nodes.fill(0);
-			block.nimap.0.clear();
-			block.nimap.0.insert(0, node.to_vec());
-			count += block.node_data.nodes.len() as u64;
+/// Set all generated nodes inside or outside an area to `new_node`. Returns
+/// the number of nodes filled.
+pub fn fill(ctx: &mut EditContext, area: Area, invert: bool, new_node: &str)
+	-> CommandResult
+{
+	check_area(Some(area), invert)?;
+	check_names(&[new_node], "node")?;
+
+	let node = to_bytes(new_node);
+
+	let blocks = query_blocks(&ctx.db, None, ctx.status,
+		&[], Some(area), invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let count = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|key, data| {
+			let pos = Vec3::from_block_key(key);
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			if area.contains_block(pos) != area.touches_block(pos) {
+				// Fill part of block
+				let block_part = area.rel_block_overlap(pos).unwrap();
+				let fill_id = block.nimap.get_id(&node).unwrap_or_else(|| {
+					let next = block.nimap.get_max_id().unwrap() + 1;
+					block.nimap.0.insert(next, node.to_vec());
+					next
+				});
+				fill_area(&mut block, fill_id, block_part, invert);
+				clean_name_id_map(&mut block);
+				count.fetch_add(block_part.volume(), Ordering::Relaxed);
+			} else { // Fill entire block
+				block.node_data.nodes.fill(0);
+				block.nimap.0.clear();
+				block.nimap.0.insert(0, node.to_vec());
+				count.fetch_add(block.node_data.nodes.len() as u64,
+					Ordering::Relaxed);
+			}
+
+			BlockAction::Write(block.serialize(level))
 		}
+	)?;
+
+	let count = count.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("{} nodes filled.", fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
 
-		inst.db.set_block(key, &block.serialize()).unwrap();
-	}
 
-	inst.status.end_editing();
-	inst.status.log_info(format!("{} nodes filled.", fmt_big_num(count)));
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	fill(&mut inst.ctx, args.area.unwrap(), args.invert,
+		args.new_node.as_ref().unwrap())
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: fill,
+		func: run,
 		verify_args: None,
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
 			(ArgType::Area(true), "Area to fill"),
 			(ArgType::Invert,
@@ -0,0 +1,92 @@
+use super::{Command, ArgResult, WorldDb, CommandError, CommandResult,
+	Summary, check_player_names};
+
+use crate::instance::{ArgType, InstArgs, InstBundle, Progress};
+use crate::auth_database::AuthDatabase;
+
+
+fn check_privs(privs: &[String]) -> Result<(), CommandError> {
+	for p in privs {
+		if p.is_empty()
+			|| !p.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+		{
+			return Err(CommandError::InvalidArgs(
+				format!("Invalid privilege name: {}", p)));
+		}
+	}
+	Ok(())
+}
+
+
+/// Grant (or revoke) privileges, returning the ones which were changed.
+pub(super) fn edit_privs(
+	db: &mut AuthDatabase,
+	player: &str,
+	privs: &[String],
+	grant: bool
+) -> Result<Vec<String>, CommandError> {
+	check_player_names(&[player])?;
+	check_privs(privs)?;
+
+	let id = db.get_id(player)?.ok_or_else(|| CommandError::Failed(
+		format!("Player \"{}\" does not exist.", player)))?;
+	let mut changed = Vec::new();
+
+	for p in privs {
+		let modified = if grant {
+			db.add_privilege(id, p)?
+		} else {
+			db.remove_privilege(id, p)?
+		};
+		if modified && !changed.contains(p) {
+			changed.push(p.clone());
+		}
+	}
+	Ok(changed)
+}
+
+
+/// Grant privileges to a player.
+pub fn grant_privs(
+	db: &mut AuthDatabase,
+	status: &dyn Progress,
+	player: &str,
+	privs: &[String]
+) -> CommandResult {
+	let granted = edit_privs(db, player, privs, true)?;
+	if granted.is_empty() {
+		status.log_info(format!("{} already has these privileges.", player));
+	} else {
+		status.log_info(format!("Granted privileges to {}: {}", player,
+			granted.join(", ")));
+	}
+	Ok(Summary::new(granted.len() as u64))
+}
+
+
+pub(super) fn verify_args(args: &InstArgs) -> ArgResult {
+	check_privs(&args.privs).into()
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	grant_privs(inst.adb.as_mut().unwrap(), inst.ctx.status,
+		args.player.as_ref().unwrap(), &args.privs)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: Some(verify_args),
+		world_dbs: &[WorldDb::Auth],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::Player, "Name of the player"),
+			(ArgType::Privs, "Names of one or more privileges to grant"),
+		],
+		help: "Grant privileges to a player."
+	}
+}
@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde_json::Value;
+
+use super::{Command, CommandError, CommandResult, Summary};
+
+use crate::unwrap_or;
+use crate::spatial::Vec3;
+use crate::instance::{ArgType, InstBundle, EditContext};
+use crate::map_database::DBError;
+use crate::map_block::{MapBlock, NodeMetadata, Inventory};
+use crate::utils::fmt_big_num;
+
+
+const EMPTY_INV: &str = "EndInventory\n";
+
+
+/// Metadata of one node, as read from the JSON file.
+struct MetaEntry {
+	idx: u16,
+	node: Option<Vec<u8>>,
+	meta: NodeMetadata
+}
+
+
+fn parse_pos(key: &str) -> Option<Vec3> {
+	let coords = key.split(',')
+		.map(|c| c.trim().parse().ok())
+		.collect::<Option<Vec<i32>>>()?;
+	match coords.as_slice() {
+		&[x, y, z] => Some(Vec3::new(x, y, z))
+			.filter(Vec3::is_valid_node_pos),
+		_ => None
+	}
+}
+
+
+fn parse_entry(value: &Value) -> Result<(Option<Vec<u8>>, NodeMetadata), String>
+{
+	let obj = value.as_object().ok_or("expected an object")?;
+	let get_str = |key: &str| match obj.get(key) {
+		None | Some(Value::Null) => Ok(None),
+		Some(Value::String(s)) => Ok(Some(s.as_str())),
+		_ => Err(format!("\"{}\" must be a string", key))
+	};
+
+	let node = get_str("node")?.map(|n| n.as_bytes().to_vec());
+	let private: Vec<&str> = match obj.get("private") {
+		None | Some(Value::Null) => Vec::new(),
+		Some(Value::Array(a)) => a.iter().map(Value::as_str)
+			.collect::<Option<_>>()
+			.ok_or("\"private\" must be a list of strings")?,
+		_ => return Err("\"private\" must be a list of strings".to_string())
+	};
+
+	let mut vars = HashMap::new();
+	match obj.get("vars") {
+		None | Some(Value::Null) => {},
+		Some(Value::Object(o)) => for (key, value) in o {
+			let value = value.as_str()
+				.ok_or(format!("value of \"{}\" must be a string", key))?;
+			vars.insert(key.as_bytes().to_vec(), (value.as_bytes().to_vec(),
+				private.contains(&key.as_str())));
+		},
+		_ => return Err("\"vars\" must be an object".to_string())
+	}
+
+	let inv = get_str("inventory")?.unwrap_or(EMPTY_INV).as_bytes().to_vec();
+	if Inventory::deserialize(&inv).is_err() {
+		return Err("invalid inventory".to_string());
+	}
+
+	Ok((node, NodeMetadata { vars, inv }))
+}
+
+
+/// Parse and validate the whole JSON object, grouping entries by mapblock.
+fn parse_json(data: &Value) -> Result<BTreeMap<i64, Vec<MetaEntry>>, String> {
+	let obj = data.as_object().ok_or("Expected a JSON object.")?;
+	let mut blocks: BTreeMap<_, Vec<_>> = BTreeMap::new();
+	let mut seen = HashSet::new();
+
+	for (key, value) in obj {
+		let pos = parse_pos(key)
+			.ok_or(format!("Invalid node position: \"{}\".", key))?;
+		let (node, meta) = parse_entry(value)
+			.map_err(|e| format!("Invalid metadata at {}: {}.", pos, e))?;
+
+		let block_pos = pos.map(|c| c.div_euclid(16));
+		let rel = pos - block_pos * 16;
+		let idx = (rel.x + rel.y * 16 + rel.z * 256) as u16;
+		let block_key = block_pos.to_block_key();
+		// Keys such as "0,0,0" and "0, 0, 0" are the same position.
+		if !seen.insert((block_key, idx)) {
+			return Err(format!("Duplicate node position: {}.", pos));
+		}
+		blocks.entry(block_key).or_default()
+			.push(MetaEntry { idx, node, meta });
+	}
+
+	Ok(blocks)
+}
+
+
+/// Import node metadata from a JSON object in the format written by
+/// `export_meta`, replacing the metadata of each listed node. Nodes whose
+/// name doesn't match the `node` field are skipped. If the data is invalid,
+/// an error is returned and nothing is changed.
+pub fn import_meta(ctx: &mut EditContext, data: &Value) -> CommandResult {
+	let blocks = parse_json(data).map_err(CommandError::InvalidArgs)?;
+
+	ctx.status.set_total(blocks.len());
+	ctx.status.begin_editing();
+	let mut count = 0;
+	let mut missing = 0;
+	let mut mismatched = 0;
+
+	for (key, entries) in blocks {
+		ctx.status.inc_done();
+		let data = match ctx.db.get_block(key) {
+			Ok(d) => d,
+			Err(DBError::MissingData) => {
+				missing += entries.len() as u64;
+				continue;
+			},
+			Err(e) => {
+				ctx.status.end_editing();
+				return Err(e.into());
+			}
+		};
+		let mut block = unwrap_or!(MapBlock::deserialize(&data),
+			{ ctx.status.inc_failed(); continue; });
+		let mut modified = false;
+
+		for entry in entries {
+			let id = block.node_data.nodes[entry.idx as usize];
+			if let Some(node) = &entry.node {
+				if block.nimap.0.get(&id) != Some(node) {
+					mismatched += 1;
+					continue;
+				}
+			}
+			// Note: serialize() will cull any empty metadata.
+			block.metadata.insert(entry.idx, entry.meta);
+			count += 1;
+			modified = true;
+		}
+
+		if modified {
+			ctx.db.set_block(key, &block.serialize(ctx.compression))?;
+		}
+	}
+
+	ctx.status.end_editing();
+	if missing > 0 {
+		ctx.status.log_warning(format!(
+			"Skipped {} nodes in mapblocks which don't exist.",
+			fmt_big_num(missing)));
+	}
+	if mismatched > 0 {
+		ctx.status.log_warning(format!(
+			"Skipped {} nodes which don't match the node name in the file.",
+			fmt_big_num(mismatched)));
+	}
+	ctx.status.log_info(format!("Imported metadata of {} nodes.",
+		fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let path = inst.args.json_file.as_ref().unwrap();
+	let text = std::fs::read_to_string(path).map_err(|e|
+		CommandError::Failed(format!("Failed to read input file: {}", e)))?;
+	let data = serde_json::from_str(&text).map_err(|e|
+		CommandError::InvalidArgs(format!("Failed to parse input file: {}",
+			e)))?;
+	import_meta(&mut inst.ctx, &data)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::JsonFile,
+				"Path of a JSON file in the format written by exportmeta"),
+		],
+		help: "Import node metadata from a JSON file."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[test]
+	fn test_parse_json() {
+		let data = json!({
+			"-1,17,32": {
+				"node": "default:sign_wall_wood",
+				"vars": {"text": "Hello", "secret": "42"},
+				"private": ["secret"],
+			},
+			"0, 0, 0": {
+				"vars": {},
+				"inventory": "List main 1\nWidth 0\nEmpty\n\
+					EndInventoryList\nEndInventory\n",
+			},
+		});
+		let blocks = parse_json(&data).unwrap();
+		assert_eq!(blocks.len(), 2);
+
+		let sign = &blocks[&Vec3::new(-1, 1, 2).to_block_key()][0];
+		assert_eq!(sign.idx, 15 + 16); // (15, 1, 0) within the block
+		assert_eq!(sign.node.as_deref(), Some(&b"default:sign_wall_wood"[..]));
+		assert_eq!(sign.meta.vars[&b"secret"[..]], (b"42".to_vec(), true));
+		assert_eq!(sign.meta.vars[&b"text"[..]], (b"Hello".to_vec(), false));
+		assert_eq!(sign.meta.inv, EMPTY_INV.as_bytes());
+
+		let chest = &blocks[&0][0];
+		assert_eq!(chest.idx, 0);
+		assert!(chest.node.is_none());
+		assert!(chest.meta.vars.is_empty());
+
+		for bad in &[
+			json!([]),
+			json!({"1,2": {}}),
+			json!({"1,2,x": {}}),
+			json!({"0,40000,0": {}}),
+			json!({"1,2,3": {"vars": {"a": 1}}}),
+			json!({"1,2,3": {"private": "a"}}),
+			json!({"1,2,3": {"inventory": "List main 1\n"}}),
+			json!({"0,0,0": {}, "0, 0, 0": {}}),
+		] {
+			assert!(parse_json(bad).is_err());
+		}
+	}
+
+	#[test]
+	fn test_export_import() {
+		use crate::map_database::MapDatabase;
+		use crate::map_block::CompressionLevel;
+		use crate::commands::export_meta;
+		use crate::utils::{read_test_file, create_test_map, TestProgress};
+
+		let original =
+			MapBlock::deserialize(&read_test_file("mapblock_v29.bin")).unwrap();
+		assert!(!original.metadata.is_empty());
+		let mut cleared = original.clone();
+		cleared.metadata.clear();
+		let key = Vec3::new(-1, 2, 3).to_block_key();
+
+		let conn = create_test_map();
+		let progress = TestProgress::default();
+		let mut ctx = EditContext::new(MapDatabase::new(&conn).unwrap(),
+			&progress);
+		ctx.db.set_block(key, &original.serialize(CompressionLevel::Default))
+			.unwrap();
+		let data = export_meta(&mut ctx, &[], None, false).unwrap();
+
+		ctx.db.set_block(key, &cleared.serialize(CompressionLevel::Default))
+			.unwrap();
+		assert_eq!(import_meta(&mut ctx, &data).unwrap().count,
+			original.metadata.len() as u64);
+		let imported =
+			MapBlock::deserialize(&ctx.db.get_block(key).unwrap()).unwrap();
+		assert_eq!(imported.metadata.len(), original.metadata.len());
+		for (idx, meta) in &original.metadata {
+			assert_eq!(imported.metadata[idx].vars, meta.vars);
+			assert_eq!(imported.metadata[idx].inv, meta.inv);
+		}
+	}
+}
@@ -0,0 +1,69 @@
+use super::{Command, WorldDb, CommandError, CommandResult, Summary};
+
+use crate::instance::{ArgType, InstBundle, Progress};
+use crate::auth_database::{AuthDatabase, Account};
+use crate::utils::{fmt_big_num, fmt_timestamp};
+
+
+/// Format an account's last login time, which may be unknown.
+fn fmt_last_login(account: &Account) -> String {
+	if account.last_login < 0 {
+		"unknown".to_string()
+	} else {
+		fmt_timestamp(account.last_login)
+	}
+}
+
+
+/// List player accounts, along with their last login times and privileges.
+/// If `before` is given, only accounts which haven't logged in since that
+/// Unix timestamp are listed.
+pub fn list_accounts(
+	db: &mut AuthDatabase,
+	status: &dyn Progress,
+	before: Option<i64>
+) -> CommandResult {
+	let accounts = db.get_accounts().map_err(|e| CommandError::Failed(
+		format!("Failed to read accounts: {}", e)))?;
+	let mut count = 0;
+
+	for account in &accounts {
+		if before.is_some_and(|b| account.last_login >= b) {
+			continue;
+		}
+		let privs = if account.privs.is_empty() {
+			"none".to_string()
+		} else {
+			account.privs.join(", ")
+		};
+		status.log_info(format!("{}: last login {}, privileges: {}",
+			account.name, fmt_last_login(account), privs));
+		count += 1;
+	}
+
+	status.log_info(format!("Found {} accounts.", fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	list_accounts(inst.adb.as_mut().unwrap(), inst.ctx.status,
+		inst.args.before)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[WorldDb::Auth],
+		optional_dbs: &[],
+		read_only: true,
+		args: vec![
+			(ArgType::Before(false),
+				"Only list accounts which haven't logged in since this date \
+				(YYYY-MM-DD)."),
+		],
+		help: "List player accounts, their last logins and privileges."
+	}
+}
@@ -0,0 +1,55 @@
+use super::{Command, WorldDb, CommandError, CommandResult, Summary};
+use super::delete_accounts::find_inactive;
+
+use crate::instance::{ArgType, InstBundle, Progress};
+use crate::auth_database::AuthDatabase;
+use crate::utils::fmt_big_num;
+
+
+/// List the accounts which `delete_accounts` would delete with the same
+/// arguments, without changing anything.
+pub fn list_inactive_accounts(
+	adb: &mut AuthDatabase,
+	status: &dyn Progress,
+	before: i64,
+	include_unknown: bool
+) -> CommandResult {
+	let inactive = find_inactive(adb, status, before, include_unknown)
+		.map_err(|e| CommandError::Failed(
+			format!("Failed to read accounts: {}", e)))?;
+
+	if !inactive.is_empty() {
+		let names: Vec<_> = inactive.iter().map(|a| a.name.as_str()).collect();
+		status.log_info(format!("Inactive accounts:\n{}", names.join("\n")));
+	}
+	let count = inactive.len() as u64;
+	status.log_info(format!("Found {} inactive accounts.",
+		fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	list_inactive_accounts(inst.adb.as_mut().unwrap(), inst.ctx.status,
+		args.before.unwrap(), args.include_unknown)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[WorldDb::Auth],
+		optional_dbs: &[],
+		read_only: true,
+		args: vec![
+			(ArgType::Before(true),
+				"List accounts which haven't logged in since this date \
+				(YYYY-MM-DD)."),
+			(ArgType::IncludeUnknown,
+				"Also list accounts with an unknown last login time."),
+		],
+		help: "List the accounts which players delete would delete."
+	}
+}
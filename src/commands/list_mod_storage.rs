@@ -0,0 +1,77 @@
+use super::{Command, WorldDb, CommandError, CommandResult, Summary,
+	ensure_arg, is_valid_mod_name};
+
+use crate::instance::{ArgType, InstBundle, Progress};
+use crate::mod_storage_database::ModStorageDatabase;
+use crate::map_database::DBError;
+use crate::utils::fmt_big_num;
+
+
+fn do_list(
+	db: &mut ModStorageDatabase,
+	status: &dyn Progress,
+	mod_name: Option<&str>
+) -> Result<u64, DBError> {
+	Ok(match mod_name {
+		Some(m) => {
+			let entries = db.get_entries(Some(m))?;
+			for e in &entries {
+				status.log_info(format!("{}: {}",
+					String::from_utf8_lossy(&e.key),
+					String::from_utf8_lossy(&e.value)));
+			}
+			let count = entries.len() as u64;
+			status.log_info(format!("Found {} entries.", fmt_big_num(count)));
+			count
+		},
+		None => {
+			let mods = db.get_mods()?;
+			for (name, count) in &mods {
+				status.log_info(format!("{}: {} entries", name,
+					fmt_big_num(*count)));
+			}
+			let count = mods.len() as u64;
+			status.log_info(format!("Found {} mods.", fmt_big_num(count)));
+			count
+		}
+	})
+}
+
+
+/// List the mods with stored data and how many entries each has, or if
+/// `mod_name` is given, list the keys and values stored by that mod.
+// `Option::is_none_or` needs Rust 1.82.
+#[allow(clippy::unnecessary_map_or)]
+pub fn list_mod_storage(
+	db: &mut ModStorageDatabase,
+	status: &dyn Progress,
+	mod_name: Option<&str>
+) -> CommandResult {
+	ensure_arg(mod_name.map_or(true, is_valid_mod_name),
+		"Invalid mod name.")?;
+	do_list(db, status, mod_name).map(Summary::new)
+		.map_err(|e| CommandError::Failed(
+			format!("Failed to read mod storage: {}", e)))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	list_mod_storage(inst.msdb.as_mut().unwrap(), inst.ctx.status,
+		inst.args.mod_name.as_deref())
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[WorldDb::ModStorage],
+		optional_dbs: &[],
+		read_only: true,
+		args: vec![
+			(ArgType::ModName(false),
+				"Name of a mod to list the stored entries of"),
+		],
+		help: "List mods with stored data, or the entries of one mod."
+	}
+}
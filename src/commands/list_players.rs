@@ -0,0 +1,64 @@
+use super::{Command, WorldDb, CommandError, CommandResult, Summary,
+	check_area};
+
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstBundle, Progress};
+use crate::player_database::PlayerDatabase;
+use crate::utils::fmt_big_num;
+
+
+/// List the players in a player database, along with their saved positions.
+/// If `area` is given, only players inside (or outside, if `invert` is set)
+/// the area are listed.
+pub fn list_players(
+	db: &mut PlayerDatabase,
+	status: &dyn Progress,
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	let players = db.get_players().map_err(|e| CommandError::Failed(
+		format!("Failed to read players: {}", e)))?;
+	let mut count = 0;
+
+	for p in players {
+		let node_pos = Vec3::new(p.pos[0].round() as i32,
+			p.pos[1].round() as i32, p.pos[2].round() as i32);
+		if let Some(a) = area {
+			if a.contains(node_pos) == invert {
+				continue;
+			}
+		}
+		status.log_info(format!(
+			"{}: ({:.1}, {:.1}, {:.1}), HP {}, last saved {}",
+			p.name, p.pos[0], p.pos[1], p.pos[2], p.hp,
+			p.modification_date));
+		count += 1;
+	}
+
+	status.log_info(format!("Found {} players.", fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	list_players(inst.pdb.as_mut().unwrap(), inst.ctx.status, args.area,
+		args.invert)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[WorldDb::Players],
+		optional_dbs: &[],
+		read_only: true,
+		args: vec![
+			(ArgType::Area(false), "Area in which to list players"),
+			(ArgType::Invert, "List players *outside* the given area."),
+		],
+		help: "List players and their saved positions."
+	}
+}
@@ -1,24 +1,235 @@
 use std::collections::BTreeMap;
 
+use crate::spatial::{Vec3, Area, MAP_LIMIT};
+use crate::map_database::DBError;
 use crate::instance::{ArgType, InstArgs, InstBundle};
 
 mod clone;
+mod delete_accounts;
 mod delete_blocks;
 mod delete_meta;
+mod delete_mod_storage;
 mod delete_objects;
 mod delete_timers;
+mod edit_inv_list;
+mod export_mod_storage;
+mod export_meta;
 mod fill;
+mod grant_privs;
+mod import_meta;
+mod list_accounts;
+mod list_inactive_accounts;
+mod list_mod_storage;
+mod list_players;
 mod overlay;
+mod prune_blocks;
+mod recompress;
+mod rename_mod_storage;
+mod rename_player;
 mod replace_in_inv;
+mod replace_in_meta;
+mod replace_in_player_inv;
 mod replace_nodes;
+mod revoke_privs;
+mod script;
+mod set_item_meta;
 mod set_meta_var;
 mod set_param2;
+mod teleport_player;
+mod upgrade_blocks;
 mod vacuum;
 
+pub use clone::clone;
+pub use delete_accounts::delete_accounts;
+pub use delete_blocks::delete_blocks;
+pub use delete_meta::delete_metadata;
+pub use delete_mod_storage::delete_mod_storage;
+pub use delete_objects::delete_objects;
+pub use delete_timers::delete_timers;
+pub use edit_inv_list::{edit_inv_list, ListEdit};
+pub use export_meta::export_meta;
+pub use export_mod_storage::export_mod_storage;
+pub use fill::fill;
+pub use grant_privs::grant_privs;
+pub use import_meta::import_meta;
+pub use list_accounts::list_accounts;
+pub use list_inactive_accounts::list_inactive_accounts;
+pub use list_mod_storage::list_mod_storage;
+pub use list_players::list_players;
+pub use overlay::overlay;
+pub use prune_blocks::prune_blocks;
+pub use recompress::recompress;
+pub use rename_mod_storage::rename_mod_storage;
+pub use rename_player::rename_player;
+pub use replace_in_inv::{replace_in_inv, ItemEdit};
+pub use replace_in_meta::replace_in_meta;
+pub use replace_in_player_inv::replace_in_player_inv;
+pub use replace_nodes::replace_nodes;
+pub use revoke_privs::revoke_privs;
+pub use script::run_script;
+pub use set_item_meta::{set_item_meta, ItemMetaEdit};
+pub use set_meta_var::set_meta_var;
+pub use set_param2::set_param2;
+pub use teleport_player::teleport_player;
+pub use upgrade_blocks::upgrade_blocks;
+pub use vacuum::vacuum;
 
+
+#[doc(hidden)]
 pub const BLOCK_CACHE_SIZE: usize = 1024;
 
 
+/// An error which stopped a command. The changes made by a failed command
+/// should not be committed.
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+	/// The command's arguments are invalid. Nothing was changed.
+	#[error("{0}")]
+	InvalidArgs(String),
+	/// A database operation failed.
+	#[error("{0}")]
+	Database(#[from] DBError),
+	/// The command failed partway through, e.g. because of a script error.
+	#[error("{0}")]
+	Failed(String),
+}
+
+
+/// The outcome of a command which finished successfully.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+	/// Number of things the command changed or listed, e.g. nodes replaced
+	/// or accounts deleted, as given in its final log message.
+	pub count: u64,
+}
+
+impl Summary {
+	pub fn new(count: u64) -> Self {
+		Self {count}
+	}
+}
+
+
+pub type CommandResult = Result<Summary, CommandError>;
+
+
+/// Return an `InvalidArgs` error if `cond` is false.
+pub(crate) fn ensure_arg(cond: bool, msg: &str) -> Result<(), CommandError> {
+	if cond {
+		Ok(())
+	} else {
+		Err(CommandError::InvalidArgs(msg.to_string()))
+	}
+}
+
+
+/// Check whether `name` is a valid node, item or entity name, i.e. "air",
+/// "ignore", or of the form "mod_name:item_name".
+pub(crate) fn is_valid_name(name: &str) -> bool {
+	if name == "air" || name == "ignore" {
+		true
+	} else {
+		let delim = match name.find(':') {
+			Some(d) => d,
+			None => return false
+		};
+
+		let mod_name = &name[..delim];
+		let item_name = &name[delim + 1..];
+
+		mod_name.chars().all(|c: char|
+			c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+		&& item_name.chars().all(|c: char|
+			c.is_ascii_alphanumeric() || c == '_')
+	}
+}
+
+
+pub(crate) fn is_valid_player_name(name: &str) -> bool {
+	// Same rules as Minetest's PLAYERNAME_ALLOWED_CHARS and PLAYERNAME_SIZE
+	!name.is_empty() && name.len() <= 20
+		&& name.chars().all(|c: char|
+			c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+
+pub(crate) fn is_valid_mod_name(name: &str) -> bool {
+	!name.is_empty() && name.chars().all(|c: char|
+		c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+
+/// Check node, item or entity names. `kind` is used in the error message,
+/// e.g. "node".
+pub(crate) fn check_names<S: AsRef<str>>(names: &[S], kind: &str)
+	-> Result<(), CommandError>
+{
+	for n in names {
+		let n = n.as_ref();
+		if !is_valid_name(n) {
+			return Err(CommandError::InvalidArgs(
+				format!("Invalid {} name: {}", kind, n)));
+		}
+	}
+	Ok(())
+}
+
+
+pub(crate) fn check_player_names<S: AsRef<str>>(names: &[S])
+	-> Result<(), CommandError>
+{
+	for n in names {
+		let n = n.as_ref();
+		if !is_valid_player_name(n) {
+			return Err(CommandError::InvalidArgs(
+				format!("Invalid player name: {}", n)));
+		}
+	}
+	Ok(())
+}
+
+
+/// Check that an area lies within the map, and that it is given if the
+/// selection is inverted.
+pub(crate) fn check_area(area: Option<Area>, invert: bool)
+	-> Result<(), CommandError>
+{
+	ensure_arg(area.is_some() || !invert,
+		"Cannot invert without a specified area.")?;
+	if let Some(a) = area {
+		for pos in &[a.min, a.max] {
+			if !pos.is_valid_node_pos() {
+				return Err(CommandError::InvalidArgs(
+					format!("Area corner is outside map bounds: {}.", pos)));
+			}
+		}
+	}
+	Ok(())
+}
+
+
+/// Check that an offset is no larger than twice the map size, and that the
+/// destination area lies at least partly within the map.
+pub(crate) fn check_offset(area: Option<Area>, offset: Vec3)
+	-> Result<(), CommandError>
+{
+	let huge = |n| !(-MAP_LIMIT * 2 ..= MAP_LIMIT * 2).contains(&n);
+	if huge(offset.x) || huge(offset.y) || huge(offset.z) {
+		return Err(CommandError::InvalidArgs(format!(
+			"Offset cannot be larger than {} nodes in any direction.",
+			MAP_LIMIT * 2)));
+	}
+
+	let map_area = Area::new(
+		Vec3::new(-MAP_LIMIT, -MAP_LIMIT, -MAP_LIMIT),
+		Vec3::new(MAP_LIMIT, MAP_LIMIT, MAP_LIMIT)
+	);
+	ensure_arg(map_area.intersection(area.unwrap_or(map_area) + offset)
+		.is_some(), "Destination area is outside map bounds.")
+}
+
+
+#[doc(hidden)]
 pub enum ArgResult {
 	Ok,
 	Warning(String),
@@ -39,15 +250,67 @@ impl ArgResult {
 	}
 }
 
+impl From<Result<(), CommandError>> for ArgResult {
+	fn from(res: Result<(), CommandError>) -> Self {
+		match res {
+			Ok(()) => Self::Ok,
+			Err(e) => Self::Error(e.to_string())
+		}
+	}
+}
+
+
+/// A database in the world directory, other than the map, which a command
+/// operates on.
+#[doc(hidden)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WorldDb {
+	Players,
+	Auth,
+	ModStorage,
+}
+
+impl WorldDb {
+	/// Name of the database file within the world directory.
+	pub fn file_name(self) -> &'static str {
+		match self {
+			Self::Players => "players.sqlite",
+			Self::Auth => "auth.sqlite",
+			Self::ModStorage => "mod_storage.sqlite",
+		}
+	}
+}
+
+
+/// Groups of related commands, with their descriptions. Commands in a group
+/// are named "<group> <command>", e.g. "players list".
+#[doc(hidden)]
+pub const COMMAND_GROUPS: &[(&str, &str)] = &[
+	("modstorage", "Manage data stored by mods in the world's mod storage \
+		database."),
+	("playerdata", "Manage saved player positions and inventories in the \
+		world's player database."),
+	("players", "Manage player accounts in the world's auth database."),
+];
+
 
+#[doc(hidden)]
 pub struct Command {
-	pub func: fn(&mut InstBundle),
+	pub func: fn(&mut InstBundle) -> CommandResult,
 	pub verify_args: Option<fn(&InstArgs) -> ArgResult>,
+	/// Other databases to open along with the map.
+	pub world_dbs: &'static [WorldDb],
+	/// Other databases to open along with the map only if they exist.
+	pub optional_dbs: &'static [WorldDb],
+	/// The command never writes to the map or other databases. These are
+	/// then opened read-only, and no confirmation is needed.
+	pub read_only: bool,
 	pub help: &'static str,
 	pub args: Vec<(ArgType, &'static str)>
 }
 
 
+#[doc(hidden)]
 pub fn get_commands() -> BTreeMap<&'static str, Command> {
 	let mut commands = BTreeMap::new();
 	macro_rules! new_cmd {
@@ -61,12 +324,34 @@ pub fn get_commands() -> BTreeMap<&'static str, Command> {
 	new_cmd!("deletemeta", delete_meta);
 	new_cmd!("deleteobjects", delete_objects);
 	new_cmd!("deletetimers", delete_timers);
+	new_cmd!("editinvlist", edit_inv_list);
+	new_cmd!("exportmeta", export_meta);
 	new_cmd!("fill", fill);
+	new_cmd!("importmeta", import_meta);
 	new_cmd!("replacenodes", replace_nodes);
 	new_cmd!("replaceininv", replace_in_inv);
+	new_cmd!("replaceinmeta", replace_in_meta);
+	new_cmd!("modstorage delete", delete_mod_storage);
+	new_cmd!("modstorage export", export_mod_storage);
+	new_cmd!("modstorage list", list_mod_storage);
+	new_cmd!("modstorage rename", rename_mod_storage);
 	new_cmd!("overlay", overlay);
+	new_cmd!("playerdata list", list_players);
+	new_cmd!("playerdata replaceininv", replace_in_player_inv);
+	new_cmd!("playerdata teleport", teleport_player);
+	new_cmd!("players delete", delete_accounts);
+	new_cmd!("players grant", grant_privs);
+	new_cmd!("players inactive", list_inactive_accounts);
+	new_cmd!("players list", list_accounts);
+	new_cmd!("players revoke", revoke_privs);
+	new_cmd!("pruneblocks", prune_blocks);
+	new_cmd!("recompress", recompress);
+	new_cmd!("renameplayer", rename_player);
+	new_cmd!("script", script);
+	new_cmd!("setitemmeta", set_item_meta);
 	new_cmd!("setmetavar", set_meta_var);
 	new_cmd!("setparam2", set_param2);
+	new_cmd!("upgradeblocks", upgrade_blocks);
 	new_cmd!("vacuum", vacuum);
 
 	commands
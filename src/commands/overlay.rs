@@ -1,34 +1,36 @@
-use super::{Command, ArgResult, BLOCK_CACHE_SIZE};
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	BLOCK_CACHE_SIZE, ensure_arg, check_area, check_offset};
 
 use crate::{unwrap_or, opt_unwrap_or};
-use crate::spatial::{Vec3, Area, MAP_LIMIT};
-use crate::instance::{ArgType, InstArgs, InstBundle};
-use crate::map_database::MapDatabase;
-use crate::map_block::{MapBlock, MapBlockError, is_valid_generated};
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
+use crate::map_database::{MapDatabase, DBError};
+use crate::map_block::{MapBlock, MapBlockError, CompressionLevel,
+	is_valid_generated, is_legacy};
 use crate::block_utils::{merge_blocks, merge_metadata, clean_name_id_map};
 use crate::utils::{query_keys, CacheMap};
 
 
+fn check_args(invert: bool, offset: Vec3) -> Result<(), CommandError> {
+	ensure_arg(!invert || offset == Vec3::new(0, 0, 0),
+		"Inverted selections cannot be offset.")
+}
+
+
 fn verify_args(args: &InstArgs) -> ArgResult {
-	if args.invert
-		&& args.offset.filter(|&ofs| ofs != Vec3::new(0, 0, 0)).is_some()
-	{
-		return ArgResult::error("Inverted selections cannot be offset.");
-	}
+	check_args(args.invert, args.offset.unwrap_or(Vec3::new(0, 0, 0))).into()
+}
 
-	let offset = args.offset.unwrap_or(Vec3::new(0, 0, 0));
-	let map_area = Area::new(
-		Vec3::new(-MAP_LIMIT, -MAP_LIMIT, -MAP_LIMIT),
-		Vec3::new(MAP_LIMIT, MAP_LIMIT, MAP_LIMIT)
-	);
 
-	if map_area.intersection(args.area.unwrap_or(map_area) + offset)
-		.is_none()
-	{
-		return ArgResult::error("Destination area is outside map bounds.");
+/// Upgrade a whole mapblock if it is too old to be written as-is.
+fn upgrade_if_legacy(data: Vec<u8>, level: CompressionLevel)
+	-> Result<Vec<u8>, MapBlockError>
+{
+	if is_legacy(&data) {
+		Ok(MapBlock::deserialize(&data)?.serialize(level))
+	} else {
+		Ok(data)
 	}
-
-	ArgResult::Ok
 }
 
 
@@ -39,37 +41,46 @@ fn verify_args(args: &InstArgs) -> ArgResult {
 /// - Area
 /// - Area + Invert
 #[inline]
-fn overlay_no_offset(inst: &mut InstBundle) {
-	let db = &mut inst.db;
-	let idb = inst.idb.as_mut().unwrap();
-	let invert = inst.args.invert;
+fn overlay_no_offset(
+	ctx: &mut EditContext,
+	idb: &mut MapDatabase,
+	area: Option<Area>,
+	invert: bool
+) -> Result<u64, DBError> {
+	let db = &mut ctx.db;
+	let level = ctx.compression;
+	let mut count = 0;
 
 	// Get keys from input database.
-	let keys = query_keys(idb, &inst.status,
-		&[], inst.args.area, invert, true);
-	inst.status.begin_editing();
+	let keys = query_keys(idb, ctx.status, &[], area, invert, true);
+	ctx.status.begin_editing();
 
 	for key in keys {
-		inst.status.inc_done();
+		ctx.status.inc_done();
 
-		if let Some(area) = inst.args.area {
+		if let Some(area) = area {
 			let pos = Vec3::from_block_key(key);
 
 			if (!invert && area.contains_block(pos))
 				|| (invert && !area.touches_block(pos))
 			{ // If possible, copy whole mapblock.
-				let data = idb.get_block(key).unwrap();
+				let data = idb.get_block(key)?;
 				if is_valid_generated(&data) {
-					db.set_block(key, &data).unwrap();
+					match upgrade_if_legacy(data, level) {
+						Ok(d) => {
+							db.set_block(key, &d)?;
+							count += 1;
+						},
+						Err(_) => ctx.status.inc_failed()
+					}
 				}
 			} else { // Copy part of mapblock
-				let res = || -> Result<(), MapBlockError> {
-					let dst_data = opt_unwrap_or!(
-						db.get_block(key).ok()
-							.filter(|d| is_valid_generated(&d)),
-						return Ok(()));
-					let src_data = idb.get_block(key).unwrap();
+				let dst_data = opt_unwrap_or!(
+					db.get_block(key).ok().filter(|d| is_valid_generated(d)),
+					continue);
+				let src_data = idb.get_block(key)?;
 
+				let res = || -> Result<Vec<u8>, MapBlockError> {
 					let mut src_block = MapBlock::deserialize(&src_data)?;
 					let mut dst_block = MapBlock::deserialize(&dst_data)?;
 
@@ -82,32 +93,42 @@ fn overlay_no_offset(inst: &mut InstBundle) {
 						merge_metadata(&dst_block.metadata, &mut src_block.metadata,
 							block_part, block_part);
 						clean_name_id_map(&mut src_block);
-						db.set_block(key, &src_block.serialize()).unwrap();
+						Ok(src_block.serialize(level))
 					} else {
 						merge_blocks(&src_block, &mut dst_block,
 							block_part, block_part);
 						merge_metadata(&src_block.metadata, &mut dst_block.metadata,
 							block_part, block_part);
 						clean_name_id_map(&mut dst_block);
-						db.set_block(key, &dst_block.serialize()).unwrap();
+						Ok(dst_block.serialize(level))
 					}
-					Ok(())
 				}();
 
-				if res.is_err() {
-					inst.status.inc_failed()
+				match res {
+					Ok(d) => {
+						db.set_block(key, &d)?;
+						count += 1;
+					},
+					Err(_) => ctx.status.inc_failed()
 				}
 			}
 		} else {
 			// No area; copy whole mapblock.
-			let data = idb.get_block(key).unwrap();
+			let data = idb.get_block(key)?;
 			if is_valid_generated(&data) {
-				db.set_block(key, &data).unwrap();
+				match upgrade_if_legacy(data, level) {
+					Ok(d) => {
+						db.set_block(key, &d)?;
+						count += 1;
+					},
+					Err(_) => ctx.status.inc_failed()
+				}
 			}
 		}
 	}
 
-	inst.status.end_editing();
+	ctx.status.end_editing();
+	Ok(count)
 }
 
 
@@ -131,30 +152,34 @@ fn get_cached(
 
 /// Overlay with offset, with or without area.
 #[inline]
-fn overlay_with_offset(inst: &mut InstBundle) {
-	let offset = inst.args.offset.unwrap();
-	let src_area = inst.args.area;
+fn overlay_with_offset(
+	ctx: &mut EditContext,
+	idb: &mut MapDatabase,
+	src_area: Option<Area>,
+	invert: bool,
+	offset: Vec3
+) -> Result<u64, DBError> {
 	let dst_area = src_area.map(|a| a + offset);
-	let idb = inst.idb.as_mut().unwrap();
+	let mut count = 0;
 
 	// Get keys from output database.
-	let dst_keys = query_keys(&mut inst.db, &inst.status,
-		&[], dst_area, inst.args.invert, true);
+	let dst_keys = query_keys(&mut ctx.db, ctx.status,
+		&[], dst_area, invert, true);
 
 	let mut src_block_cache = CacheMap::with_capacity(BLOCK_CACHE_SIZE);
 
-	inst.status.begin_editing();
+	ctx.status.begin_editing();
 	for dst_key in dst_keys {
-		inst.status.inc_done();
+		ctx.status.inc_done();
 
 		let dst_pos = Vec3::from_block_key(dst_key);
 		let dst_data = opt_unwrap_or!(
-			inst.db.get_block(dst_key).ok().filter(|d| is_valid_generated(d)),
+			ctx.db.get_block(dst_key).ok().filter(|d| is_valid_generated(d)),
 			continue
 		);
 		let mut dst_block = unwrap_or!(
 			MapBlock::deserialize(&dst_data),
-			{ inst.status.inc_failed(); continue; }
+			{ ctx.status.inc_failed(); continue; }
 		);
 
 		let dst_part_abs = dst_area.map_or(
@@ -188,27 +213,51 @@ fn overlay_with_offset(inst: &mut InstBundle) {
 		}
 
 		clean_name_id_map(&mut dst_block);
-		inst.db.set_block(dst_key, &dst_block.serialize()).unwrap();
+		ctx.db.set_block(dst_key, &dst_block.serialize(ctx.compression))?;
+		count += 1;
 	}
 
-	inst.status.end_editing();
+	ctx.status.end_editing();
+	Ok(count)
 }
 
 
-fn overlay(inst: &mut InstBundle) {
-	let offset = inst.args.offset.unwrap_or(Vec3::new(0, 0, 0));
-	if offset == Vec3::new(0, 0, 0) {
-		overlay_no_offset(inst);
+/// Copy part or all of the input map `idb` into the map being edited,
+/// shifting it by `offset`. Inverted selections cannot be offset.
+pub fn overlay(
+	ctx: &mut EditContext,
+	idb: &mut MapDatabase,
+	area: Option<Area>,
+	invert: bool,
+	offset: Vec3
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_offset(area, offset)?;
+	check_args(invert, offset)?;
+
+	let count = if offset == Vec3::new(0, 0, 0) {
+		overlay_no_offset(ctx, idb, area, invert)?
 	} else {
-		overlay_with_offset(inst);
-	}
+		overlay_with_offset(ctx, idb, area, invert, offset)?
+	};
+	Ok(Summary::new(count))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	overlay(&mut inst.ctx, inst.idb.as_mut().unwrap(), args.area, args.invert,
+		args.offset.unwrap_or(Vec3::new(0, 0, 0)))
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: overlay,
+		func: run,
 		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
 			(ArgType::InputMapPath, "Path to the source map/world"),
 			(ArgType::Area(false), "Area to copy from. If not specified, \
@@ -0,0 +1,162 @@
+use std::sync::Mutex;
+
+use super::{Command, CommandResult, Summary, check_area, check_names};
+
+use crate::unwrap_or;
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstBundle, EditContext};
+use crate::map_block::MapBlock;
+use crate::block_utils::block_contains_only;
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
+
+
+fn can_prune(block: &MapBlock, max_timestamp: u32, allow_meta: bool,
+	natural_nodes: &[Vec<u8>]) -> bool
+{
+	// Undefined timestamps (0xFFFFFFFF) are never older than anything.
+	if block.timestamp >= max_timestamp {
+		return false;
+	}
+	if !allow_meta && !block.metadata.is_empty() {
+		return false;
+	}
+	if !natural_nodes.is_empty() && !block_contains_only(block, natural_nodes) {
+		return false;
+	}
+	true
+}
+
+
+/// Delete mapblocks last saved before `max_timestamp`, which contain no node
+/// metadata (unless `allow_meta` is set) and, if `nodes` is not empty,
+/// consist entirely of the listed nodes. If `list` is set, prunable mapblocks
+/// are only listed instead of being deleted.
+pub fn prune_blocks(
+	ctx: &mut EditContext,
+	max_timestamp: u32,
+	nodes: &[String],
+	allow_meta: bool,
+	list: bool,
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(nodes, "node")?;
+
+	let natural_nodes: Vec<_> = nodes.iter().map(to_bytes).collect();
+
+	let blocks = query_blocks(&ctx.db, None, ctx.status,
+		&[], area, invert, false);
+
+	ctx.status.begin_editing();
+	let pruned = Mutex::new(Vec::new());
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|key, data| {
+			let block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			if !can_prune(&block, max_timestamp, allow_meta, &natural_nodes) {
+				return BlockAction::Keep;
+			}
+			pruned.lock().unwrap().push(key);
+			if list {
+				BlockAction::Keep
+			} else {
+				BlockAction::Delete
+			}
+		}
+	)?;
+
+	ctx.status.end_editing();
+	let mut pruned = pruned.into_inner().unwrap();
+	let count = pruned.len() as u64;
+
+	if list {
+		if !pruned.is_empty() {
+			pruned.sort_unstable();
+			let positions: Vec<_> = pruned.iter()
+				.map(|&k| Vec3::from_block_key(k).to_string()).collect();
+			ctx.status.log_info(format!("Prunable mapblocks:\n{}",
+				positions.join("\n")));
+		}
+		ctx.status.log_info(format!("Found {} prunable mapblocks.",
+			fmt_big_num(count)));
+	} else {
+		ctx.status.log_info(format!("Pruned {} mapblocks.",
+			fmt_big_num(count)));
+	}
+	Ok(Summary::new(count))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	prune_blocks(&mut inst.ctx, args.timestamp.unwrap(), &args.nodes,
+		args.allow_meta, args.list, args.area, args.invert)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::Timestamp,
+				"Game time, in seconds. Only mapblocks last saved before this \
+				time will be pruned."),
+			(ArgType::Nodes,
+				"Names of one or more \"natural\" nodes. If specified, only \
+				mapblocks consisting entirely of these nodes will be pruned."),
+			(ArgType::AllowMeta,
+				"Prune mapblocks even if they contain node metadata."),
+			(ArgType::List,
+				"List prunable mapblocks instead of deleting them."),
+			(ArgType::Area(false), "Area containing mapblocks to prune"),
+			(ArgType::Invert,
+				"Prune mapblocks fully *outside* the given area."),
+		],
+		help: "Delete old mapblocks which contain no player-made content."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::utils::read_test_file;
+
+	fn read_test_block(filename: &str) -> MapBlock {
+		MapBlock::deserialize(&read_test_file(filename)).unwrap()
+	}
+
+	#[test]
+	fn test_can_prune() {
+		// Block has timestamp 2756, no metadata, and contains only
+		// test_mod:timer and air.
+		let block = read_test_block("mapblock_v28.bin");
+		let names = |names: &[&[u8]]| -> Vec<Vec<u8>> {
+			names.iter().map(|n| n.to_vec()).collect()
+		};
+
+		assert!(can_prune(&block, 2757, false, &[]));
+		assert!(!can_prune(&block, 2756, false, &[]));
+		assert!(can_prune(&block, 10_000, false,
+			&names(&[b"air", b"test_mod:timer"])));
+		assert!(!can_prune(&block, 10_000, false, &names(&[b"air"])));
+
+		let mut with_meta = block.clone();
+		with_meta.metadata = read_test_block("mapblock_v29.bin").metadata;
+		assert!(!can_prune(&with_meta, 10_000, false, &[]));
+		assert!(can_prune(&with_meta, 10_000, true, &[]));
+
+		let mut undefined = block;
+		undefined.timestamp = 0xFFFFFFFF;
+		assert!(!can_prune(&undefined, 10_000, true, &[]));
+	}
+}
@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, CommandResult, Summary, check_area};
+
+use crate::unwrap_or;
+use crate::spatial::Area;
+use crate::instance::{ArgType, InstBundle, EditContext};
+use crate::map_block::MapBlock;
+use crate::utils::{query_blocks, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
+
+
+/// Rewrite mapblocks inside or outside an area using the context's
+/// compression level.
+pub fn recompress(ctx: &mut EditContext, area: Option<Area>, invert: bool)
+	-> CommandResult
+{
+	check_area(area, invert)?;
+	let blocks = query_blocks(&ctx.db, None, ctx.status,
+		&[], area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let count = AtomicU64::new(0);
+	let old_size = AtomicU64::new(0);
+	let new_size = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|_, data| {
+			let block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+			let new_data = block.serialize(level);
+
+			old_size.fetch_add(data.len() as u64, Ordering::Relaxed);
+			new_size.fetch_add(new_data.len() as u64, Ordering::Relaxed);
+			if new_data == data {
+				BlockAction::Keep
+			} else {
+				count.fetch_add(1, Ordering::Relaxed);
+				BlockAction::Write(new_data)
+			}
+		}
+	)?;
+
+	let old_size = old_size.into_inner();
+	let new_size = new_size.into_inner();
+
+	let count = count.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("Recompressed {} mapblocks.",
+		fmt_big_num(count)));
+	let (change, amount) = if new_size <= old_size {
+		("saved", old_size - new_size)
+	} else {
+		("added", new_size - old_size)
+	};
+	ctx.status.log_info(format!(
+		"Total size of mapblocks: {} bytes -> {} bytes ({} bytes {}).",
+		fmt_big_num(old_size), fmt_big_num(new_size),
+		fmt_big_num(amount), change));
+	Ok(Summary::new(count))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	recompress(&mut inst.ctx, inst.args.area, inst.args.invert)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::Area(false), "Area in which to recompress mapblocks"),
+			(ArgType::Invert,
+				"Recompress mapblocks *outside* the given area."),
+		],
+		help: "Rewrite mapblocks using the chosen compression level."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::spatial::Vec3;
+	use crate::map_database::MapDatabase;
+	use crate::map_block::CompressionLevel;
+	use crate::utils::{read_test_file, create_test_map, assert_same_contents,
+		TestProgress};
+
+	#[test]
+	fn test_recompress() {
+		// One zstd (v29) and one zlib (v28) mapblock.
+		let files = ["mapblock_v29.bin", "mapblock_v28.bin"];
+		let keys = [Vec3::new(0, 0, 0), Vec3::new(1, 0, 0)]
+			.map(|p| p.to_block_key());
+
+		for &level in &[CompressionLevel::Fast, CompressionLevel::Default,
+			CompressionLevel::Best]
+		{
+			let conn = create_test_map();
+			let progress = TestProgress::default();
+			let mut ctx = EditContext::new(MapDatabase::new(&conn).unwrap(),
+				&progress);
+			ctx.compression = level;
+			for (&key, file) in keys.iter().zip(&files) {
+				ctx.db.set_block(key, &read_test_file(file)).unwrap();
+			}
+			recompress(&mut ctx, None, false).unwrap();
+
+			for (&key, file) in keys.iter().zip(&files) {
+				let old = MapBlock::deserialize(&read_test_file(file))
+					.unwrap();
+				let new = MapBlock::deserialize(&ctx.db.get_block(key)
+					.unwrap()).unwrap();
+				assert_eq!(new.version, old.version);
+				assert_same_contents(&new, &old);
+			}
+		}
+	}
+}
@@ -0,0 +1,75 @@
+use super::{Command, ArgResult, WorldDb, CommandError, CommandResult,
+	Summary, ensure_arg, is_valid_mod_name};
+
+use crate::instance::{ArgType, InstArgs, InstBundle, Progress};
+use crate::mod_storage_database::ModStorageDatabase;
+use crate::utils::fmt_big_num;
+
+
+fn check_args(mod_name: &str, new_mod_name: &str)
+	-> Result<(), CommandError>
+{
+	ensure_arg(mod_name != new_mod_name,
+		"mod_name and new_mod_name cannot be the same.")
+}
+
+
+/// Move the data stored by the mod `mod_name` to `new_mod_name`, e.g. after
+/// renaming a mod. Nothing is changed if `new_mod_name` already has stored
+/// data.
+pub fn rename_mod_storage(
+	db: &mut ModStorageDatabase,
+	status: &dyn Progress,
+	mod_name: &str,
+	new_mod_name: &str
+) -> CommandResult {
+	ensure_arg(is_valid_mod_name(mod_name) && is_valid_mod_name(new_mod_name),
+		"Invalid mod name.")?;
+	check_args(mod_name, new_mod_name)?;
+	let db_error = |e| CommandError::Failed(
+		format!("Failed to rename mod storage: {}", e));
+
+	let mods = db.get_mods().map_err(db_error)?;
+	if mods.iter().any(|(m, _)| m == new_mod_name) {
+		// Merging could silently overwrite entries of the other mod.
+		return Err(CommandError::Failed(format!(
+			"Mod \"{}\" already has stored data.", new_mod_name)));
+	}
+
+	let count = db.rename_mod(mod_name, new_mod_name).map_err(db_error)?;
+	if count == 0 {
+		status.log_warning(format!("Mod \"{}\" has no stored data.",
+			mod_name));
+	}
+	status.log_info(format!("Moved {} entries.", fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
+
+
+fn verify_args(args: &InstArgs) -> ArgResult {
+	check_args(args.mod_name.as_deref().unwrap_or_default(),
+		args.new_mod_name.as_deref().unwrap_or_default()).into()
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	rename_mod_storage(inst.msdb.as_mut().unwrap(), inst.ctx.status,
+		args.mod_name.as_ref().unwrap(), args.new_mod_name.as_ref().unwrap())
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: Some(verify_args),
+		world_dbs: &[WorldDb::ModStorage],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::ModName(true), "Current name of the mod"),
+			(ArgType::NewModName, "New name of the mod"),
+		],
+		help: "Move the stored data of a mod to a new mod name."
+	}
+}
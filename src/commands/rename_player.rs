@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	ensure_arg, check_area, check_names, check_player_names};
+
+use crate::unwrap_or;
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
+use crate::map_block::{MapBlock, MapBlockError, Inventory, ItemMeta};
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
+
+
+/// Replace each occurrence of the player name `old` in `src` with `new`.
+/// Occurrences which are part of a longer name, e.g. "alice" in "alice2",
+/// are ignored. Returns `None` if there are no occurrences.
+fn replace_name(src: &[u8], old: &[u8], new: &[u8]) -> Option<Vec<u8>> {
+	let is_name_char = |c: &u8| c.is_ascii_alphanumeric() || b"_-".contains(c);
+	let mut res = Vec::new();
+	let mut last = 0;
+	let mut i = 0;
+
+	while i + old.len() <= src.len() {
+		if &src[i .. i + old.len()] == old
+			&& (i == 0 || !is_name_char(&src[i - 1]))
+			&& !src.get(i + old.len()).is_some_and(is_name_char)
+		{
+			res.extend_from_slice(&src[last..i]);
+			res.extend_from_slice(new);
+			i += old.len();
+			last = i;
+		} else {
+			i += 1;
+		}
+	}
+
+	if last == 0 {
+		return None;
+	}
+	res.extend_from_slice(&src[last..]);
+	Some(res)
+}
+
+
+/// Rename the player `old` in the value of the metadata variable `key`.
+/// Player names are only replaced inside the text of `infotext` and
+/// `formspec`; `members` is a space-separated list of names, as used by
+/// protectors. Any other value is only replaced if it is exactly `old`, e.g.
+/// `owner`.
+fn rename_var(key: &[u8], value: &[u8], old: &[u8], new: &[u8])
+	-> Option<Vec<u8>>
+{
+	match key {
+		b"infotext" | b"formspec" => replace_name(value, old, new),
+		b"members" => {
+			if !value.split(|&c| c == b' ').any(|n| n == old) {
+				return None;
+			}
+			let names: Vec<_> = value.split(|&c| c == b' ')
+				.map(|n| if n == old { new } else { n })
+				.collect();
+			Some(names.join(&b' '))
+		}
+		_ if value == old => Some(new.to_vec()),
+		_ => None
+	}
+}
+
+
+/// Rename a player in the metadata variables of a node. Returns `true` if
+/// any variable was changed.
+fn rename_in_vars(vars: &mut HashMap<Vec<u8>, (Vec<u8>, bool)>, old: &[u8],
+	new: &[u8]) -> bool
+{
+	let mut modified = false;
+	for (key, (value, _)) in vars {
+		if let Some(v) = rename_var(key, value, old, new) {
+			*value = v;
+			modified = true;
+		}
+	}
+	modified
+}
+
+
+/// Rename a player in the metadata of all items in a serialized inventory.
+/// Returns `true` if anything was changed, or an error if the inventory
+/// can't be read.
+fn rename_in_inv(inv_data: &mut Vec<u8>, old: &[u8], new: &[u8])
+	-> Result<bool, MapBlockError>
+{
+	let mut inv = Inventory::deserialize(inv_data)?;
+	let mut inv_modified = false;
+	for item in inv.items_mut().filter(|i| !i.meta.is_empty()) {
+		let mut item_meta = unwrap_or!(ItemMeta::deserialize(&item.meta),
+			continue);
+		let mut item_modified = false;
+		for (key, value) in &mut item_meta.vars {
+			if let Some(v) = rename_var(key, value, old, new) {
+				*value = v;
+				item_modified = true;
+			}
+		}
+		if item_modified {
+			item.meta = item_meta.serialize();
+			inv_modified = true;
+		}
+	}
+	if inv_modified {
+		*inv_data = inv.serialize();
+	}
+	Ok(inv_modified)
+}
+
+
+/// Rename the player `old` to `new` in node metadata, e.g. owners of locked
+/// chests and protectors, and in item metadata inside node inventories. If
+/// `nodes` is not empty, only metadata of the listed nodes is modified.
+pub fn rename_player(
+	ctx: &mut EditContext,
+	old: &str,
+	new: &str,
+	nodes: &[String],
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(nodes, "node")?;
+	check_player_names(&[old, new])?;
+	check_args(old, new)?;
+
+	let old = to_bytes(old);
+	let new = to_bytes(new);
+	let nodes: Vec<_> = nodes.iter().map(to_bytes).collect();
+
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		&nodes, area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let counts = Mutex::new(BTreeMap::new());
+	let unreadable = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|block_key, data| {
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			let node_ids: Vec<_> = nodes.iter()
+				.filter_map(|n| block.nimap.get_id(n)).collect();
+			if !nodes.is_empty() && node_ids.is_empty() {
+				// Block doesn't contain any of the required nodes.
+				return BlockAction::Keep;
+			}
+
+			let block_corner = Vec3::from_block_key(block_key) * 16;
+			let mut modified = Vec::new();
+
+			for (&idx, data) in &mut block.metadata {
+				let pos = Vec3::from_u16_key(idx);
+				if let Some(a) = area {
+					if a.contains(pos + block_corner) == invert {
+						continue;
+					}
+				}
+				let id = block.node_data.nodes[idx as usize];
+				if !node_ids.is_empty() && !node_ids.contains(&id) {
+					continue;
+				}
+
+				let vars_modified = rename_in_vars(&mut data.vars, &old, &new);
+				let inv_modified = unwrap_or!(
+					rename_in_inv(&mut data.inv, &old, &new), {
+						unreadable.fetch_add(1, Ordering::Relaxed);
+						false
+					});
+				if vars_modified || inv_modified {
+					modified.push(id);
+				}
+			}
+
+			if modified.is_empty() {
+				BlockAction::Keep
+			} else {
+				let mut counts = counts.lock().unwrap();
+				for id in modified {
+					let name = block.nimap.0.get(&id).cloned()
+						.unwrap_or_default();
+					*counts.entry(name).or_insert(0u64) += 1;
+				}
+				BlockAction::Write(block.serialize(level))
+			}
+		}
+	)?;
+
+	ctx.status.end_editing();
+	let unreadable = unreadable.into_inner();
+	if unreadable > 0 {
+		ctx.status.log_warning(format!(
+			"Skipped {} node inventories which could not be read.",
+			fmt_big_num(unreadable)));
+	}
+	let counts = counts.into_inner().unwrap();
+	for (name, count) in &counts {
+		ctx.status.log_info(format!("{}: {} nodes",
+			String::from_utf8_lossy(name), fmt_big_num(*count)));
+	}
+	let count = counts.values().sum();
+	ctx.status.log_info(format!("Renamed player in metadata of {} nodes.",
+		fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
+
+
+fn check_args(old: &str, new: &str) -> Result<(), CommandError> {
+	ensure_arg(old != new, "player and new_player cannot be the same.")
+}
+
+
+fn verify_args(args: &InstArgs) -> ArgResult {
+	check_args(args.player.as_deref().unwrap_or_default(),
+		args.new_player.as_deref().unwrap_or_default()).into()
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	rename_player(&mut inst.ctx, args.player.as_ref().unwrap(),
+		args.new_player.as_ref().unwrap(), &args.nodes, args.area,
+		args.invert)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::Player, "Current name of the player"),
+			(ArgType::NewPlayer, "New name of the player"),
+			(ArgType::Nodes, "Names of one or more nodes to modify"),
+			(ArgType::Area(false), "Area in which to modify node metadata"),
+			(ArgType::Invert, "Modify node metadata *outside* the given area."),
+		],
+		help: "Rename a player in node and item metadata."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::map_database::MapDatabase;
+	use crate::map_block::CompressionLevel;
+	use crate::utils::{read_test_file, create_test_map, TestProgress};
+
+	#[test]
+	fn test_replace_name() {
+		let r = |s: &[u8]| replace_name(s, b"alice", b"bob");
+		assert_eq!(r(b"alice"), Some(b"bob".to_vec()));
+		assert_eq!(r(b"Locked Chest (owned by alice)"),
+			Some(b"Locked Chest (owned by bob)".to_vec()));
+		assert_eq!(r(b"alice carol alice"), Some(b"bob carol bob".to_vec()));
+		assert_eq!(r(b"alice,alice"), Some(b"bob,bob".to_vec()));
+		assert_eq!(r(b"alice2 malice alice-x x_alice Alice"), None);
+		assert_eq!(r(b""), None);
+	}
+
+	#[test]
+	fn test_rename_var() {
+		let r = |k: &[u8], v: &[u8]| rename_var(k, v, b"alice", b"bob");
+		assert_eq!(r(b"owner", b"alice"), Some(b"bob".to_vec()));
+		assert_eq!(r(b"owner", b"alice2"), None);
+		assert_eq!(r(b"text", b"Hello alice"), None);
+		assert_eq!(r(b"infotext", b"Chest (owned by alice)"),
+			Some(b"Chest (owned by bob)".to_vec()));
+		assert_eq!(r(b"formspec", b"label[0,0;Owner: alice]"),
+			Some(b"label[0,0;Owner: bob]".to_vec()));
+		assert_eq!(r(b"members", b"carol alice dave"),
+			Some(b"carol bob dave".to_vec()));
+		assert_eq!(r(b"members", b"carol alice-x"), None);
+	}
+
+	#[test]
+	fn test_invalid_names() {
+		let conn = create_test_map();
+		let progress = TestProgress::default();
+		let mut ctx = EditContext::new(MapDatabase::new(&conn).unwrap(),
+			&progress);
+		for (old, new) in [("", "bob"), ("alice", ""), ("alice", "alice"),
+			("a b", "bob")]
+		{
+			assert!(matches!(
+				rename_player(&mut ctx, old, new, &[], None, false),
+				Err(CommandError::InvalidArgs(_))));
+		}
+	}
+
+	#[test]
+	fn test_rename_in_meta() {
+		let mut vars = HashMap::new();
+		vars.insert(b"owner".to_vec(), (b"alice".to_vec(), false));
+		vars.insert(b"members".to_vec(), (b"carol alice".to_vec(), false));
+		vars.insert(b"text".to_vec(), (b"Hi alice".to_vec(), false));
+		let mut inv = b"List main 2\n\
+			Width 0\n\
+			Item default:key 1 0 \"\\u0001owner\\u0002alice\\u0003\"\n\
+			Item default:book 1 0 \"\\u0001owner\\u0002dave\\u0003\"\n\
+			EndInventoryList\n\
+			EndInventory\n".to_vec();
+
+		assert!(rename_in_vars(&mut vars, b"alice", b"bob"));
+		assert_eq!(vars[&b"owner"[..]].0, b"bob");
+		assert_eq!(vars[&b"members"[..]].0, b"carol bob");
+		assert_eq!(vars[&b"text"[..]].0, b"Hi alice");
+		assert!(!rename_in_vars(&mut vars, b"alice", b"bob"));
+
+		assert_eq!(rename_in_inv(&mut inv, b"alice", b"bob"), Ok(true));
+		assert_eq!(inv, b"List main 2\n\
+			Width 0\n\
+			Item default:key 1 0 \"\\u0001owner\\u0002bob\\u0003\"\n\
+			Item default:book 1 0 \"\\u0001owner\\u0002dave\\u0003\"\n\
+			EndInventoryList\n\
+			EndInventory\n");
+		assert_eq!(rename_in_inv(&mut inv, b"alice", b"bob"), Ok(false));
+	}
+
+	#[test]
+	fn test_unreadable_inventory() {
+		let mut block =
+			MapBlock::deserialize(&read_test_file("mapblock_v29.bin")).unwrap();
+		let (&idx, _) = block.metadata.iter().next().unwrap();
+		let meta = block.metadata.get_mut(&idx).unwrap();
+		meta.vars.insert(b"owner".to_vec(), (b"alice".to_vec(), false));
+		// The list has no size.
+		meta.inv = b"List main\nEndInventoryList\nEndInventory\n".to_vec();
+
+		let conn = create_test_map();
+		let progress = TestProgress::default();
+		let mut ctx = EditContext::new(MapDatabase::new(&conn).unwrap(),
+			&progress);
+		ctx.db.set_block(0, &block.serialize(CompressionLevel::Default))
+			.unwrap();
+		let summary = rename_player(&mut ctx, "alice", "bob", &[], None,
+			false).unwrap();
+		assert_eq!(summary.count, 1);
+
+		// Variables are still renamed, but the inventory is reported.
+		let block = MapBlock::deserialize(&ctx.db.get_block(0).unwrap())
+			.unwrap();
+		assert_eq!(block.metadata[&idx].vars[&b"owner"[..]].0, b"bob");
+		assert!(progress.logs.lock().unwrap().contains(&
+			"warning: Skipped 1 node inventories which could not be read."
+				.to_string()));
+	}
+}
@@ -1,153 +1,270 @@
-use super::{Command, ArgResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	ensure_arg, check_area, check_names};
 
 use crate::unwrap_or;
-use crate::spatial::Vec3;
-use crate::instance::{ArgType, InstArgs, InstBundle};
-use crate::map_block::MapBlock;
-use crate::utils::{query_keys, to_bytes, fmt_big_num};
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
+use crate::map_block::{MapBlock, Inventory, ItemStack};
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
+
+
+/// Changes to make to each affected item stack, besides replacing it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ItemEdit {
+	/// Delete the item's metadata.
+	pub delete_meta: bool,
+	/// Set the number of items in the stack. A count of 0 deletes the stack.
+	pub count: Option<u16>,
+	/// Reduce the number of items in the stack to at most this value.
+	pub max_count: Option<u16>,
+	/// Set the item's wear, e.g. 0 to fully repair a tool.
+	pub wear: Option<u16>,
+}
+
 
+impl ItemEdit {
+	/// Check whether any changes besides replacing the item are made.
+	fn modifies(&self) -> bool {
+		self.delete_meta || self.count.is_some() || self.max_count.is_some()
+			|| self.wear.is_some()
+	}
 
-fn do_replace(inv: &mut Vec<u8>, item: &[u8], new_item: &[u8], del_meta: bool)
+	/// Replace `stack` with `new_item`, or delete it if `new_item` is empty,
+	/// and apply the other changes. Returns `true` if the stack was changed.
+	pub fn apply(&self, stack: &mut ItemStack, new_item: &[u8]) -> bool {
+		let old = stack.clone();
+		stack.name = new_item.to_vec();
+		if self.delete_meta {
+			stack.meta.clear();
+		}
+		if let Some(count) = self.count {
+			stack.count = count;
+		}
+		if let Some(max) = self.max_count {
+			stack.count = stack.count.min(max);
+		}
+		if let Some(wear) = self.wear {
+			stack.wear = wear;
+		}
+		if new_item.is_empty() || stack.count == 0 {
+			*stack = ItemStack::default();
+		}
+		*stack != old
+	}
+}
+
+
+/// Check the item names and edit given to `replace_in_inv` or
+/// `replace_in_player_inv`.
+pub(super) fn check_edit(item: &str, new_item: &str, edit: ItemEdit)
+	-> Result<(), CommandError>
+{
+	check_names(&[item], "item")?;
+	if !new_item.is_empty() {
+		check_names(&[new_item], "item")?;
+	}
+	ensure_arg(!new_item.is_empty() || !edit.modifies(),
+		"Cannot modify items which are being deleted.")?;
+	ensure_arg(edit.count.is_none() || edit.max_count.is_none(),
+		"Cannot use both --count and --maxcount.")?;
+	ensure_arg(item != new_item || edit.modifies(),
+		"item and new_item cannot be the same.")
+}
+
+
+/// Edit the `item` stacks in a serialized inventory. Returns the number of
+/// stacks changed.
+fn do_replace(inv: &mut Vec<u8>, item: &[u8], new_item: &[u8], edit: ItemEdit)
 	-> u64
 {
-	const NEWLINE: u8 = b'\n';
-	const SPACE: u8 = b' ';
+	let mut parsed = unwrap_or!(Inventory::deserialize(inv),
+		return replace_lines(inv, item, new_item, edit));
+	let mods = parsed.items_mut().filter(|s| s.name == item)
+		.map(|stack| edit.apply(stack, new_item) as u64)
+		.sum();
 
-	let delete = new_item.is_empty();
-	let mut new_inv = Vec::new();
+	if mods > 0 {
+		*inv = parsed.serialize();
+	}
+	mods
+}
+
+
+/// Edit the `item` stacks of an inventory which can't be read as a whole,
+/// e.g. because it contains legacy item strings, one line at a time. Other
+/// lines, including `item` stacks which can't be read, are left unchanged.
+fn replace_lines(inv: &mut Vec<u8>, item: &[u8], new_item: &[u8],
+	edit: ItemEdit) -> u64
+{
+	let mut lines = Vec::new();
 	let mut mods = 0;
 
-	for line in inv.split(|&x| x == NEWLINE) {
-		if line.is_empty() {
-			// Necessary because of newline after final EndInventory
-			continue;
-		}
-		// Max 5 parts: Item <name> <count> <wear> <metadata>
-		let mut parts = line.splitn(5, |&x| x == SPACE);
-		if parts.next() == Some(b"Item") && parts.next() == Some(item) {
-			if delete {
-				new_inv.extend_from_slice(b"Empty");
-			} else {
-				new_inv.extend_from_slice(b"Item ");
-				new_inv.extend_from_slice(new_item);
-
-				if del_meta { // Only re-serialize necessary parts
-					let count = parts.next().unwrap_or(b"1");
-					let wear = parts.next().unwrap_or(b"0");
-					if count != b"1" || wear != b"0" {
-						new_inv.push(SPACE);
-						new_inv.extend_from_slice(count);
-					}
-					if wear != b"0" {
-						new_inv.push(SPACE);
-						new_inv.extend_from_slice(wear);
-					}
+	for line in inv.split(|&c| c == b'\n') {
+		let new_line = line.strip_prefix(b"Item ")
+			.filter(|s| s.split(|&c| c == b' ').next() == Some(item))
+			.and_then(|s| ItemStack::deserialize(s).ok())
+			.and_then(|mut stack| {
+				if !edit.apply(&mut stack, new_item) {
+					None
+				} else if stack.is_empty() {
+					Some(b"Empty".to_vec())
 				} else {
-					for part in parts {
-						new_inv.push(SPACE);
-						new_inv.extend_from_slice(part);
-					}
+					Some([&b"Item "[..], &stack.serialize()].concat())
 				}
-			}
+			});
+		if new_line.is_some() {
 			mods += 1;
-		} else {
-			new_inv.extend_from_slice(line);
 		}
-		new_inv.push(NEWLINE);
+		lines.push(new_line.unwrap_or_else(|| line.to_vec()));
 	}
 
 	if mods > 0 {
-		*inv = new_inv;
+		*inv = lines.join(&b'\n');
 	}
 	mods
 }
 
 
-fn replace_in_inv(inst: &mut InstBundle) {
-	let item = to_bytes(inst.args.item.as_ref().unwrap());
-	let new_item = inst.args.new_item.as_ref().map(to_bytes)
-		.unwrap_or(if inst.args.delete { vec![] } else { item.clone() });
+/// Replace `item` with `new_item` in node inventories, or delete it if
+/// `new_item` is empty. Affected item stacks are also changed as specified by
+/// `edit`. If `nodes` is not empty, only inventories of the listed nodes are
+/// modified.
+pub fn replace_in_inv(
+	ctx: &mut EditContext,
+	item: &str,
+	new_item: &str,
+	edit: ItemEdit,
+	nodes: &[String],
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(nodes, "node")?;
+	check_edit(item, new_item, edit)?;
 
-	let nodes: Vec<_> = inst.args.nodes.iter().map(to_bytes).collect();
-	let keys = query_keys(&mut inst.db, &mut inst.status,
-		&nodes, inst.args.area, inst.args.invert, true);
+	let item = to_bytes(item);
+	let new_item = to_bytes(new_item);
 
-	inst.status.begin_editing();
-	let mut item_mods: u64 = 0;
-	let mut node_mods: u64 = 0;
+	let nodes: Vec<_> = nodes.iter().map(to_bytes).collect();
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		&nodes, area, invert, true);
 
-	for key in keys {
-		inst.status.inc_done();
-		let data = inst.db.get_block(key).unwrap();
-		let mut block = unwrap_or!(MapBlock::deserialize(&data),
-			{ inst.status.inc_failed(); continue; });
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let item_mods = AtomicU64::new(0);
+	let node_mods = AtomicU64::new(0);
 
-		let node_ids: Vec<_> = nodes.iter()
-			.filter_map(|n| block.nimap.get_id(n)).collect();
-		if !nodes.is_empty() && node_ids.is_empty() {
-			continue; // Block doesn't contain any of the required nodes.
-		}
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|key, data| {
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
 
-		let block_corner = Vec3::from_block_key(key) * 16;
-		let mut modified = false;
+			let node_ids: Vec<_> = nodes.iter()
+				.filter_map(|n| block.nimap.get_id(n)).collect();
+			if !nodes.is_empty() && node_ids.is_empty() {
+				// Block doesn't contain any of the required nodes.
+				return BlockAction::Keep;
+			}
+
+			let block_corner = Vec3::from_block_key(key) * 16;
+			let mut modified = false;
 
-		for (&idx, data) in &mut block.metadata {
-			let pos = Vec3::from_u16_key(idx);
-			let abs_pos = pos + block_corner;
-			if let Some(a) = inst.args.area {
-				if a.contains(abs_pos) == inst.args.invert {
+			for (&idx, data) in &mut block.metadata {
+				let pos = Vec3::from_u16_key(idx);
+				let abs_pos = pos + block_corner;
+				if let Some(a) = area {
+					if a.contains(abs_pos) == invert {
+						continue;
+					}
+				}
+				if !node_ids.is_empty()
+					&& !node_ids.contains(&block.node_data.nodes[idx as usize])
+				{
 					continue;
 				}
-			}
-			if !node_ids.is_empty()
-				&& !node_ids.contains(&block.node_data.nodes[idx as usize])
-			{
-				continue;
+
+				let i_mods = do_replace(&mut data.inv, &item, &new_item,
+					edit);
+				if i_mods > 0 {
+					item_mods.fetch_add(i_mods, Ordering::Relaxed);
+					node_mods.fetch_add(1, Ordering::Relaxed);
+					modified = true;
+				}
 			}
 
-			let i_mods = do_replace(&mut data.inv, &item, &new_item,
-				inst.args.delete_meta);
-			item_mods += i_mods;
-			if i_mods > 0 {
-				node_mods += 1;
-				modified = true;
+			if modified {
+				BlockAction::Write(block.serialize(level))
+			} else {
+				BlockAction::Keep
 			}
 		}
+	)?;
 
-		if modified {
-			inst.db.set_block(key, &block.serialize()).unwrap();
-		}
-	}
+	let item_mods = item_mods.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("Modified {} itemstacks in {} nodes.",
+		fmt_big_num(item_mods), fmt_big_num(node_mods.into_inner())));
+	Ok(Summary::new(item_mods))
+}
 
-	inst.status.end_editing();
-	inst.status.log_info(format!("Replaced {} itemstacks in {} nodes.",
-		fmt_big_num(item_mods), fmt_big_num(node_mods)));
+
+/// Get the item, new item (empty if deleting) and edit given on the command
+/// line.
+pub(super) fn get_edit(args: &InstArgs) -> (&str, &str, ItemEdit) {
+	let item = args.item.as_deref().unwrap_or_default();
+	let new_item = args.new_item.as_deref()
+		.unwrap_or(if args.delete { "" } else { item });
+	let edit = ItemEdit {
+		delete_meta: args.delete_meta,
+		count: args.count,
+		max_count: args.max_count,
+		wear: args.wear,
+	};
+	(item, new_item, edit)
 }
 
 
-fn verify_args(args: &InstArgs) -> ArgResult {
-	if args.new_item.is_none() && !args.delete && !args.delete_meta {
-		return ArgResult::error(
-			"new_item is required unless --delete or --deletemeta is used.");
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	let (item, new_item, edit) = get_edit(args);
+	replace_in_inv(&mut inst.ctx, item, new_item, edit,
+		&args.nodes, args.area, args.invert)
+}
+
+
+pub(super) fn verify_args(args: &InstArgs) -> ArgResult {
+	let (item, new_item, edit) = get_edit(args);
+
+	if args.new_item.is_none() && !args.delete && !edit.modifies() {
+		return ArgResult::error("new_item is required unless --delete, \
+			--deletemeta, --count, --maxcount, or --wear is used.");
 	} else if args.new_item.is_some() && args.delete {
 		return ArgResult::error(
 			"Cannot delete items if new_item is specified.");
-	} else if args.item == args.new_item && !args.delete_meta {
-		return ArgResult::error("item and new_item cannot be the same.");
 	}
-	ArgResult::Ok
+	check_edit(item, new_item, edit).into()
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: replace_in_inv,
+		func: run,
 		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
 			(ArgType::Item, "Name of the item to replace/delete"),
 			(ArgType::NewItem, "Name of the new item, if replacing items."),
 			(ArgType::Delete, "Delete items instead of replacing them."),
 			(ArgType::DeleteMeta, "Delete metadata of affected items."),
+			(ArgType::Count, "Set the count of affected item stacks."),
+			(ArgType::MaxCount,
+				"Reduce the count of affected item stacks to at most this."),
+			(ArgType::Wear, "Set the wear of affected items."),
 			(ArgType::Nodes,
 				"Names of one or more nodes to modify inventories of"),
 			(ArgType::Area(false), "Area in which to modify node inventories"),
@@ -161,7 +278,7 @@ pub fn get_command() -> Command {
 
 #[cfg(test)]
 mod tests {
-	use super::do_replace;
+	use super::{do_replace, ItemEdit};
 
 	#[test]
 	fn test_replace_in_inv() {
@@ -227,15 +344,115 @@ mod tests {
 			EndInventory\n";
 
 		let mut inv = original.to_vec();
-		do_replace(&mut inv, b"test:foo", b"test:bar", false);
+		do_replace(&mut inv, b"test:foo", b"test:bar", ItemEdit::default());
 		assert_eq!(&inv, replace);
 
 		let mut inv = original.to_vec();
-		do_replace(&mut inv, b"test:foo", b"", false);
+		do_replace(&mut inv, b"test:foo", b"", ItemEdit::default());
 		assert_eq!(&inv, delete);
 
 		let mut inv = original.to_vec();
-		do_replace(&mut inv, b"test:foo", b"test:bar", true);
+		do_replace(&mut inv, b"test:foo", b"test:bar",
+			ItemEdit { delete_meta: true, ..Default::default() });
 		assert_eq!(&inv, replace_delete_meta);
 	}
+
+	#[test]
+	fn test_unreadable_lines() {
+		// Stacks next to lines which can't be read are still edited.
+		let original = b"\
+			List main 4\n\
+			Width 0\n\
+			Item CraftItem default:coal_lump 5\n\
+			Item test:foo 5\n\
+			Item test:foo 70000\n\
+			Item test:bar 2\n\
+			EndInventoryList\n\
+			EndInventory\n";
+		let replaced = b"\
+			List main 4\n\
+			Width 0\n\
+			Item CraftItem default:coal_lump 5\n\
+			Item test:bar 5\n\
+			Item test:foo 70000\n\
+			Item test:bar 2\n\
+			EndInventoryList\n\
+			EndInventory\n";
+		let deleted = b"\
+			List main 4\n\
+			Width 0\n\
+			Item CraftItem default:coal_lump 5\n\
+			Empty\n\
+			Item test:foo 70000\n\
+			Item test:bar 2\n\
+			EndInventoryList\n\
+			EndInventory\n";
+
+		let mut inv = original.to_vec();
+		assert_eq!(do_replace(&mut inv, b"test:foo", b"test:bar",
+			ItemEdit::default()), 1);
+		assert_eq!(&inv, replaced);
+
+		let mut inv = original.to_vec();
+		assert_eq!(do_replace(&mut inv, b"test:foo", b"",
+			ItemEdit::default()), 1);
+		assert_eq!(&inv, deleted);
+
+		let mut inv = original.to_vec();
+		assert_eq!(do_replace(&mut inv, b"test:baz", b"test:bar",
+			ItemEdit::default()), 0);
+		assert_eq!(&inv, original);
+	}
+
+	#[test]
+	fn test_edit_stacks() {
+		let original = b"\
+			List main 5\n\
+			Width 0\n\
+			Item default:diamond 65535\n\
+			Item default:diamond 50\n\
+			Item default:pick_diamond 1 40000\n\
+			Item default:diamond\n\
+			Empty\n\
+			EndInventoryList\n\
+			EndInventory\n";
+		let capped = b"\
+			List main 5\n\
+			Width 0\n\
+			Item default:diamond 99\n\
+			Item default:diamond 50\n\
+			Item default:pick_diamond 1 40000\n\
+			Item default:diamond\n\
+			Empty\n\
+			EndInventoryList\n\
+			EndInventory\n";
+		let zeroed = b"\
+			List main 5\n\
+			Width 0\n\
+			Empty\n\
+			Empty\n\
+			Item default:pick_diamond 1 40000\n\
+			Empty\n\
+			Empty\n\
+			EndInventoryList\n\
+			EndInventory\n";
+
+		let mut inv = original.to_vec();
+		assert_eq!(do_replace(&mut inv, b"default:diamond", b"default:diamond",
+			ItemEdit { max_count: Some(99), ..Default::default() }), 1);
+		assert_eq!(&inv, capped);
+
+		let mut inv = original.to_vec();
+		assert_eq!(do_replace(&mut inv, b"default:diamond", b"default:diamond",
+			ItemEdit { count: Some(0), ..Default::default() }), 3);
+		assert_eq!(&inv, zeroed);
+
+		let mut inv = original.to_vec();
+		assert_eq!(do_replace(&mut inv, b"default:pick_diamond",
+			b"default:pick_diamond",
+			ItemEdit { wear: Some(0), ..Default::default() }), 1);
+		let repaired = std::str::from_utf8(original).unwrap()
+			.replace("pick_diamond 1 40000", "pick_diamond");
+		assert_eq!(inv, repaired.as_bytes());
+	}
 }
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use regex::bytes::Regex;
+
+use super::{Command, ArgResult, CommandResult, Summary, ensure_arg,
+	check_area, check_names};
+
+use crate::unwrap_or;
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
+use crate::map_block::{MapBlock, NodeMetadata};
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
+
+
+/// Replace all matches of `pattern` in the variables of `meta`, or only in
+/// `key` if given. Returns the names of the modified variables.
+fn do_replace(
+	meta: &mut NodeMetadata,
+	pattern: &Regex,
+	replacement: &[u8],
+	key: Option<&[u8]>
+) -> Vec<Vec<u8>> {
+	let mut modified = Vec::new();
+
+	for (name, (value, _)) in &mut meta.vars {
+		if key.is_some_and(|k| k != name.as_slice()) {
+			continue;
+		}
+		let new_value = pattern.replace_all(value, replacement);
+		if new_value != value.as_slice() {
+			*value = new_value.into_owned();
+			modified.push(name.clone());
+		}
+	}
+
+	// Minetest treats empty variables as unset.
+	meta.vars.retain(|_, (value, _)| !value.is_empty());
+	modified
+}
+
+
+/// Replace all matches of `pattern` with `replacement` in node metadata
+/// variables, or only in the variable `key` if given. `replacement` may refer
+/// to capture groups, e.g. `$1`. If `nodes` is not empty, only metadata of
+/// the listed nodes is modified.
+pub fn replace_in_meta(
+	ctx: &mut EditContext,
+	pattern: &Regex,
+	replacement: &str,
+	key: Option<&str>,
+	nodes: &[String],
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(nodes, "node")?;
+	ensure_arg(!pattern.as_str().is_empty(), "Pattern cannot be empty.")?;
+
+	let replacement = to_bytes(replacement);
+	let key = key.map(to_bytes);
+	let nodes: Vec<_> = nodes.iter().map(to_bytes).collect();
+
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		&nodes, area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let counts = Mutex::new(BTreeMap::new());
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|block_key, data| {
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			let node_ids: Vec<_> = nodes.iter()
+				.filter_map(|n| block.nimap.get_id(n)).collect();
+			if !nodes.is_empty() && node_ids.is_empty() {
+				// Block doesn't contain any of the required nodes.
+				return BlockAction::Keep;
+			}
+
+			let block_corner = Vec3::from_block_key(block_key) * 16;
+			let mut modified = Vec::new();
+
+			for (&idx, data) in &mut block.metadata {
+				let pos = Vec3::from_u16_key(idx);
+				if let Some(a) = area {
+					if a.contains(pos + block_corner) == invert {
+						continue;
+					}
+				}
+				if !node_ids.is_empty()
+					&& !node_ids.contains(&block.node_data.nodes[idx as usize])
+				{
+					continue;
+				}
+
+				modified.extend(do_replace(data, pattern, &replacement,
+					key.as_deref()));
+			}
+
+			if modified.is_empty() {
+				BlockAction::Keep
+			} else {
+				let mut counts = counts.lock().unwrap();
+				for name in modified {
+					*counts.entry(name).or_insert(0u64) += 1;
+				}
+				BlockAction::Write(block.serialize(level))
+			}
+		}
+	)?;
+
+	ctx.status.end_editing();
+	let counts = counts.into_inner().unwrap();
+	for (name, count) in &counts {
+		ctx.status.log_info(format!("Modified \"{}\" in {} nodes.",
+			String::from_utf8_lossy(name), fmt_big_num(*count)));
+	}
+	let count = counts.values().sum();
+	ctx.status.log_info(format!("Modified {} metadata variables.",
+		fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
+
+
+/// Compile the pattern given on the command line.
+fn get_regex(args: &InstArgs) -> Result<Regex, regex::Error> {
+	let pattern = args.pattern.as_deref().unwrap();
+	if args.regex {
+		Regex::new(pattern)
+	} else {
+		Regex::new(&regex::escape(pattern))
+	}
+}
+
+
+fn verify_args(args: &InstArgs) -> ArgResult {
+	if args.pattern.as_deref() == Some("") {
+		return ArgResult::error("Pattern cannot be empty.");
+	}
+	if let Err(e) = get_regex(args) {
+		return ArgResult::Error(format!("Invalid regular expression: {}", e));
+	}
+	ArgResult::Ok
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	let pattern = get_regex(args).unwrap();
+	let replacement = args.replacement.as_deref().unwrap();
+	// Without --regex, "$" in the replacement has no special meaning.
+	let replacement = if args.regex {
+		replacement.to_string()
+	} else {
+		replacement.replace('$', "$$")
+	};
+	replace_in_meta(&mut inst.ctx, &pattern, &replacement,
+		args.key.as_deref(), &args.nodes, args.area, args.invert)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::Pattern, "Text to search for"),
+			(ArgType::Replacement,
+				"Text to replace matches with. With --regex, this can refer \
+				to capture groups, e.g. $1 or ${name}."),
+			(ArgType::Regex,
+				"Treat the pattern as a regular expression."),
+			(ArgType::Key(false),
+				"Name of the variable to modify. If not specified, all \
+				variables are modified."),
+			(ArgType::Nodes, "Names of one or more nodes to modify"),
+			(ArgType::Area(false), "Area in which to modify node metadata"),
+			(ArgType::Invert, "Modify node metadata *outside* the given area."),
+		],
+		help: "Replace text in node metadata variables."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	#[test]
+	fn test_do_replace() {
+		let mut vars = HashMap::new();
+		vars.insert(b"owner".to_vec(), (b"alice".to_vec(), false));
+		vars.insert(b"infotext".to_vec(),
+			(b"Locked Chest (owned by alice)".to_vec(), false));
+		vars.insert(b"url".to_vec(),
+			(b"http://old.example/alice".to_vec(), true));
+		let original = NodeMetadata { vars, inv: b"EndInventory\n".to_vec() };
+		let get = |meta: &NodeMetadata, key: &[u8]|
+			String::from_utf8(meta.vars[key].0.clone()).unwrap();
+
+		let mut meta = original.clone();
+		let pattern = Regex::new(&regex::escape("alice")).unwrap();
+		let mut modified = do_replace(&mut meta, &pattern, b"bob", None);
+		modified.sort();
+		assert_eq!(modified, [&b"infotext"[..], b"owner", b"url"]);
+		assert_eq!(get(&meta, b"infotext"), "Locked Chest (owned by bob)");
+		assert_eq!(meta.vars[&b"url"[..]], (b"http://old.example/bob".to_vec(),
+			true));
+
+		let mut meta = original.clone();
+		assert_eq!(do_replace(&mut meta, &pattern, b"bob", Some(b"owner")),
+			[b"owner"]);
+		assert_eq!(get(&meta, b"owner"), "bob");
+		assert_eq!(get(&meta, b"infotext"), "Locked Chest (owned by alice)");
+
+		let mut meta = original.clone();
+		let pattern = Regex::new(r"^http://old\.example/(\w+)$").unwrap();
+		assert_eq!(do_replace(&mut meta, &pattern,
+			b"https://new.example/u/$1", None), [b"url"]);
+		assert_eq!(get(&meta, b"url"), "https://new.example/u/alice");
+
+		// Variables which become empty are deleted.
+		let mut meta = original;
+		let pattern = Regex::new("^alice$").unwrap();
+		assert_eq!(do_replace(&mut meta, &pattern, b"", None), [b"owner"]);
+		assert!(!meta.vars.contains_key(&b"owner"[..]));
+	}
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeSet;
+
+use super::{Command, ItemEdit, WorldDb, CommandError, CommandResult, Summary,
+	check_player_names};
+use super::replace_in_inv::{verify_args, check_edit, get_edit};
+
+use crate::unwrap_or;
+use crate::instance::{ArgType, InstBundle, Progress};
+use crate::map_block::ItemStack;
+use crate::player_database::PlayerDatabase;
+use crate::utils::{to_bytes, fmt_big_num};
+
+
+/// Edit a serialized item stack if it is an `item`. Returns the new stack if
+/// it was changed.
+fn do_replace(src: &str, item: &[u8], new_item: &[u8], edit: ItemEdit)
+	-> Option<ItemStack>
+{
+	let mut stack = unwrap_or!(ItemStack::deserialize(src.as_bytes()),
+		return None);
+	if stack.name == item && edit.apply(&mut stack, new_item) {
+		Some(stack)
+	} else {
+		None
+	}
+}
+
+
+/// Replace `item` with `new_item` in player inventories, or delete it if
+/// `new_item` is empty. Affected item stacks are also changed as specified by
+/// `edit`. If `players` is not empty, only inventories of the listed players
+/// are modified.
+pub fn replace_in_player_inv(
+	db: &mut PlayerDatabase,
+	status: &dyn Progress,
+	item: &str,
+	new_item: &str,
+	edit: ItemEdit,
+	players: &[String]
+) -> CommandResult {
+	check_player_names(players)?;
+	check_edit(item, new_item, edit)?;
+
+	let item = to_bytes(item);
+	let new_item = to_bytes(new_item);
+
+	let items = db.get_items().map_err(|e| CommandError::Failed(
+		format!("Failed to read player inventories: {}", e)))?;
+	let mut item_mods = 0;
+	let mut modified_players = BTreeSet::new();
+
+	for mut slot in items {
+		if !players.is_empty() && !players.contains(&slot.player) {
+			continue;
+		}
+		let stack = match do_replace(&slot.item, &item, &new_item, edit) {
+			Some(s) => s,
+			None => continue
+		};
+
+		// Minetest saves empty slots with an empty item string.
+		slot.item = if stack.is_empty() {
+			String::new()
+		} else {
+			String::from_utf8_lossy(&stack.serialize()).into_owned()
+		};
+		db.set_item(&slot).map_err(|e| CommandError::Failed(
+			format!("Failed to modify player inventory: {}", e)))?;
+		item_mods += 1;
+		modified_players.insert(slot.player);
+	}
+
+	status.log_info(format!("Modified {} itemstacks of {} players.",
+		fmt_big_num(item_mods), fmt_big_num(modified_players.len() as u64)));
+	Ok(Summary::new(item_mods))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	let (item, new_item, edit) = get_edit(args);
+	replace_in_player_inv(inst.pdb.as_mut().unwrap(), inst.ctx.status, item,
+		new_item, edit, &args.players)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: Some(verify_args),
+		world_dbs: &[WorldDb::Players],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::Item, "Name of the item to replace/delete"),
+			(ArgType::NewItem, "Name of the new item, if replacing items."),
+			(ArgType::Delete, "Delete items instead of replacing them."),
+			(ArgType::DeleteMeta, "Delete metadata of affected items."),
+			(ArgType::Count, "Set the count of affected item stacks."),
+			(ArgType::MaxCount,
+				"Reduce the count of affected item stacks to at most this."),
+			(ArgType::Wear, "Set the wear of affected items."),
+			(ArgType::Players,
+				"Names of one or more players to modify inventories of. If \
+				not specified, all players are affected."),
+		],
+		help: "Replace, delete, or modify items in player inventories."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_do_replace() {
+		let serialize = |s: Option<ItemStack>| s.map(|s| s.serialize());
+		let edit = ItemEdit::default();
+
+		assert_eq!(serialize(do_replace("default:dirt 5", b"default:dirt",
+			b"default:stone", edit)), Some(b"default:stone 5".to_vec()));
+		assert_eq!(serialize(do_replace("default:dirt 5", b"default:sand",
+			b"default:stone", edit)), None);
+		assert!(do_replace("default:dirt 5", b"default:dirt", b"", edit)
+			.unwrap().is_empty());
+		assert_eq!(serialize(do_replace(
+			"default:pick_steel 1 30000 \"\\u0001owner\\u0002bob\\u0003\"",
+			b"default:pick_steel", b"default:pick_steel",
+			ItemEdit { wear: Some(0), delete_meta: true, ..edit })),
+			Some(b"default:pick_steel".to_vec()));
+		// No changes needed.
+		assert_eq!(serialize(do_replace("default:dirt 5", b"default:dirt",
+			b"default:dirt", ItemEdit { max_count: Some(10), ..edit })), None);
+	}
+}
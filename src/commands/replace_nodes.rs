@@ -1,10 +1,14 @@
-use super::{Command, ArgResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	ensure_arg, check_area, check_names};
 
 use crate::unwrap_or;
 use crate::spatial::{Vec3, Area, InverseBlockIterator};
-use crate::instance::{ArgType, InstArgs, InstBundle};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
 use crate::map_block::MapBlock;
-use crate::utils::{query_keys, to_bytes, fmt_big_num};
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
 
 
 fn do_replace(
@@ -94,50 +98,76 @@ fn do_replace(
 }
 
 
-fn replace_nodes(inst: &mut InstBundle) {
-	let old_node = to_bytes(inst.args.node.as_ref().unwrap());
-	let new_node = to_bytes(inst.args.new_node.as_ref().unwrap());
-	let keys = query_keys(&mut inst.db, &inst.status,
-		std::slice::from_ref(&old_node),
-		inst.args.area, inst.args.invert, true);
-
-	inst.status.begin_editing();
-	let mut count = 0;
+/// Replace all instances of `old_node` with `new_node`, optionally only
+/// inside or outside an area.
+pub fn replace_nodes(
+	ctx: &mut EditContext,
+	old_node: &str,
+	new_node: &str,
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(&[old_node, new_node], "node")?;
+	check_args(old_node, new_node)?;
+
+	let old_node = to_bytes(old_node);
+	let new_node = to_bytes(new_node);
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		std::slice::from_ref(&old_node), area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let count = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|key, data| {
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			if let Some(old_id) = block.nimap.get_id(&old_node) {
+				let replaced = do_replace(&mut block, key, old_id, &new_node,
+					area, invert);
+				count.fetch_add(replaced, Ordering::Relaxed);
+				BlockAction::Write(block.serialize(level))
+			} else {
+				BlockAction::Keep
+			}
+		}
+	)?;
 
-	for key in keys {
-		let data = inst.db.get_block(key).unwrap();
+	let count = count.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("{} nodes replaced.", fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
 
-		let mut block = unwrap_or!(MapBlock::deserialize(&data),
-			{ inst.status.inc_failed(); continue; });
 
-		if let Some(old_id) = block.nimap.get_id(&old_node) {
-			count += do_replace(&mut block, key, old_id, &new_node,
-				inst.args.area, inst.args.invert);
-			let new_data = block.serialize();
-			inst.db.set_block(key, &new_data).unwrap();
-		}
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	replace_nodes(&mut inst.ctx, args.node.as_ref().unwrap(),
+		args.new_node.as_ref().unwrap(), args.area, args.invert)
+}
 
-		inst.status.inc_done();
-	}
 
-	inst.status.end_editing();
-	inst.status.log_info(format!("{} nodes replaced.", fmt_big_num(count)));
+fn check_args(node: &str, new_node: &str) -> Result<(), CommandError> {
+	ensure_arg(node != new_node, "node and new_node must be different.")
 }
 
 
 fn verify_args(args: &InstArgs) -> ArgResult {
-	if args.node == args.new_node {
-		return ArgResult::error("node and new_node must be different.");
-	}
-
-	ArgResult::Ok
+	check_args(args.node.as_deref().unwrap_or_default(),
+		args.new_node.as_deref().unwrap_or_default()).into()
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: replace_nodes,
+		func: run,
 		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
 			(ArgType::Node(true), "Name of node to replace"),
 			(ArgType::NewNode, "Name of node to replace with"),
@@ -0,0 +1,47 @@
+use super::{Command, WorldDb, CommandResult, Summary};
+use super::grant_privs::{edit_privs, verify_args};
+
+use crate::instance::{ArgType, InstBundle, Progress};
+use crate::auth_database::AuthDatabase;
+
+
+/// Revoke privileges from a player.
+pub fn revoke_privs(
+	db: &mut AuthDatabase,
+	status: &dyn Progress,
+	player: &str,
+	privs: &[String]
+) -> CommandResult {
+	let revoked = edit_privs(db, player, privs, false)?;
+	if revoked.is_empty() {
+		status.log_info(
+			format!("{} doesn't have any of these privileges.", player));
+	} else {
+		status.log_info(format!("Revoked privileges from {}: {}", player,
+			revoked.join(", ")));
+	}
+	Ok(Summary::new(revoked.len() as u64))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	revoke_privs(inst.adb.as_mut().unwrap(), inst.ctx.status,
+		args.player.as_ref().unwrap(), &args.privs)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: Some(verify_args),
+		world_dbs: &[WorldDb::Auth],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::Player, "Name of the player"),
+			(ArgType::Privs, "Names of one or more privileges to revoke"),
+		],
+		help: "Revoke privileges from a player."
+	}
+}
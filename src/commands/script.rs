@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+
+use mlua::{Lua, Function, Table};
+
+use super::{Command, CommandError, CommandResult, Summary, check_area,
+	check_names, is_valid_name};
+
+use crate::unwrap_or;
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstBundle, EditContext};
+use crate::map_block::{MapBlock, NodeMetadata};
+use crate::block_utils::clean_name_id_map;
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+
+
+const EMPTY_INV: &[u8] = b"EndInventory\n";
+
+
+fn load_script<'lua>(lua: &'lua Lua, source: &str)
+	-> Result<Function<'lua>, String>
+{
+	lua.load(source).set_name("script").exec().map_err(|e| e.to_string())?;
+	lua.globals().get::<_, Option<Function>>("edit_node")
+		.map_err(|e| e.to_string())?
+		.ok_or_else(|| "Script must define a function named edit_node."
+			.to_string())
+}
+
+
+fn to_table<'lua>(lua: &'lua Lua, pos: Vec3) -> mlua::Result<Table<'lua>> {
+	let table = lua.create_table()?;
+	table.set("x", pos.x)?;
+	table.set("y", pos.y)?;
+	table.set("z", pos.z)?;
+	Ok(table)
+}
+
+
+/// Call `edit_node` for one node, and apply any changes made by the script.
+/// Returns `true` if the node was changed.
+fn edit_node(lua: &Lua, func: &Function, block: &mut MapBlock, idx: u16,
+	pos: Vec3) -> mlua::Result<bool>
+{
+	let nd = &mut block.node_data;
+	let i = idx as usize;
+	let old_name = block.nimap.0.get(&nd.nodes[i]).cloned()
+		.unwrap_or_default();
+	let old_meta = block.metadata.get(&idx);
+	let old_vars: HashMap<_, _> = old_meta.iter()
+		.flat_map(|m| m.vars.iter().map(|(k, v)| (k.clone(), v.0.clone())))
+		.collect();
+	let old_inv = old_meta.map_or(EMPTY_INV, |m| &m.inv).to_vec();
+
+	let node = lua.create_table()?;
+	node.set("name", lua.create_string(&old_name)?)?;
+	node.set("param1", nd.param1[i])?;
+	node.set("param2", nd.param2[i])?;
+	let meta = lua.create_table()?;
+	for (key, value) in &old_vars {
+		meta.set(lua.create_string(key)?, lua.create_string(value)?)?;
+	}
+	node.set("meta", meta)?;
+	node.set("inventory", lua.create_string(&old_inv)?)?;
+
+	func.call::<_, ()>((to_table(lua, pos)?, node.clone()))?;
+
+	let name = node.get::<_, mlua::String>("name")?.as_bytes().to_vec();
+	let param1: u8 = node.get("param1")?;
+	let param2: u8 = node.get("param2")?;
+	let vars = node.get::<_, Table>("meta")?
+		.pairs::<mlua::String, mlua::String>()
+		.map(|pair| pair.map(|(k, v)|
+			(k.as_bytes().to_vec(), v.as_bytes().to_vec())))
+		.collect::<mlua::Result<HashMap<_, _>>>()?;
+	let inv = node.get::<_, mlua::String>("inventory")?.as_bytes().to_vec();
+	if !inv.ends_with(EMPTY_INV) {
+		return Err(mlua::Error::RuntimeError(
+			"Inventory must end with \"EndInventory\\n\".".to_string()));
+	}
+
+	let mut changed = param1 != nd.param1[i] || param2 != nd.param2[i];
+	nd.param1[i] = param1;
+	nd.param2[i] = param2;
+
+	if name != old_name {
+		if !std::str::from_utf8(&name).is_ok_and(is_valid_name) {
+			return Err(mlua::Error::RuntimeError(format!(
+				"Invalid node name: {}", String::from_utf8_lossy(&name))));
+		}
+		// Unused IDs are removed later by clean_name_id_map.
+		let id = match block.nimap.get_id(&name) {
+			Some(id) => id,
+			None => {
+				let next = block.nimap.get_max_id()
+					.map_or(Some(0), |id| id.checked_add(1))
+					.ok_or_else(|| mlua::Error::RuntimeError(
+						"Too many different nodes in mapblock.".to_string()))?;
+				block.nimap.0.insert(next, name);
+				next
+			}
+		};
+		block.node_data.nodes[i] = id;
+		changed = true;
+	}
+
+	if vars != old_vars || inv != old_inv {
+		let meta = block.metadata.entry(idx).or_insert_with(|| NodeMetadata {
+			vars: HashMap::new(),
+			inv: Vec::new()
+		});
+		// Keep the private flag of existing variables.
+		meta.vars = vars.into_iter().map(|(k, v)| {
+			let private = meta.vars.get(&k).is_some_and(|old| old.1);
+			(k, (v, private))
+		}).collect();
+		// Note: serialize() will cull any newly empty metadata.
+		meta.inv = inv;
+		changed = true;
+	}
+
+	Ok(changed)
+}
+
+
+fn edit_block(
+	lua: &Lua,
+	func: &Function,
+	block: &mut MapBlock,
+	key: i64,
+	node_ids: Option<&[u16]>,
+	area: Option<Area>,
+	invert: bool
+) -> Result<u64, String> {
+	let block_corner = Vec3::from_block_key(key) * 16;
+	let mut count = 0;
+
+	for idx in 0..4096 {
+		let pos = Vec3::from_u16_key(idx) + block_corner;
+		if let Some(a) = area {
+			if a.contains(pos) == invert {
+				continue;
+			}
+		}
+		if let Some(ids) = node_ids {
+			if !ids.contains(&block.node_data.nodes[idx as usize]) {
+				continue;
+			}
+		}
+
+		let changed = edit_node(lua, func, block, idx, pos)
+			.map_err(|e| format!("At {}: {}", pos, e))?;
+		count += changed as u64;
+	}
+
+	Ok(count)
+}
+
+
+/// Run a Lua script on each node inside or outside an area. The script must
+/// define a function `edit_node(pos, node)`, which can modify the node's
+/// name, param1, param2, metadata variables and inventory. If `nodes` is not
+/// empty, the function is only called for the listed nodes.
+///
+/// If the script fails, editing stops and an error is returned.
+pub fn run_script(
+	ctx: &mut EditContext,
+	source: &str,
+	nodes: &[String],
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(nodes, "node")?;
+
+	let lua = Lua::new();
+	let func = load_script(&lua, source).map_err(|e|
+		CommandError::InvalidArgs(format!("Failed to load script: {}", e)))?;
+
+	let nodes: Vec<_> = nodes.iter().map(to_bytes).collect();
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		&nodes, area, invert, true);
+
+	ctx.status.begin_editing();
+	let mut count = 0;
+	let mut error = None;
+
+	// Lua states can't be shared between threads, so mapblocks are
+	// processed one at a time.
+	for (key, data) in blocks {
+		ctx.status.inc_done();
+		let mut block = unwrap_or!(MapBlock::deserialize(&data),
+			{ ctx.status.inc_failed(); continue; });
+
+		let node_ids: Vec<_> = nodes.iter()
+			.filter_map(|n| block.nimap.get_id(n)).collect();
+		if !nodes.is_empty() && node_ids.is_empty() {
+			// Block doesn't contain any of the required nodes.
+			continue;
+		}
+		let node_ids = Some(node_ids.as_slice())
+			.filter(|_| !nodes.is_empty());
+
+		match edit_block(&lua, &func, &mut block, key, node_ids, area, invert)
+		{
+			Ok(0) => {},
+			Ok(changed) => {
+				count += changed;
+				clean_name_id_map(&mut block);
+				if let Err(e) = ctx.db.set_block(key,
+					&block.serialize(ctx.compression))
+				{
+					ctx.status.end_editing();
+					return Err(e.into());
+				}
+			},
+			Err(e) => {
+				error = Some(e);
+				break;
+			}
+		}
+	}
+
+	ctx.status.end_editing();
+	match error {
+		Some(e) => Err(CommandError::Failed(format!("Script error: {}", e))),
+		None => {
+			ctx.status.log_info(format!("Script modified {} nodes.",
+				fmt_big_num(count)));
+			Ok(Summary::new(count))
+		}
+	}
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	let path = args.lua_script.as_ref().unwrap();
+	let source = std::fs::read_to_string(path).map_err(|e|
+		CommandError::Failed(format!("Failed to read script file: {}", e)))?;
+	run_script(&mut inst.ctx, &source, &args.nodes, args.area, args.invert)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::LuaScript,
+				"Path to a Lua script defining edit_node(pos, node)"),
+			(ArgType::Nodes,
+				"Names of one or more nodes to run the script on. If not \
+				specified, the script is run on every node."),
+			(ArgType::Area(false), "Area in which to run the script"),
+			(ArgType::Invert, "Run the script *outside* the given area."),
+		],
+		help: "Edit nodes using a custom Lua script."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::read_test_file;
+
+	#[test]
+	fn test_edit_block() {
+		let mut block =
+			MapBlock::deserialize(&read_test_file("mapblock_v29.bin")).unwrap();
+
+		let lua = Lua::new();
+		let func = load_script(&lua, r#"
+			function edit_node(pos, node)
+				if node.name == "test_mod:timer" then
+					node.name = "test_mod:new"
+					node.param2 = 5
+					node.meta.foo = "bar"
+				end
+			end
+		"#).unwrap();
+		assert_eq!(edit_block(&lua, &func, &mut block, 0, None, None, false),
+			Ok(1));
+
+		let id = block.nimap.get_id(b"test_mod:new").unwrap();
+		let idx = block.node_data.nodes.iter().position(|&n| n == id)
+			.unwrap();
+		assert_eq!(block.node_data.param2[idx], 5);
+		let meta = &block.metadata[&(idx as u16)];
+		assert_eq!(meta.vars[&b"foo".to_vec()].0, b"bar");
+
+		// Nothing is changed by a script which leaves nodes alone.
+		let noop = load_script(&lua, "function edit_node() end").unwrap();
+		assert_eq!(edit_block(&lua, &noop, &mut block, 0, None, None, false),
+			Ok(0));
+
+		assert!(load_script(&Lua::new(), "x = 1").is_err());
+		let bad = load_script(&lua,
+			"function edit_node(p, n) n.inventory = '' end").unwrap();
+		assert!(edit_block(&lua, &bad, &mut block, 0, None, None, false)
+			.is_err());
+		let bad_name = load_script(&lua,
+			"function edit_node(p, n) n.name = 'Not a node' end").unwrap();
+		assert!(edit_block(&lua, &bad_name, &mut block, 0, None, None, false)
+			.is_err());
+
+		// No IDs are left for a new node name.
+		block.nimap.0.insert(u16::MAX, b"test_mod:last".to_vec());
+		let new_name = load_script(&lua,
+			"function edit_node(p, n) n.name = 'test_mod:other' end").unwrap();
+		assert!(edit_block(&lua, &new_name, &mut block, 0, None, None, false)
+			.is_err());
+	}
+}
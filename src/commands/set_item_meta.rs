@@ -0,0 +1,310 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	ensure_arg, check_area, check_names};
+
+use crate::unwrap_or;
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
+use crate::map_block::{MapBlock, MapBlockError, Inventory, ItemMeta};
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
+
+
+/// Change to make to an item metadata variable.
+#[derive(Clone, Copy, Debug)]
+pub enum ItemMetaEdit<'a> {
+	/// Set the variable to a new value. Item stacks which don't have the
+	/// variable are not changed; the variable is never added.
+	Set(&'a str),
+	/// Rename the variable, keeping its value.
+	Rename(&'a str),
+	/// Delete the variable.
+	Delete,
+}
+
+
+/// Same as `ItemMetaEdit`, with values converted to bytes.
+enum EditBytes {
+	Set(Vec<u8>),
+	Rename(Vec<u8>),
+	Delete,
+}
+
+
+fn check_new_key(key: &str, edit: ItemMetaEdit) -> Result<(), CommandError> {
+	if let ItemMetaEdit::Rename(new_key) = edit {
+		ensure_arg(!new_key.is_empty() && new_key != key, "Invalid new key.")?;
+	}
+	Ok(())
+}
+
+
+/// Edit the metadata variable `key` of each item stack in `inv` named `item`.
+/// Returns the number of stacks modified, or an error if the inventory or
+/// the metadata of one of the stacks can't be read.
+fn edit_inv(inv: &mut Vec<u8>, item: &[u8], key: &[u8], edit: &EditBytes)
+	-> Result<u64, MapBlockError>
+{
+	let mut parsed = Inventory::deserialize(inv)?;
+	let mut mods = 0;
+
+	for stack in parsed.items_mut().filter(|s| s.name == item) {
+		let mut meta = ItemMeta::deserialize(&stack.meta)?;
+		let value = match meta.vars.get_mut(key) {
+			Some(v) => v,
+			None => continue
+		};
+
+		match edit {
+			EditBytes::Set(new_value) => {
+				if value == new_value {
+					continue;
+				}
+				*value = new_value.clone();
+			},
+			EditBytes::Rename(new_key) => {
+				let value = meta.vars.remove(key).unwrap();
+				meta.vars.insert(new_key.clone(), value);
+			},
+			EditBytes::Delete => { meta.vars.remove(key); },
+		}
+		stack.meta = meta.serialize();
+		mods += 1;
+	}
+
+	if mods > 0 {
+		*inv = parsed.serialize();
+	}
+	Ok(mods)
+}
+
+
+/// Set, rename, or delete the metadata variable `key` of all `item` stacks
+/// in node inventories which have the variable. If `nodes` is not empty,
+/// only inventories of the listed nodes are modified.
+pub fn set_item_meta(
+	ctx: &mut EditContext,
+	item: &str,
+	key: &str,
+	edit: ItemMetaEdit,
+	nodes: &[String],
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(nodes, "node")?;
+	check_names(&[item], "item")?;
+	check_new_key(key, edit)?;
+
+	let item = to_bytes(item);
+	let key = to_bytes(key);
+	let edit = match edit {
+		ItemMetaEdit::Set(v) => EditBytes::Set(to_bytes(v)),
+		ItemMetaEdit::Rename(k) => EditBytes::Rename(to_bytes(k)),
+		ItemMetaEdit::Delete => EditBytes::Delete,
+	};
+
+	let nodes: Vec<_> = nodes.iter().map(to_bytes).collect();
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		&nodes, area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let item_mods = AtomicU64::new(0);
+	let node_mods = AtomicU64::new(0);
+	let unreadable = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|block_key, data| {
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			let node_ids: Vec<_> = nodes.iter()
+				.filter_map(|n| block.nimap.get_id(n)).collect();
+			if !nodes.is_empty() && node_ids.is_empty() {
+				// Block doesn't contain any of the required nodes.
+				return BlockAction::Keep;
+			}
+
+			let block_corner = Vec3::from_block_key(block_key) * 16;
+			let mut modified = false;
+
+			for (&idx, data) in &mut block.metadata {
+				let pos = Vec3::from_u16_key(idx);
+				if let Some(a) = area {
+					if a.contains(pos + block_corner) == invert {
+						continue;
+					}
+				}
+				if !node_ids.is_empty()
+					&& !node_ids.contains(&block.node_data.nodes[idx as usize])
+				{
+					continue;
+				}
+
+				let i_mods = unwrap_or!(edit_inv(&mut data.inv, &item, &key,
+					&edit), {
+						unreadable.fetch_add(1, Ordering::Relaxed);
+						continue;
+					});
+				if i_mods > 0 {
+					item_mods.fetch_add(i_mods, Ordering::Relaxed);
+					node_mods.fetch_add(1, Ordering::Relaxed);
+					modified = true;
+				}
+			}
+
+			if modified {
+				BlockAction::Write(block.serialize(level))
+			} else {
+				BlockAction::Keep
+			}
+		}
+	)?;
+
+	let item_mods = item_mods.into_inner();
+	let unreadable = unreadable.into_inner();
+	ctx.status.end_editing();
+	if unreadable > 0 {
+		ctx.status.log_warning(format!(
+			"Skipped {} nodes with inventories which could not be read.",
+			fmt_big_num(unreadable)));
+	}
+	ctx.status.log_info(format!(
+		"Modified item metadata of {} itemstacks in {} nodes.",
+		fmt_big_num(item_mods), fmt_big_num(node_mods.into_inner())));
+	Ok(Summary::new(item_mods))
+}
+
+
+fn verify_args(args: &InstArgs) -> ArgResult {
+	let num_edits = args.value.is_some() as u8 + args.new_key.is_some() as u8
+		+ args.delete as u8;
+	if num_edits != 1 {
+		return ArgResult::error(
+			"Exactly one of value, --newkey, or --delete is required.");
+	}
+	check_new_key(args.key.as_deref().unwrap_or_default(), get_edit(args))
+		.into()
+}
+
+
+fn get_edit(args: &InstArgs) -> ItemMetaEdit<'_> {
+	if let Some(value) = &args.value {
+		ItemMetaEdit::Set(value)
+	} else if let Some(new_key) = &args.new_key {
+		ItemMetaEdit::Rename(new_key)
+	} else {
+		ItemMetaEdit::Delete
+	}
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	set_item_meta(&mut inst.ctx, args.item.as_ref().unwrap(),
+		args.key.as_ref().unwrap(), get_edit(args), &args.nodes, args.area,
+		args.invert)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::Item, "Name of the item to modify"),
+			(ArgType::Key(true), "Name of the item metadata variable to modify"),
+			(ArgType::Value,
+				"Value to set the variable to, if setting it. Only items \
+				which already have the variable are changed."),
+			(ArgType::NewKey, "New name of the variable, if renaming it"),
+			(ArgType::Delete, "Delete the variable."),
+			(ArgType::Nodes,
+				"Names of one or more nodes to modify inventories of"),
+			(ArgType::Area(false), "Area in which to modify node inventories"),
+			(ArgType::Invert,
+				"Modify node inventories *outside* the given area."),
+		],
+		help: "Set, rename, or delete an existing metadata variable of \
+			certain items in node inventories."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::{edit_inv, EditBytes};
+
+	#[test]
+	fn test_edit_inv() {
+		let original = b"\
+			List main 3\n\
+			Width 0\n\
+			Item default:book 1 0 \"\\u0001owner\\u0002bob\\u0003\
+				title\\u0002Notes\\u0003\"\n\
+			Item default:book\n\
+			Item default:key 1 0 \"\\u0001owner\\u0002bob\\u0003\"\n\
+			EndInventoryList\n\
+			EndInventory\n";
+		let set = b"\
+			List main 3\n\
+			Width 0\n\
+			Item default:book 1 0 \"\\u0001owner\\u0002alice\\u0003\
+				title\\u0002Notes\\u0003\"\n\
+			Item default:book\n\
+			Item default:key 1 0 \"\\u0001owner\\u0002bob\\u0003\"\n\
+			EndInventoryList\n\
+			EndInventory\n";
+		let renamed = b"\
+			List main 3\n\
+			Width 0\n\
+			Item default:book 1 0 \"\\u0001author\\u0002bob\\u0003\
+				title\\u0002Notes\\u0003\"\n\
+			Item default:book\n\
+			Item default:key 1 0 \"\\u0001owner\\u0002bob\\u0003\"\n\
+			EndInventoryList\n\
+			EndInventory\n";
+		let deleted = b"\
+			List main 3\n\
+			Width 0\n\
+			Item default:book\n\
+			Item default:book\n\
+			Item default:key 1 0 \"\\u0001owner\\u0002bob\\u0003\"\n\
+			EndInventoryList\n\
+			EndInventory\n";
+
+		let mut inv = original.to_vec();
+		let edit = EditBytes::Set(b"alice".to_vec());
+		assert_eq!(edit_inv(&mut inv, b"default:book", b"owner", &edit)
+			.unwrap(), 1);
+		assert_eq!(&inv, set);
+		// Setting the same value again does nothing.
+		assert_eq!(edit_inv(&mut inv, b"default:book", b"owner", &edit)
+			.unwrap(), 0);
+
+		let mut inv = original.to_vec();
+		let edit = EditBytes::Rename(b"author".to_vec());
+		assert_eq!(edit_inv(&mut inv, b"default:book", b"owner", &edit)
+			.unwrap(), 1);
+		assert_eq!(&inv, renamed);
+
+		let mut inv = original.to_vec();
+		assert_eq!(edit_inv(&mut inv, b"default:book", b"owner",
+			&EditBytes::Delete).unwrap(), 1);
+		assert_eq!(edit_inv(&mut inv, b"default:book", b"title",
+			&EditBytes::Delete).unwrap(), 1);
+		assert_eq!(&inv, deleted);
+
+		let mut inv = b"List main 1\nItem default:book 1 0 \"\\u0001owner\n\
+			EndInventoryList\nEndInventory\n".to_vec();
+		assert!(edit_inv(&mut inv, b"default:book", b"owner",
+			&EditBytes::Delete).is_err());
+		assert!(edit_inv(&mut b"Bad".to_vec(), b"default:book", b"owner",
+			&EditBytes::Delete).is_err());
+	}
+}
@@ -1,10 +1,14 @@
-use super::{Command, ArgResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	ensure_arg, check_area, check_names};
 
 use crate::unwrap_or;
-use crate::spatial::Vec3;
-use crate::instance::{ArgType, InstArgs, InstBundle};
+use crate::spatial::{Vec3, Area};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
 use crate::map_block::MapBlock;
-use crate::utils::{query_keys, to_bytes, fmt_big_num};
+use crate::utils::{query_blocks, to_bytes, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
 
 
 fn verify_args(args: &InstArgs) -> ArgResult {
@@ -14,83 +18,118 @@ fn verify_args(args: &InstArgs) -> ArgResult {
 	} else if args.value.is_some() && args.delete {
 		return ArgResult::error(
 			"value cannot be used when deleting the variable.");
-	} else if args.value == Some(String::new()) {
-		return ArgResult::error("Metadata value cannot be empty.");
 	}
-	ArgResult::Ok
+	check_value(args.value.as_deref()).into()
+}
+
+
+fn check_value(value: Option<&str>) -> Result<(), CommandError> {
+	ensure_arg(value != Some(""), "Metadata value cannot be empty.")
 }
 
 
-fn set_meta_var(inst: &mut InstBundle) {
+/// Set the metadata variable `key` to `value` in all nodes which have it, or
+/// delete the variable if `value` is `None`. If `nodes` is not empty, only
+/// the listed nodes are modified.
+pub fn set_meta_var(
+	ctx: &mut EditContext,
+	key: &str,
+	value: Option<&str>,
+	nodes: &[String],
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(nodes, "node")?;
+	check_value(value)?;
+
 	// TODO: Bytes input
-	let key = to_bytes(inst.args.key.as_ref().unwrap());
-	let value = to_bytes(inst.args.value.as_ref().unwrap_or(&String::new()));
-	let nodes: Vec<_> = inst.args.nodes.iter().map(to_bytes).collect();
-
-	let keys = query_keys(&mut inst.db, &mut inst.status,
-		&nodes, inst.args.area, inst.args.invert, true);
-
-	inst.status.begin_editing();
-	let mut count: u64 = 0;
-
-	for block_key in keys {
-		inst.status.inc_done();
-		let data = inst.db.get_block(block_key).unwrap();
-		let mut block = unwrap_or!(MapBlock::deserialize(&data),
-			{ inst.status.inc_failed(); continue; });
-
-		let node_ids: Vec<_> = nodes.iter()
-			.filter_map(|n| block.nimap.get_id(n)).collect();
-		if !nodes.is_empty() && node_ids.is_empty() {
-			continue; // Block doesn't contain any of the required nodes.
-		}
+	let key = to_bytes(key);
+	let delete = value.is_none();
+	let value = to_bytes(value.unwrap_or_default());
+	let nodes: Vec<_> = nodes.iter().map(to_bytes).collect();
+
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		&nodes, area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let count = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|block_key, data| {
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			let node_ids: Vec<_> = nodes.iter()
+				.filter_map(|n| block.nimap.get_id(n)).collect();
+			if !nodes.is_empty() && node_ids.is_empty() {
+				// Block doesn't contain any of the required nodes.
+				return BlockAction::Keep;
+			}
 
-		let block_corner = Vec3::from_block_key(block_key) * 16;
-		let mut modified = false;
+			let block_corner = Vec3::from_block_key(block_key) * 16;
+			let mut modified = false;
 
-		for (&idx, data) in &mut block.metadata {
-			let pos = Vec3::from_u16_key(idx);
+			for (&idx, data) in &mut block.metadata {
+				let pos = Vec3::from_u16_key(idx);
 
-			if let Some(a) = inst.args.area {
-				if a.contains(pos + block_corner) == inst.args.invert {
+				if let Some(a) = area {
+					if a.contains(pos + block_corner) == invert {
+						continue;
+					}
+				}
+				if !node_ids.is_empty()
+					&& !node_ids.contains(&block.node_data.nodes[idx as usize])
+				{
 					continue;
 				}
-			}
-			if !node_ids.is_empty()
-				&& !node_ids.contains(&block.node_data.nodes[idx as usize])
-			{
-				continue;
-			}
 
-			if data.vars.contains_key(&key) {
-				if inst.args.delete {
-					// Note: serialize() will cull any newly empty metadata.
-					data.vars.remove(&key);
-				} else {
-					data.vars.get_mut(&key).unwrap().0 = value.clone();
+				if data.vars.contains_key(&key) {
+					if delete {
+						// Note: serialize() will cull any newly empty metadata.
+						data.vars.remove(&key);
+					} else {
+						data.vars.get_mut(&key).unwrap().0 = value.clone();
+					}
+					modified = true;
+					count.fetch_add(1, Ordering::Relaxed);
 				}
-				modified = true;
-				count += 1;
 			}
-		}
 
-		if modified {
-			inst.db.set_block(block_key, &block.serialize()).unwrap();
+			if modified {
+				BlockAction::Write(block.serialize(level))
+			} else {
+				BlockAction::Keep
+			}
 		}
-	}
+	)?;
+
+	let count = count.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("Set metadata variable of {} nodes.",
+		fmt_big_num(count)));
+	Ok(Summary::new(count))
+}
+
 
-	inst.status.end_editing();
-	inst.status.log_info(
-		format!("Set metadata variable of {} nodes.", fmt_big_num(count)));
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	let value = if args.delete { None } else { args.value.as_deref() };
+	set_meta_var(&mut inst.ctx, args.key.as_ref().unwrap(), value,
+		&args.nodes, args.area, args.invert)
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: set_meta_var,
+		func: run,
 		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
-			(ArgType::Key, "Name of variable to set/delete"),
+			(ArgType::Key(true), "Name of variable to set/delete"),
 			(ArgType::Value, "Value to set variable to, if setting a value"),
 			(ArgType::Delete, "Delete the variable."),
 			(ArgType::Nodes,
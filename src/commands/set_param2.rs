@@ -1,10 +1,14 @@
-use super::{Command, ArgResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	ensure_arg, check_area, check_names};
 
 use crate::unwrap_or;
 use crate::spatial::{Vec3, Area, InverseBlockIterator};
-use crate::instance::{ArgType, InstArgs, InstBundle};
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
 use crate::map_block::MapBlock;
-use crate::utils::{query_keys, to_bytes, to_slice, fmt_big_num};
+use crate::utils::{query_blocks, to_bytes, to_slice, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
 
 
 fn set_param2_partial(block: &mut MapBlock, area: Area, invert: bool,
@@ -50,73 +54,102 @@ fn set_param2_partial(block: &mut MapBlock, area: Area, invert: bool,
 }
 
 
-fn set_param2(inst: &mut InstBundle) {
-	let param2_val = inst.args.param2.unwrap();
-	let node = inst.args.node.as_ref().map(to_bytes);
-
-	let keys = query_keys(&mut inst.db, &mut inst.status,
-		to_slice(&node), inst.args.area, inst.args.invert, true);
-
-	inst.status.begin_editing();
-
-	let mut count: u64 = 0;
-	for key in keys {
-		inst.status.inc_done();
-
-		let pos = Vec3::from_block_key(key);
-		let data = inst.db.get_block(key).unwrap();
-		let mut block = unwrap_or!(MapBlock::deserialize(&data),
-			{ inst.status.inc_failed(); continue; });
-
-		let node_id = node.as_ref().and_then(|n| block.nimap.get_id(n));
-		if inst.args.node.is_some() && node_id.is_none() {
-			// Node not found in this mapblock.
-			continue;
-		}
+/// Set the param2 value of all nodes named `node`, or of all nodes in `area`
+/// if no node is given. At least one of `node` and `area` is required.
+pub fn set_param2(
+	ctx: &mut EditContext,
+	param2_val: u8,
+	node: Option<&str>,
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_area(area, invert)?;
+	check_names(node.as_slice(), "node")?;
+	check_args(node, area)?;
+
+	let node = node.map(to_bytes);
+
+	let blocks = query_blocks(&ctx.db, ctx.index.as_mut(), ctx.status,
+		to_slice(&node), area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let count = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|key, data| {
+			let pos = Vec3::from_block_key(key);
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+
+			let node_id = node.as_ref().and_then(|n| block.nimap.get_id(n));
+			if node.is_some() && node_id.is_none() {
+				// Node not found in this mapblock.
+				return BlockAction::Keep;
+			}
 
-		let nd = &mut block.node_data;
-		if let Some(area) = inst.args.area
-			.filter(|a| a.contains_block(pos) != a.touches_block(pos))
-		{ // Modify part of block
-			let block_part = area.rel_block_overlap(pos).unwrap();
-			count += set_param2_partial(&mut block,
-				block_part, inst.args.invert, node_id, param2_val);
-		} else { // Modify whole block
-			if let Some(nid) = node_id {
-				for i in 0 .. nd.param2.len() {
-					if nd.nodes[i] == nid {
-						nd.param2[i] = param2_val;
-						count += 1;
+			let nd = &mut block.node_data;
+			let mut block_count = 0;
+			if let Some(area) = area
+				.filter(|a| a.contains_block(pos) != a.touches_block(pos))
+			{ // Modify part of block
+				let block_part = area.rel_block_overlap(pos).unwrap();
+				block_count += set_param2_partial(&mut block,
+					block_part, invert, node_id, param2_val);
+			} else { // Modify whole block
+				if let Some(nid) = node_id {
+					for i in 0 .. nd.param2.len() {
+						if nd.nodes[i] == nid {
+							nd.param2[i] = param2_val;
+							block_count += 1;
+						}
 					}
+				} else {
+					nd.param2.fill(param2_val);
+					block_count += nd.param2.len() as u64;
 				}
-			} else {
-				nd.param2.fill(param2_val);
-				count += nd.param2.len() as u64;
 			}
-		}
 
-		inst.db.set_block(key, &block.serialize()).unwrap();
-	}
+			count.fetch_add(block_count, Ordering::Relaxed);
+			BlockAction::Write(block.serialize(level))
+		}
+	)?;
 
-	inst.status.end_editing();
-	inst.status.log_info(format!("Set param2 of {} nodes.",
+	let count = count.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("Set param2 of {} nodes.",
 		fmt_big_num(count)));
+	Ok(Summary::new(count))
 }
 
 
-fn verify_args(args: &InstArgs) -> ArgResult {
-	if args.area.is_none() && args.node.is_none() {
-		return ArgResult::error("An area and/or node is required.");
-	}
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	set_param2(&mut inst.ctx, args.param2.unwrap(), args.node.as_deref(),
+		args.area, args.invert)
+}
+
 
-	ArgResult::Ok
+fn check_args(node: Option<&str>, area: Option<Area>)
+	-> Result<(), CommandError>
+{
+	ensure_arg(area.is_some() || node.is_some(),
+		"An area and/or node is required.")
+}
+
+
+fn verify_args(args: &InstArgs) -> ArgResult {
+	check_args(args.node.as_deref(), args.area).into()
 }
 
 
 pub fn get_command() -> Command {
 	Command {
-		func: set_param2,
+		func: run,
 		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: vec![
 			(ArgType::Node(false), "Name of node to modify"),
 			(ArgType::Area(false), "Area in which to set param2 values"),
@@ -0,0 +1,58 @@
+use super::{Command, WorldDb, CommandError, CommandResult, Summary,
+	check_player_names};
+
+use crate::spatial::Vec3;
+use crate::instance::{ArgType, InstBundle, Progress};
+use crate::player_database::PlayerDatabase;
+
+
+/// Move the saved position of `player` to the node position `pos`. The
+/// player will be there the next time they join the game.
+pub fn teleport_player(
+	db: &mut PlayerDatabase,
+	status: &dyn Progress,
+	player: &str,
+	pos: Vec3
+) -> CommandResult {
+	check_player_names(&[player])?;
+	if !pos.is_valid_node_pos() {
+		return Err(CommandError::InvalidArgs(
+			format!("Position is outside map bounds: {}.", pos)));
+	}
+
+	let coords = [pos.x as f64, pos.y as f64, pos.z as f64];
+	match db.set_position(player, coords) {
+		Ok(true) => {
+			status.log_info(
+				format!("Moved player \"{}\" to {}.", player, pos));
+			Ok(Summary::new(1))
+		},
+		Ok(false) => Err(CommandError::Failed(
+			format!("Player \"{}\" does not exist.", player))),
+		Err(e) => Err(CommandError::Failed(
+			format!("Failed to move player: {}", e))),
+	}
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	teleport_player(inst.pdb.as_mut().unwrap(), inst.ctx.status,
+		args.player.as_ref().unwrap(), args.pos.unwrap())
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: None,
+		world_dbs: &[WorldDb::Players],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::Player, "Name of the player to move"),
+			(ArgType::Pos, "Node position to move the player to"),
+		],
+		help: "Change the saved position of a player."
+	}
+}
@@ -0,0 +1,131 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Command, ArgResult, CommandError, CommandResult, Summary,
+	ensure_arg, check_area};
+
+use crate::unwrap_or;
+use crate::spatial::Area;
+use crate::instance::{ArgType, InstArgs, InstBundle, EditContext};
+use crate::map_block::MapBlock;
+use crate::utils::{query_blocks, fmt_big_num};
+use crate::worker_pool::{process_blocks, BlockAction};
+
+
+fn check_version(version: u8) -> Result<(), CommandError> {
+	ensure_arg(version == 28 || version == 29,
+		"Mapblock version must be 28 or 29.")
+}
+
+
+fn verify_args(args: &InstArgs) -> ArgResult {
+	check_version(args.block_version.unwrap_or_default()).into()
+}
+
+
+/// Convert mapblocks inside or outside an area to the given format version,
+/// which must be 28 or 29.
+pub fn upgrade_blocks(
+	ctx: &mut EditContext,
+	version: u8,
+	area: Option<Area>,
+	invert: bool
+) -> CommandResult {
+	check_version(version)?;
+	check_area(area, invert)?;
+
+	let blocks = query_blocks(&ctx.db, None, ctx.status,
+		&[], area, invert, true);
+
+	ctx.status.begin_editing();
+	let level = ctx.compression;
+	let count = AtomicU64::new(0);
+
+	process_blocks(&mut ctx.db, ctx.status, ctx.threads, blocks,
+		|_, data| {
+			if data.first() == Some(&version) {
+				return BlockAction::Keep; // Already the right version.
+			}
+
+			let mut block = unwrap_or!(MapBlock::deserialize(data),
+				return BlockAction::Fail);
+			block.version = version;
+			count.fetch_add(1, Ordering::Relaxed);
+			BlockAction::Write(block.serialize(level))
+		}
+	)?;
+
+	let count = count.into_inner();
+	ctx.status.end_editing();
+	ctx.status.log_info(format!("Converted {} mapblocks to version {}.",
+		fmt_big_num(count), version));
+	Ok(Summary::new(count))
+}
+
+
+fn run(inst: &mut InstBundle) -> CommandResult {
+	let args = &inst.args;
+	upgrade_blocks(&mut inst.ctx, args.block_version.unwrap(), args.area,
+		args.invert)
+}
+
+
+pub fn get_command() -> Command {
+	Command {
+		func: run,
+		verify_args: Some(verify_args),
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
+		args: vec![
+			(ArgType::BlockVersion,
+				"Mapblock version to convert to: 29 (zstd, Minetest 5.5+) or \
+				28 (zlib, Minetest 0.4.16-5.4)"),
+			(ArgType::Area(false), "Area in which to convert mapblocks"),
+			(ArgType::Invert, "Convert mapblocks *outside* the given area."),
+		],
+		help: "Convert mapblocks to a different mapblock format version."
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::spatial::Vec3;
+	use crate::map_database::MapDatabase;
+	use crate::utils::{read_test_file, create_test_map, assert_same_contents,
+		TestProgress};
+
+	#[test]
+	fn test_upgrade_blocks() {
+		let files =
+			["mapblock_v25.bin", "mapblock_v28.bin", "mapblock_v29.bin"];
+		let keys: Vec<_> = (0..files.len() as i32)
+			.map(|x| Vec3::new(x, 0, 0).to_block_key())
+			.collect();
+
+		for &version in &[29, 28] {
+			let conn = create_test_map();
+			let progress = TestProgress::default();
+			let mut ctx = EditContext::new(MapDatabase::new(&conn).unwrap(),
+				&progress);
+			for (&key, file) in keys.iter().zip(&files) {
+				ctx.db.set_block(key, &read_test_file(file)).unwrap();
+			}
+			assert_eq!(upgrade_blocks(&mut ctx, version, None, false)
+				.unwrap(), Summary::new(2));
+			assert_eq!(progress.logs.lock().unwrap().last().unwrap(),
+				&format!("info: Converted 2 mapblocks to version {}.",
+					version));
+
+			for (&key, file) in keys.iter().zip(&files) {
+				let old = MapBlock::deserialize(&read_test_file(file))
+					.unwrap();
+				let new = MapBlock::deserialize(&ctx.db.get_block(key)
+					.unwrap()).unwrap();
+				assert_eq!(new.version, version);
+				assert_same_contents(&new, &old);
+			}
+		}
+	}
+}
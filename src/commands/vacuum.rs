@@ -1,29 +1,40 @@
-use super::Command;
+use super::{Command, CommandError, CommandResult, Summary};
 
-use crate::instance::InstBundle;
+use crate::instance::{InstBundle, EditContext};
 
 
-fn vacuum(inst: &mut InstBundle) {
-	inst.status.log_info("Starting vacuum.");
+/// Rebuild the map database to reduce its size. Commits any pending changes
+/// first.
+pub fn vacuum(ctx: &mut EditContext) -> CommandResult {
+	ctx.status.log_info("Starting vacuum.");
 
-	inst.status.set_show_progress(false); // No ETA for vacuum.
-	inst.status.begin_editing();
-	let res = inst.db.vacuum();
-	inst.status.end_editing();
+	ctx.status.set_show_progress(false); // No ETA for vacuum.
+	ctx.status.begin_editing();
+	let res = ctx.db.vacuum();
+	ctx.status.end_editing();
 
 	match res {
 		Ok(_) => {
-			inst.status.log_info("Completed vacuum.");
+			ctx.status.log_info("Completed vacuum.");
+			Ok(Summary::default())
 		},
-		Err(e) => inst.status.log_error(format!("Vacuum failed: {}.", e))
+		Err(e) => Err(CommandError::Failed(format!("Vacuum failed: {}.", e)))
 	}
 }
 
 
+fn run(inst: &mut InstBundle) -> CommandResult {
+	vacuum(&mut inst.ctx)
+}
+
+
 pub fn get_command() -> Command {
 	Command {
-		func: vacuum,
+		func: run,
 		verify_args: None,
+		world_dbs: &[],
+		optional_dbs: &[],
+		read_only: false,
 		args: Vec::new(),
 		help: "Rebuild the map database to reduce its size."
 	}
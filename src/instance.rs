@@ -1,13 +1,20 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
 
 use anyhow::Context;
 
-use crate::spatial::{Vec3, Area, MAP_LIMIT};
-use crate::map_database::MapDatabase;
+use crate::spatial::{Vec3, Area};
+use crate::map_block::CompressionLevel;
+use crate::map_database::{MapDatabase, DBError};
+use crate::player_database::PlayerDatabase;
+use crate::auth_database::AuthDatabase;
+use crate::mod_storage_database::ModStorageDatabase;
+use crate::block_index::BlockIndex;
 use crate::commands;
-use crate::commands::ArgResult;
+use crate::commands::{ArgResult, Command, WorldDb, is_valid_name,
+	is_valid_player_name, is_valid_mod_name, check_offset};
 use crate::utils::fmt_big_num;
 
 
@@ -26,13 +33,39 @@ pub enum ArgType {
 	NewItem,
 	Delete,
 	DeleteMeta,
-	Key,
+	Key(bool),
+	NewKey,
 	Value,
 	Param2,
+	Timestamp,
+	AllowMeta,
+	List,
+	BlockVersion,
+	LuaScript,
+	Count,
+	MaxCount,
+	Wear,
+	ListName,
+	Size,
+	NewName,
+	Overflow,
+	JsonFile,
+	Pattern,
+	Replacement,
+	Regex,
+	Player,
+	NewPlayer,
+	Players,
+	Pos,
+	Before(bool),
+	IncludeUnknown,
+	Privs,
+	ModName(bool),
+	NewModName,
 }
 
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct InstArgs {
 	pub do_confirmation: bool,
 	pub command: String,
@@ -51,8 +84,40 @@ pub struct InstArgs {
 	pub delete: bool,
 	pub delete_meta: bool,
 	pub key: Option<String>,
+	pub new_key: Option<String>,
 	pub value: Option<String>,
 	pub param2: Option<u8>,
+	pub timestamp: Option<u32>,
+	pub allow_meta: bool,
+	pub list: bool,
+	pub block_version: Option<u8>,
+	pub lua_script: Option<String>,
+	pub count: Option<u16>,
+	pub max_count: Option<u16>,
+	pub wear: Option<u16>,
+	pub list_name: Option<String>,
+	pub size: Option<u32>,
+	pub new_name: Option<String>,
+	pub overflow: Option<String>,
+	pub json_file: Option<String>,
+	pub pattern: Option<String>,
+	pub replacement: Option<String>,
+	pub regex: bool,
+	pub player: Option<String>,
+	pub new_player: Option<String>,
+	pub players: Vec<String>,
+	pub pos: Option<Vec3>,
+	/// Unix timestamp.
+	pub before: Option<i64>,
+	pub include_unknown: bool,
+	pub privs: Vec<String>,
+	pub mod_name: Option<String>,
+	pub new_mod_name: Option<String>,
+	pub compression: CompressionLevel,
+	pub threads: usize,
+	pub use_index: bool,
+	/// Commands to run with `run`, along with their line numbers.
+	pub script: Vec<(usize, InstArgs)>,
 }
 
 
@@ -71,6 +136,8 @@ pub struct InstStatus {
 	pub blocks_total: usize,
 	pub blocks_done: usize,
 	pub blocks_failed: usize,
+	/// Whether an error has been logged.
+	pub had_error: bool,
 	pub state: InstState
 }
 
@@ -81,6 +148,7 @@ impl InstStatus {
 			blocks_total: 0,
 			blocks_done: 0,
 			blocks_failed: 0,
+			had_error: false,
 			state: InstState::Ignore
 		}
 	}
@@ -104,6 +172,49 @@ impl std::fmt::Display for LogType {
 }
 
 
+/// Receives progress updates and log messages from a running command.
+///
+/// All methods do nothing by default, so implementors only need to handle
+/// the updates they care about.
+pub trait Progress {
+	/// Called when a command starts or finishes querying or editing.
+	fn set_state(&self, _new_state: InstState) {}
+	/// Set the total number of mapblocks to be processed.
+	fn set_total(&self, _total: usize) {}
+	/// Called after each mapblock is processed.
+	fn inc_done(&self) {}
+	/// Called for each invalid or unsupported mapblock that was skipped.
+	fn inc_failed(&self) {}
+	/// Set whether the total is known, i.e. whether progress can be shown.
+	fn set_show_progress(&self, _sp: bool) {}
+	/// Log a message. Commands log an error when they fail, in which case
+	/// their changes should not be committed.
+	fn log(&self, _lt: LogType, _msg: &str) {}
+}
+
+impl dyn Progress + '_ {
+	pub fn begin_editing(&self) {
+		self.set_state(InstState::Editing);
+	}
+
+	pub fn end_editing(&self) {
+		self.set_state(InstState::Ignore);
+	}
+
+	pub fn log_info<S: AsRef<str>>(&self, msg: S) {
+		self.log(LogType::Info, msg.as_ref());
+	}
+
+	pub fn log_warning<S: AsRef<str>>(&self, msg: S) {
+		self.log(LogType::Warning, msg.as_ref());
+	}
+
+	pub fn log_error<S: AsRef<str>>(&self, msg: S) {
+		self.log(LogType::Error, msg.as_ref());
+	}
+}
+
+
 pub enum ServerEvent {
 	Log(LogType, String),
 	NewState(InstState),
@@ -127,33 +238,13 @@ impl StatusServer {
 		self.status.lock().unwrap().clone()
 	}
 
-	pub fn set_state(&self, new_state: InstState) {
-		self.status.lock().unwrap().state = new_state;
-		self.event_tx.send(ServerEvent::NewState(new_state)).unwrap();
-	}
-
-	pub fn set_total(&self, total: usize) {
-		self.status.lock().unwrap().blocks_total = total;
-	}
-
-	pub fn inc_done(&self) {
-		self.status.lock().unwrap().blocks_done += 1;
-	}
-
-	pub fn inc_failed(&mut self) {
-		self.status.lock().unwrap().blocks_failed += 1;
-	}
-
-	pub fn set_show_progress(&self, sp: bool) {
-		self.status.lock().unwrap().show_progress = sp;
-	}
-
-	pub fn begin_editing(&self) {
-		self.set_state(InstState::Editing);
-	}
-
-	pub fn end_editing(&self) {
-		self.set_state(InstState::Ignore);
+	/// Reset the progress counters before running another command. Failed
+	/// mapblocks are still counted.
+	fn reset_progress(&self) {
+		let mut status = self.status.lock().unwrap();
+		status.blocks_total = 0;
+		status.blocks_done = 0;
+		status.show_progress = true;
 	}
 
 	pub fn get_confirmation(&self) -> bool {
@@ -165,22 +256,35 @@ impl StatusServer {
 		}
 		false
 	}
+}
 
-	fn log<S: AsRef<str>>(&self, lt: LogType, msg: S) {
-		self.event_tx.send(ServerEvent::Log(lt, msg.as_ref().to_string()))
-			.unwrap();
+impl Progress for StatusServer {
+	fn set_state(&self, new_state: InstState) {
+		self.status.lock().unwrap().state = new_state;
+		self.event_tx.send(ServerEvent::NewState(new_state)).unwrap();
 	}
 
-	pub fn log_info<S: AsRef<str>>(&self, msg: S) {
-		self.log(LogType::Info, msg);
+	fn set_total(&self, total: usize) {
+		self.status.lock().unwrap().blocks_total = total;
 	}
 
-	pub fn log_warning<S: AsRef<str>>(&self, msg: S) {
-		self.log(LogType::Warning, msg);
+	fn inc_done(&self) {
+		self.status.lock().unwrap().blocks_done += 1;
 	}
 
-	pub fn log_error<S: AsRef<str>>(&self, msg: S) {
-		self.log(LogType::Error, msg);
+	fn inc_failed(&self) {
+		self.status.lock().unwrap().blocks_failed += 1;
+	}
+
+	fn set_show_progress(&self, sp: bool) {
+		self.status.lock().unwrap().show_progress = sp;
+	}
+
+	fn log(&self, lt: LogType, msg: &str) {
+		if let LogType::Error = lt {
+			self.status.lock().unwrap().had_error = true;
+		}
+		self.event_tx.send(ServerEvent::Log(lt, msg.to_string())).unwrap();
 	}
 }
 
@@ -207,7 +311,7 @@ impl StatusClient {
 }
 
 
-fn status_link() -> (StatusServer, StatusClient) {
+pub fn status_link() -> (StatusServer, StatusClient) {
 	let status1 = Arc::new(Mutex::new(InstStatus::new()));
 	let status2 = status1.clone();
 	let (s_event_tx, s_event_rx) = mpsc::channel();
@@ -227,15 +331,57 @@ fn status_link() -> (StatusServer, StatusClient) {
 }
 
 
+/// The map being edited, along with the settings shared by all commands.
+pub struct EditContext<'a> {
+	pub db: MapDatabase<'a>,
+	/// Node name index used to skip mapblocks without decompressing them.
+	pub index: Option<BlockIndex<'a>>,
+	pub status: &'a dyn Progress,
+	/// Number of threads to process mapblocks with.
+	pub threads: usize,
+	/// Compression level for modified mapblocks.
+	pub compression: CompressionLevel,
+}
+
+impl<'a> EditContext<'a> {
+	/// Create a context using one thread per CPU core, the default
+	/// compression level, and no node name index.
+	pub fn new(db: MapDatabase<'a>, status: &'a dyn Progress) -> Self {
+		Self {
+			db,
+			index: None,
+			status,
+			threads: std::thread::available_parallelism()
+				.map_or(1, |n| n.get()),
+			compression: CompressionLevel::default(),
+		}
+	}
+
+	/// Commit all changes to the map and the node name index, if any.
+	pub fn commit(&mut self) -> Result<(), DBError> {
+		if let Some(index) = &mut self.index {
+			index.commit_if_needed()?;
+		}
+		self.db.commit_if_needed()
+	}
+}
+
+
 pub struct InstBundle<'a> {
 	pub args: InstArgs,
-	pub status: StatusServer,
-	pub db: MapDatabase<'a>,
-	pub idb: Option<MapDatabase<'a>>
+	pub ctx: EditContext<'a>,
+	pub idb: Option<MapDatabase<'a>>,
+	/// Player database, if the command operates on one.
+	pub pdb: Option<PlayerDatabase<'a>>,
+	/// Auth database, if the command operates on one.
+	pub adb: Option<AuthDatabase<'a>>,
+	/// Mod storage database, if the command operates on one.
+	pub msdb: Option<ModStorageDatabase<'a>>,
 }
 
 
 fn verify_args(args: &InstArgs) -> anyhow::Result<()> {
+	anyhow::ensure!(args.threads > 0, "Thread count must be at least 1.");
 	if args.area.is_none() && args.invert {
 		anyhow::bail!("Cannot invert without a specified area.");
 	}
@@ -245,33 +391,12 @@ fn verify_args(args: &InstArgs) -> anyhow::Result<()> {
 				"Area corner is outside map bounds: {}.", pos);
 		}
 	}
-	if let Some(offset) = args.offset {
-		let huge = |n| n < -MAP_LIMIT * 2 || n > MAP_LIMIT * 2;
-
-		if huge(offset.x) || huge(offset.y) || huge(offset.z) {
-			anyhow::bail!(
-				"Offset cannot be larger than {} nodes in any direction.",
-				MAP_LIMIT * 2);
-		}
+	if let Some(pos) = args.pos {
+		anyhow::ensure!(pos.is_valid_node_pos(),
+			"Position is outside map bounds: {}.", pos);
 	}
-
-	fn is_valid_name(name: &str) -> bool {
-		if name == "air" || name == "ignore" {
-			true
-		} else {
-			let delim = match name.find(':') {
-				Some(d) => d,
-				None => return false
-			};
-
-			let mod_name = &name[..delim];
-			let item_name = &name[delim + 1..];
-
-			mod_name.chars().all(|c: char|
-				c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
-			&& item_name.chars().all(|c: char|
-				c.is_ascii_alphanumeric() || c == '_')
-		}
+	if let Some(offset) = args.offset {
+		check_offset(args.area, offset)?;
 	}
 
 	macro_rules! verify_name {
@@ -295,91 +420,246 @@ fn verify_args(args: &InstArgs) -> anyhow::Result<()> {
 		}
 	}
 	verify_name!(args.new_item, "Invalid item name: {}");
+	for p in args.player.iter().chain(&args.new_player).chain(&args.players) {
+		anyhow::ensure!(is_valid_player_name(p), "Invalid player name: {}", p);
+	}
+	for m in args.mod_name.iter().chain(&args.new_mod_name) {
+		anyhow::ensure!(is_valid_mod_name(m), "Invalid mod name: {}", m);
+	}
 	// TODO: Are keys/values escaped?
 
 	Ok(())
 }
 
 
-fn open_map(path: PathBuf, flags: sqlite::OpenFlags)
-	-> anyhow::Result<sqlite::Connection>
-{
-	let new_path = if path.is_file() {
-		path
+fn find_map_file(path: PathBuf) -> anyhow::Result<PathBuf> {
+	if path.is_file() {
+		Ok(path)
 	} else {
 		let with_file = path.join("map.sqlite");
 		if with_file.is_file() {
-			with_file
+			Ok(with_file)
 		} else {
 			anyhow::bail!("Could not find the map file.");
 		}
-	};
+	}
+}
+
 
+fn open_map(path: PathBuf, flags: sqlite::OpenFlags)
+	-> anyhow::Result<sqlite::Connection>
+{
+	let new_path = find_map_file(path)?;
 	Ok(sqlite::Connection::open_with_flags(new_path, flags)?)
 }
 
 
-fn compute_thread(args: InstArgs, status: StatusServer) -> anyhow::Result<()> {
-	verify_args(&args)?;
+/// Open the node name index belonging to a map, creating it if needed.
+fn open_index(map_path: PathBuf) -> anyhow::Result<sqlite::Connection> {
+	let mut index_path = find_map_file(map_path)?.into_os_string();
+	index_path.push(".mapeditr-index");
+	Ok(sqlite::Connection::open(index_path)?)
+}
 
+
+/// Get the path of another database in the same world directory as a map.
+fn world_db_path(map_path: PathBuf, db: WorldDb) -> anyhow::Result<PathBuf> {
+	Ok(find_map_file(map_path)?.with_file_name(db.file_name()))
+}
+
+
+/// Open another database in the same world directory as a map.
+fn open_world_db(map_path: PathBuf, db: WorldDb, flags: sqlite::OpenFlags)
+	-> anyhow::Result<sqlite::Connection>
+{
+	let path = world_db_path(map_path, db)?;
+	anyhow::ensure!(path.is_file(),
+		"Could not find {} in the world directory.", db.file_name());
+	Ok(sqlite::Connection::open_with_flags(path, flags)?)
+}
+
+
+/// Verify the arguments of a single command, returning any warning.
+fn verify_command(commands: &BTreeMap<&str, Command>, args: &InstArgs)
+	-> anyhow::Result<Option<String>>
+{
+	verify_args(args)?;
+
+	match commands[args.command.as_str()].verify_args.map(|f| f(args)) {
+		Some(ArgResult::Warning(w)) => Ok(Some(w)),
+		Some(ArgResult::Error(e)) => anyhow::bail!(e),
+		_ => Ok(None)
+	}
+}
+
+
+fn compute_thread(mut args: InstArgs, status: StatusServer)
+	-> anyhow::Result<()>
+{
+	// A script runs each of its commands in turn, tagged with their line
+	// numbers. Any other command is run on its own.
+	let steps: Vec<(Option<usize>, InstArgs)> = if args.command == "run" {
+		std::mem::take(&mut args.script).into_iter()
+			.map(|(line, step)| (Some(line), step)).collect()
+	} else {
+		vec![(None, args.clone())]
+	};
 	let commands = commands::get_commands();
-	let mut cmd_warning = None;
-	if let Some(cmd_verify) = commands[args.command.as_str()].verify_args {
-		cmd_warning = match cmd_verify(&args) {
-			ArgResult::Ok => None,
-			ArgResult::Warning(w) => Some(w),
-			ArgResult::Error(e) => anyhow::bail!(e)
-		}
+	let with_line = |line: Option<usize>, msg: String| match line {
+		Some(l) => format!("Line {}: {}", l, msg),
+		None => msg
+	};
+
+	let mut warnings = Vec::new();
+	for (line, step) in &steps {
+		let warning = verify_command(&commands, step)
+			.map_err(|e| anyhow::anyhow!(with_line(*line, e.to_string())))?;
+		warnings.extend(warning.map(|w| with_line(*line, w)));
 	}
 
-	let db_conn = open_map(
-		PathBuf::from(&args.map_path),
+	// Commands which only read the world don't need a warning or prompt.
+	let read_only = steps.iter()
+		.all(|(_, step)| commands[step.command.as_str()].read_only);
+	let flags = || if read_only {
+		sqlite::OpenFlags::new().set_read_only()
+	} else {
 		sqlite::OpenFlags::new().set_read_write()
-	).context("Failed to open main world/map.")?;
+	};
+
+	let db_conn = open_map(PathBuf::from(&args.map_path), flags())
+		.context("Failed to open main world/map.")?;
 	let db = MapDatabase::new(&db_conn)
 		.context("Main world or map database is invalid.")?;
 
-	let idb_conn = args.input_map_path.as_deref()
-		.map(|imp| open_map(PathBuf::from(imp),
-			sqlite::OpenFlags::new().set_read_only()))
-		.transpose().context("Failed to open input world/map.")?;
-	let idb = match &idb_conn {
-		Some(conn) => Some(MapDatabase::new(conn)
-			.context("Input world or map database is invalid.")?),
+	// Open all input maps up front, so that a script can't fail halfway
+	// through because of a missing map.
+	let idb_conns = steps.iter()
+		.map(|(_, step)| step.input_map_path.as_deref()
+			.map(|imp| open_map(PathBuf::from(imp),
+				sqlite::OpenFlags::new().set_read_only()))
+			.transpose())
+		.collect::<anyhow::Result<Vec<_>>>()
+		.context("Failed to open input world/map.")?;
+	let idbs = idb_conns.iter()
+		.map(|conn| conn.as_ref().map(MapDatabase::new).transpose())
+		.collect::<Result<Vec<_>, _>>()
+		.context("Input world or map database is invalid.")?;
+
+	let index_conn = if args.use_index {
+		Some(open_index(PathBuf::from(&args.map_path))
+			.context("Failed to open node name index.")?)
+	} else {
+		None
+	};
+	let index = match &index_conn {
+		Some(conn) => Some(BlockIndex::new(conn)
+			.context("Node name index is invalid.")?),
+		None => None
+	};
+
+	// Open any other world databases used by the commands. Optional
+	// databases are skipped if they don't exist.
+	let open_if_needed = |db| -> anyhow::Result<_> {
+		let uses = |f: fn(&Command) -> &'static [WorldDb]| steps.iter()
+			.any(|(_, step)| f(&commands[step.command.as_str()]).contains(&db));
+		let needed = uses(|c| c.world_dbs) || (uses(|c| c.optional_dbs)
+			&& world_db_path(PathBuf::from(&args.map_path), db)?.is_file());
+		if !needed {
+			return Ok(None);
+		}
+		let conn = open_world_db(PathBuf::from(&args.map_path), db, flags())
+			.with_context(|| format!("Failed to open {}.", db.file_name()))?;
+		Ok(Some(conn))
+	};
+	let pdb_conn = open_if_needed(WorldDb::Players)?;
+	let pdb = match &pdb_conn {
+		Some(conn) => Some(PlayerDatabase::new(conn)
+			.context("Player database is invalid.")?),
+		None => None
+	};
+	let adb_conn = open_if_needed(WorldDb::Auth)?;
+	let adb = match &adb_conn {
+		Some(conn) => Some(AuthDatabase::new(conn)
+			.context("Auth database is invalid.")?),
+		None => None
+	};
+	let msdb_conn = open_if_needed(WorldDb::ModStorage)?;
+	let msdb = match &msdb_conn {
+		Some(conn) => Some(ModStorageDatabase::new(conn)
+			.context("Mod storage database is invalid.")?),
 		None => None
 	};
 
-	let func = commands[args.command.as_str()].func;
-	let mut inst = InstBundle {args, status, db, idb};
+	let ctx = EditContext {
+		db,
+		index,
+		status: &status,
+		threads: args.threads,
+		compression: args.compression,
+	};
+	let mut inst = InstBundle {args, ctx, idb: None, pdb, adb, msdb};
+	let log: &dyn Progress = &status;
 
 	// Issue warnings and confirmation prompt.
-	if inst.args.do_confirmation {
-		inst.status.log_warning(
+	let do_confirmation = inst.args.do_confirmation && !read_only;
+	if do_confirmation {
+		log.log_warning(
 			"This tool can permanently damage your Minetest world.\n\
 			Always EXIT Minetest and BACK UP the map database before use.");
 	}
-	if let Some(w) = cmd_warning {
-		inst.status.log_warning(w);
+	for w in warnings {
+		log.log_warning(w);
 	}
-	if inst.args.do_confirmation && !inst.status.get_confirmation() {
+	if do_confirmation && !status.get_confirmation() {
 		return Ok(());
 	}
 
-	func(&mut inst); // The real thing!
+	// Nothing is committed until every command has finished; if any command
+	// fails, the whole transaction is rolled back.
+	for ((line, step), idb) in steps.into_iter().zip(idbs) {
+		if let Some(l) = line {
+			status.reset_progress();
+			log.log_info(format!("Line {}: Running {}.", l, step.command));
+		}
+		let func = commands[step.command.as_str()].func;
+		inst.args = step;
+		inst.idb = idb;
+		// The real thing!
+		if let Err(e) = func(&mut inst) {
+			log.log_error(with_line(line, e.to_string()));
+		}
+
+		if status.get_status().had_error {
+			anyhow::bail!("No changes were saved.");
+		}
+	}
 
-	let fails = inst.status.get_status().blocks_failed;
+	let fails = status.get_status().blocks_failed;
 	if fails > 0 {
-		inst.status.log_info(format!(
+		log.log_info(format!(
 			"Skipped {} invalid/unsupported mapblocks.",
 			fmt_big_num(fails as u64)
 		));
 	}
 
-	if inst.db.is_in_transaction() {
-		inst.status.log_info("Committing...");
-		inst.db.commit_if_needed()?;
+	// Each database is a separate file, so the commits can't be made
+	// atomic. The map is committed last: if committing any other database
+	// fails, the map is left unchanged, but databases which were already
+	// committed keep their changes.
+	if inst.ctx.db.is_in_transaction() && !read_only {
+		log.log_info("Committing...");
 	}
-	inst.status.log_info("Done.");
+	if let Some(pdb) = &mut inst.pdb {
+		pdb.commit_if_needed()?;
+	}
+	if let Some(adb) = &mut inst.adb {
+		adb.commit_if_needed()?;
+	}
+	if let Some(msdb) = &mut inst.msdb {
+		msdb.commit_if_needed()?;
+	}
+	inst.ctx.commit()?;
+	log.log_info("Done.");
 	Ok(())
 }
 
@@ -402,3 +682,37 @@ pub fn spawn_compute_thread(args: InstArgs)
 		.unwrap();
 	(h, status_client)
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::Ordering;
+	use crate::map_block::MapBlock;
+	use crate::utils::{read_test_file, create_test_map, TestProgress};
+
+	#[test]
+	fn test_edit_context() {
+		let data = read_test_file("mapblock_v29.bin");
+
+		let conn = create_test_map();
+		let progress = TestProgress::default();
+		let mut ctx = EditContext::new(MapDatabase::new(&conn).unwrap(),
+			&progress);
+		for key in 0..4 {
+			ctx.db.set_block(key, &data).unwrap();
+		}
+
+		commands::replace_nodes(&mut ctx, "test_mod:timer", "test_mod:new",
+			None, false).unwrap();
+		ctx.commit().unwrap();
+
+		assert_eq!(progress.done.load(Ordering::Relaxed), 4);
+		assert_eq!(progress.logs.lock().unwrap().last().unwrap(),
+			"info: 4 nodes replaced.");
+		let block = MapBlock::deserialize(&ctx.db.get_block(3).unwrap())
+			.unwrap();
+		assert!(block.nimap.get_id(b"test_mod:new").is_some());
+		assert!(block.nimap.get_id(b"test_mod:timer").is_none());
+	}
+}
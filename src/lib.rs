@@ -0,0 +1,34 @@
+//! Edits Minetest worlds/map databases.
+//!
+//! Each command is available as a function in [`commands`], which edits the
+//! map held by an [`EditContext`] (or one of the other world databases) and
+//! returns a [`CommandResult`]. Commands check their own arguments, and an
+//! error means that the changes should not be committed. Progress and log
+//! messages are reported through the context's [`Progress`] implementation.
+
+// Uncomment if needed for testing
+// mod testing;
+pub mod spatial;
+#[doc(hidden)]
+pub mod utils;
+pub mod map_database;
+pub mod player_database;
+pub mod auth_database;
+pub mod mod_storage_database;
+pub mod map_block;
+mod block_utils;
+pub mod block_index;
+mod worker_pool;
+#[doc(hidden)]
+pub mod instance;
+pub mod commands;
+
+pub use spatial::{Vec3, Area};
+pub use map_database::{MapDatabase, DBError};
+pub use player_database::PlayerDatabase;
+pub use auth_database::AuthDatabase;
+pub use mod_storage_database::ModStorageDatabase;
+pub use map_block::{MapBlock, CompressionLevel};
+pub use block_index::BlockIndex;
+pub use instance::{EditContext, Progress, InstState, LogType};
+pub use commands::{CommandError, CommandResult, Summary};
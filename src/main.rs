@@ -1,12 +1,3 @@
-// Uncomment if needed for testing
-// mod testing;
-mod spatial;
-mod utils;
-mod map_database;
-mod map_block;
-mod block_utils;
-mod instance;
-mod commands;
 mod cmd_line;
 
 
@@ -3,6 +3,9 @@ use zstd;
 
 /*
 Supported mapblock versions:
+22: Used by 0.4 development versions; legacy node metadata format.
+23: New node metadata format.
+24: 16-bit node IDs and node timers.
 25: In use from 0.4.2-rc1 until 0.4.15.
 26: Only ever sent over the network, not saved.
 27: Existed for around 3 months during 0.4.16 development.
@@ -10,9 +13,16 @@ Supported mapblock versions:
 29: In use since 5.5.0 (mapblocks are now compressed with zstd instead of zlib).
 */
 
-const MIN_BLOCK_VER: u8 = 25;
+const MIN_BLOCK_VER: u8 = 22;
 const MAX_BLOCK_VER: u8 = 29;
+/// Mapblocks older than this version are upgraded when serialized.
+const MIN_WRITE_VER: u8 = 25;
+/// Version that older mapblocks are upgraded to.
+const UPGRADE_VER: u8 = 28;
 const SERIALIZE_BUF_SIZE: usize = 2048;
+/// Enough for the uncompressed body of a typical version 29 mapblock, which
+/// includes 16 KiB of node data.
+const ZSTD_BODY_SIZE: usize = 20480;
 
 
 pub fn is_valid_generated(src: &[u8]) -> bool {
@@ -40,6 +50,45 @@ pub fn is_valid_generated(src: &[u8]) -> bool {
 }
 
 
+/// Returns `true` if the mapblock is readable but too old to be written
+/// as-is, i.e. it will be upgraded to a newer version when serialized.
+pub fn is_legacy(src: &[u8]) -> bool {
+	match src.first() {
+		Some(version) => (MIN_BLOCK_VER..MIN_WRITE_VER).contains(version),
+		None => false
+	}
+}
+
+
+/// Read the node names in a version 29 mapblock's name-ID map, without
+/// decompressing the rest of the mapblock.
+pub fn read_node_names(src: &[u8]) -> Result<Vec<Vec<u8>>, MapBlockError> {
+	if src.first() != Some(&29) {
+		return Err(MapBlockError::InvalidBlockVersion);
+	}
+	let mut dec = zstd::stream::Decoder::new(&src[1..])?;
+
+	// Skip flags, lighting_complete, and timestamp.
+	let mut header = [0; 7];
+	dec.read_exact(&mut header)?;
+
+	if dec.read_u8()? != 0 {
+		return Err(MapBlockError::InvalidSubVersion);
+	}
+	let count = dec.read_u16::<BigEndian>()?;
+	let mut names = Vec::with_capacity(count as usize);
+
+	for _ in 0..count {
+		dec.read_u16::<BigEndian>()?; // ID
+		let len = dec.read_u16::<BigEndian>()?;
+		let mut name = vec![0; len as usize];
+		dec.read_exact(&mut name)?;
+		names.push(name);
+	}
+	Ok(names)
+}
+
+
 #[derive(Clone, Debug)]
 pub struct MapBlock {
 	pub version: u8,
@@ -87,25 +136,36 @@ impl MapBlock {
 
 		let content_width = crs.read_u8()?;
 		let params_width = crs.read_u8()?;
-		// TODO: support content_width == 1?
-		if content_width != 2 || params_width != 2 {
+		if (content_width != 1 && content_width != 2) || params_width != 2 {
 			return Err(MapBlockError::InvalidFeature);
 		}
 
 		let node_data =
 			if version >= 29 {
-				NodeData::deserialize(&mut crs)?
+				NodeData::deserialize(&mut crs, content_width)?
 			} else {
-				NodeData::decompress(&mut crs)?
+				NodeData::decompress(&mut crs, content_width)?
 			};
 
+		let mut node_timers = NodeTimerList::new();
 		let metadata =
 			if version >= 29 {
 				NodeMetadataList::deserialize(&mut crs)?
-			} else {
+			} else if version >= 23 {
 				NodeMetadataList::decompress(&mut crs)?
+			} else {
+				let (meta, timers) = NodeMetadataList::decompress_legacy(
+					&mut crs)?;
+				node_timers = timers;
+				meta
 			};
 
+		if version == 23 {
+			crs.read_u8()?; // Unused
+		} else if version == 24 {
+			node_timers = deserialize_legacy_timers(&mut crs)?;
+		}
+
 		let static_objects = deserialize_objects(&mut crs)?;
 
 		if version < 29 {
@@ -113,13 +173,16 @@ impl MapBlock {
 			nimap = Some(NameIdMap::deserialize(&mut crs)?);
 		}
 
-		let node_timers = deserialize_timers(&mut crs)?;
+		if version >= 25 {
+			node_timers = deserialize_timers(&mut crs)?;
+		}
 
 		Ok(Self {
 			version,
 			flags,
 			lighting_complete,
-			content_width,
+			// Node data is always stored and written with 16-bit content IDs.
+			content_width: 2,
 			params_width,
 			node_data,
 			metadata,
@@ -130,42 +193,56 @@ impl MapBlock {
 		})
 	}
 
-	pub fn serialize(&self) -> Vec<u8> {
-		// TODO: Retain compression level used by Minetest?
+	/// Serialize the mapblock. Legacy mapblocks (older than version 25) are
+	/// upgraded to version 28.
+	pub fn serialize(&self, level: CompressionLevel) -> Vec<u8> {
 		assert!(MIN_BLOCK_VER <= self.version && self.version <= MAX_BLOCK_VER,
 			"Invalid mapblock version.");
+		assert!(self.content_width == 2 && self.params_width == 2,
+			"Invalid content or params width.");
+
+		let version = if self.version < MIN_WRITE_VER {
+			UPGRADE_VER
+		} else {
+			self.version
+		};
 
 		// TODO: Use a bigger buffer (unsafe?) to reduce heap allocations.
 		let mut buf = Vec::with_capacity(SERIALIZE_BUF_SIZE);
 		let mut crs = Cursor::new(buf);
-		crs.write_u8(self.version).unwrap();
-
-		if self.version >= 29 {
-			let mut enc = zstd::stream::Encoder::new(crs, 0).unwrap();
-
-			enc.write_u8(self.flags).unwrap();
-			enc.write_u16::<BigEndian>(self.lighting_complete).unwrap();
-			enc.write_u32::<BigEndian>(self.timestamp).unwrap();
-			self.nimap.serialize(&mut enc);
-			enc.write_u8(self.content_width).unwrap();
-			enc.write_u8(self.params_width).unwrap();
-			self.node_data.serialize(&mut enc);
-			self.metadata.serialize(&mut enc, self.version);
-			serialize_objects(&self.static_objects, &mut enc);
-			serialize_timers(&self.node_timers, &mut enc);
-
-			crs = enc.finish().unwrap();
+		crs.write_u8(version).unwrap();
+
+		if version >= 29 {
+			// Compress the whole body at once so zstd knows the source size
+			// and can size its tables for it, which is much faster at high
+			// compression levels than streaming.
+			let mut body = Cursor::new(Vec::with_capacity(ZSTD_BODY_SIZE));
+
+			body.write_u8(self.flags).unwrap();
+			body.write_u16::<BigEndian>(self.lighting_complete).unwrap();
+			body.write_u32::<BigEndian>(self.timestamp).unwrap();
+			self.nimap.serialize(&mut body);
+			body.write_u8(self.content_width).unwrap();
+			body.write_u8(self.params_width).unwrap();
+			self.node_data.serialize(&mut body);
+			self.metadata.serialize(&mut body, version);
+			serialize_objects(&self.static_objects, &mut body);
+			serialize_timers(&self.node_timers, &mut body);
+
+			let compressed = zstd::bulk::compress(body.get_ref(), level.zstd())
+				.unwrap();
+			crs.write_all(&compressed).unwrap();
 		} else { // version <= 28
 			crs.write_u8(self.flags).unwrap();
 
-			if self.version >= 27 {
+			if version >= 27 {
 				crs.write_u16::<BigEndian>(self.lighting_complete).unwrap();
 			}
 
 			crs.write_u8(self.content_width).unwrap();
 			crs.write_u8(self.params_width).unwrap();
-			self.node_data.compress(&mut crs);
-			self.metadata.compress(&mut crs, self.version);
+			self.node_data.compress(&mut crs, level);
+			self.metadata.compress(&mut crs, version, level);
 			serialize_objects(&self.static_objects, &mut crs);
 			crs.write_u32::<BigEndian>(self.timestamp).unwrap();
 			self.nimap.serialize(&mut crs);
@@ -183,7 +260,7 @@ impl MapBlock {
 mod tests {
 	use super::*;
 	use crate::spatial::Vec3;
-	use std::path::Path;
+	use crate::utils::read_test_file;
 
 	#[test]
 	fn test_is_valid_generated() {
@@ -191,10 +268,10 @@ mod tests {
 
 		// Too short
 		assert_eq!(ivg(b""), false);
-		assert_eq!(ivg(b"\x18"), false); // v24
+		assert_eq!(ivg(b"\x15"), false); // v21
 		assert_eq!(ivg(b"\x1D"), false); // v29
 		// Invalid version
-		assert_eq!(ivg(b"\x18\x00\x00\x00"), false); // v24
+		assert_eq!(ivg(b"\x15\x00\x00\x00"), false); // v21
 		assert_eq!(ivg(b"\x1E\x00\x00\x00"), false); // v30
 		// v28, "not generated" flag set
 		assert_eq!(ivg(b"\x1C\x08"), false);
@@ -202,26 +279,21 @@ mod tests {
 		assert_eq!(ivg(b"\x1D\x00\xFF"), false);
 		// v29, "not generated" flag set
 		assert_eq!(ivg(b"\x1D\x28\xB5\x2F\xFD\x00\x58\x19\x00\x00\x08\xFF\xFF"), false);
-		// v28, good
+		// v22 and v28, good
+		assert_eq!(ivg(b"\x16\x00"), true);
 		assert_eq!(ivg(b"\x1C\x00"), true);
 		// v29, good
 		assert_eq!(ivg(b"\x1D\x28\xB5\x2F\xFD\x00\x58\x19\x00\x00\x00\xFF\xFF"), true);
 	}
 
-	fn read_test_file(filename: &str) -> anyhow::Result<Vec<u8>> {
-		let cargo_path = std::env::var("CARGO_MANIFEST_DIR")?;
-		let path = Path::new(&cargo_path).join("testing").join(filename);
-		Ok(std::fs::read(path)?)
-	}
-
 	#[test]
 	fn test_mapblock_v29() {
 		// Original block positioned at (0, 0, 0).
-		let data1 = read_test_file("mapblock_v29.bin").unwrap();
+		let data1 = read_test_file("mapblock_v29.bin");
 		let block1 = MapBlock::deserialize(&data1).unwrap();
 		// Re-serialize and re-deserialize to test serialization, since
 		// serialization results can vary.
-		let data2 = block1.serialize();
+		let data2 = block1.serialize(CompressionLevel::Default);
 		let block2 = MapBlock::deserialize(&data2).unwrap();
 
 		for block in &[block1, block2] {
@@ -278,9 +350,9 @@ mod tests {
 	#[test]
 	fn test_mapblock_v28() {
 		// Original block positioned at (0, 0, 0).
-		let data1 = read_test_file("mapblock_v28.bin").unwrap();
+		let data1 = read_test_file("mapblock_v28.bin");
 		let block1 = MapBlock::deserialize(&data1).unwrap();
-		let data2 = block1.serialize();
+		let data2 = block1.serialize(CompressionLevel::Default);
 		let block2 = MapBlock::deserialize(&data2).unwrap();
 
 		for block in &[block1, block2] {
@@ -331,9 +403,9 @@ mod tests {
 	#[test]
 	fn test_mapblock_v25() {
 		// Original block positioned at (-1, -1, -1).
-		let data1 = read_test_file("mapblock_v25.bin").unwrap();
+		let data1 = read_test_file("mapblock_v25.bin");
 		let block1 = MapBlock::deserialize(&data1).unwrap();
-		let data2 = block1.serialize();
+		let data2 = block1.serialize(CompressionLevel::Default);
 		let block2 = MapBlock::deserialize(&data2).unwrap();
 
 		for block in &[block1, block2] {
@@ -382,9 +454,177 @@ mod tests {
 		}
 	}
 
+	fn zlib_compress(data: &[u8]) -> Vec<u8> {
+		use flate2::{Compression, write::ZlibEncoder};
+		let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+		enc.write_all(data).unwrap();
+		enc.finish().unwrap()
+	}
+
+	#[test]
+	fn test_mapblock_content_width_1() {
+		// Build a v25 mapblock with 8-bit content IDs by hand.
+		let mut node_data = vec![0; 4096 * 4];
+		node_data[0x000] = 0x01; // test_mod:stone
+		node_data[0x001] = 0x80; // Extended ID 0x805
+		node_data[0x2000] = 0x07; // param2
+		node_data[0x2001] = 0x5C;
+		node_data[0x1000 + 0xFFF] = 0x0F; // param1
+		node_data.truncate(4096 * 3);
+
+		let mut data = b"\x19\x03\x01\x02".to_vec();
+		data.extend(zlib_compress(&node_data));
+		data.extend(zlib_compress(b"\x00")); // Metadata
+		data.extend(b"\x00\x00\x00"); // Static objects
+		data.extend(b"\x00\x00\x01\x2C"); // Timestamp
+		data.extend(b"\x00\x00\x03\
+			\x00\x00\x00\x03air\
+			\x00\x01\x00\x0Etest_mod:stone\
+			\x08\x05\x00\x0Ftest_mod:legacy"); // Name-ID map
+		data.extend(b"\x0A\x00\x00"); // Node timers
+
+		let block1 = MapBlock::deserialize(&data).unwrap();
+		let data2 = block1.serialize(CompressionLevel::Default);
+		// Mapblocks are always written with 16-bit content IDs.
+		assert_eq!(data2[3], 2);
+		let block2 = MapBlock::deserialize(&data2).unwrap();
+
+		for block in &[block1, block2] {
+			assert_eq!(block.version, 25);
+			assert_eq!(block.content_width, 2);
+			assert_eq!(block.timestamp, 300);
+
+			let nd = &block.node_data;
+			assert_eq!(nd.nodes[0x000], 0x001);
+			assert_eq!(nd.param2[0x000], 0x07);
+			assert_eq!(nd.nodes[0x001], 0x805);
+			assert_eq!(nd.param2[0x001], 0x0C);
+			assert!(nd.nodes[0x002..].iter().all(|&n| n == 0));
+			assert_eq!(nd.param1[0xFFF], 0x0F);
+
+			assert_eq!(block.nimap.0[&nd.nodes[0x001]], b"test_mod:legacy");
+		}
+	}
+
+	#[test]
+	fn test_legacy_mapblocks() {
+		const NIMAP: &[u8] = b"\x00\x00\x02\
+			\x00\x00\x00\x03air\
+			\x00\x01\x00\x0Etest_mod:stone";
+		let mut node_data = vec![0; 4096 * 3];
+		node_data[0x000] = 0x01;
+
+		/* Version 22: legacy node metadata */
+		let chest_inv: &[u8] = b"List main 1\nWidth 0\n\
+			Item test_mod:stone 5\nEndInventoryList\nEndInventory\n";
+		// Legacy chests store their items in the list "0".
+		let legacy_chest_inv: &[u8] = b"List 0 1\nWidth 0\n\
+			Item test_mod:stone 5\nEndInventoryList\nEndInventory\n";
+		// Legacy item strings, which are kept as they are.
+		let legacy_items_inv: &[u8] = b"List 0 2\nWidth 0\n\
+			Item CraftItem default:coal_lump 5\nItem MaterialItem2 1 99\n\
+			EndInventoryList\nEndInventory\n";
+		let mut legacy_meta = b"\x00\x01\x00\x06".to_vec();
+		// Chest
+		legacy_meta.extend(b"\x00\x10\x00\x0F\x00\x48");
+		legacy_meta.extend(chest_inv);
+		// Chest with legacy list name
+		legacy_meta.extend(b"\x00\x11\x00\x0F\x00\x45");
+		legacy_meta.extend(legacy_chest_inv);
+		// Locking chest with legacy list name
+		legacy_meta.extend(b"\x00\x12\x00\x11\x00\x4A\x00\x03bob");
+		legacy_meta.extend(legacy_chest_inv);
+		// Chest with legacy items
+		legacy_meta.extend(b"\x00\x13\x00\x0F");
+		legacy_meta.extend((legacy_items_inv.len() as u16).to_be_bytes());
+		legacy_meta.extend(legacy_items_inv);
+		// Furnace
+		legacy_meta.extend(b"\x00\x20\x00\x10\x00\x17EndInventory\n\
+			15 3 40 20");
+		// Sign
+		legacy_meta.extend(b"\x00\x30\x00\x0E\x00\x07\x00\x05Hello");
+
+		let mut data22 = b"\x16\x03\x01\x02".to_vec();
+		data22.extend(zlib_compress(&node_data));
+		data22.extend(zlib_compress(&legacy_meta));
+		data22.extend(b"\x00\x00\x00\x00\x00\x00\x64");
+		data22.extend(NIMAP);
+
+		/* Version 23: modern node metadata, unused byte */
+		let meta = b"\x01\x00\x01\x00\x30\x00\x00\x00\x01\
+			\x00\x04text\x00\x00\x00\x05HelloEndInventory\n";
+		let mut data23 = b"\x17\x03\x01\x02".to_vec();
+		data23.extend(zlib_compress(&node_data));
+		data23.extend(zlib_compress(meta));
+		data23.extend(b"\x00\x00\x00\x00\x00\x00\x00\x64");
+		data23.extend(NIMAP);
+
+		/* Version 24: 16-bit node IDs, node timers */
+		let mut node_data24 = vec![0; 4096 * 4];
+		node_data24[0x001] = 0x01; // Low byte of first node
+		let mut data24 = b"\x18\x03\x02\x02".to_vec();
+		data24.extend(zlib_compress(&node_data24));
+		data24.extend(zlib_compress(b"\x00"));
+		data24.extend(b"\x01\x00\x01\x01\x11\x00\x00\x03\xE8\x00\x00\x01\xF4");
+		data24.extend(b"\x00\x00\x00\x00\x00\x00\x64");
+		data24.extend(NIMAP);
+
+		for data in &[&data22, &data23, &data24] {
+			assert!(is_legacy(data));
+			let block1 = MapBlock::deserialize(data).unwrap();
+			let data2 = block1.serialize(CompressionLevel::Default);
+			// Legacy blocks are upgraded when written.
+			assert!(!is_legacy(&data2));
+			let block2 = MapBlock::deserialize(&data2).unwrap();
+			assert_eq!(block1.version, data[0]);
+			assert_eq!(block2.version, 28);
+
+			for block in &[block1, block2] {
+				assert_eq!(block.flags, 0x03);
+				assert_eq!(block.lighting_complete, 0xFFFF);
+				assert_eq!(block.content_width, 2);
+				assert_eq!(block.timestamp, 100);
+				let stone_id = block.nimap.get_id(b"test_mod:stone").unwrap();
+				assert_eq!(block.node_data.nodes[0x000], stone_id);
+
+				let text = |pos: u16| &block.metadata[&pos].vars[&b"text"[..]];
+				match data[0] {
+					22 => {
+						assert_eq!(block.metadata.len(), 6);
+						assert_eq!(block.metadata[&0x010].inv, chest_inv);
+						assert_eq!(block.metadata[&0x011].inv, chest_inv);
+						let locking = &block.metadata[&0x012];
+						assert_eq!(locking.inv, chest_inv);
+						assert_eq!(locking.vars[&b"owner"[..]].0, b"bob");
+						assert_eq!(block.metadata[&0x013].inv,
+							[b"List main", &legacy_items_inv[6..]].concat());
+						let furnace = &block.metadata[&0x020].vars;
+						assert_eq!(furnace[&b"fuel_totaltime"[..]].0, b"1.5");
+						assert_eq!(furnace[&b"fuel_time"[..]].0, b"0.3");
+						assert_eq!(furnace[&b"src_time"[..]].0, b"2");
+						assert_eq!(text(0x030).0, b"Hello");
+						assert_eq!(block.node_timers.len(), 1);
+						assert_eq!(block.node_timers[0].pos, 0x020);
+					},
+					23 => {
+						assert_eq!(block.metadata.len(), 1);
+						assert_eq!(text(0x030).0, b"Hello");
+						assert!(block.node_timers.is_empty());
+					},
+					_ => {
+						assert!(block.metadata.is_empty());
+						assert_eq!(block.node_timers[0].pos, 0x111);
+						assert_eq!(block.node_timers[0].timeout, 1000);
+						assert_eq!(block.node_timers[0].elapsed, 500);
+					}
+				}
+			}
+		}
+	}
+
 	#[test]
 	fn test_failures() {
-		let data = read_test_file("mapblock_v28.bin").unwrap();
+		let data = read_test_file("mapblock_v28.bin");
 
 		// Change specific parts of the serialized data and make sure
 		// MapBlock::deserialize() catches the errors. Something like a hex
@@ -400,10 +640,13 @@ mod tests {
 		};
 
 		// Invalid versions
-		check_error(|d| d[0x0] = 24, MapBlockError::InvalidBlockVersion);
+		check_error(|d| d[0x0] = 21, MapBlockError::InvalidBlockVersion);
 		check_error(|d| d[0x0] = 30, MapBlockError::InvalidBlockVersion);
 		// Invalid content width
-		check_error(|d| d[0x4] = 1, MapBlockError::InvalidFeature);
+		check_error(|d| d[0x4] = 0, MapBlockError::InvalidFeature);
+		check_error(|d| d[0x4] = 3, MapBlockError::InvalidFeature);
+		// Content width doesn't match node data size
+		check_error(|d| d[0x4] = 1, MapBlockError::BadData);
 		// Invalid parameter width
 		check_error(|d| d[0x5] = 3, MapBlockError::InvalidFeature);
 		// Invalid static object version
@@ -416,12 +659,12 @@ mod tests {
 		{ // Invalid node data size
 			let mut block = MapBlock::deserialize(&data).unwrap();
 			block.node_data.param1.push(0);
-			let new_data = block.serialize();
+			let new_data = block.serialize(CompressionLevel::Default);
 			assert_eq!(MapBlock::deserialize(&new_data).unwrap_err(),
 				MapBlockError::BadData);
 
 			block.node_data.param1.truncate(4095);
-			let new_data = block.serialize();
+			let new_data = block.serialize(CompressionLevel::Default);
 			assert_eq!(MapBlock::deserialize(&new_data).unwrap_err(),
 				MapBlockError::BadData);
 		}
@@ -6,7 +6,6 @@ use std::cmp::min;
 use memmem::{Searcher, TwoWaySearcher};
 use flate2::write::ZlibEncoder;
 use flate2::read::ZlibDecoder;
-use flate2::Compression;
 
 
 const END_STR: &[u8; 13] = b"EndInventory\n";
@@ -35,19 +34,96 @@ impl NodeMetadata {
 			vars.insert(name.clone(), (val, private));
 		}
 
-		let end_finder = TwoWaySearcher::new(END_STR);
-		// This should be safe; EndInventory\n cannot appear in item metadata
-		// since newlines are escaped.
-		let end = end_finder
-			.search_in(&src.get_ref()[src.position() as usize ..])
-			.ok_or(MapBlockError::BadData)?;
-
-		let mut inv = vec_with_len(end + END_STR.len());
-		src.read_exact(&mut inv)?;
+		let inv = read_inventory(src)?;
 
 		Ok(Self { vars, inv })
 	}
 
+	/// Deserialize the body of a legacy (pre-v23) node metadata entry,
+	/// converting it to the modern format the same way Minetest does.
+	///
+	/// Returns the metadata and whether the node needs a node timer.
+	///
+	/// Only the metadata itself is converted. Minetest's conversions of
+	/// legacy facedir, wallmounted and mineral param values are not done.
+	///
+	/// Relevant Minetest source file: src/content_nodemeta.cpp
+	fn deserialize_legacy(src: &mut Cursor<&[u8]>, id: u16)
+		-> Result<(Self, bool), MapBlockError>
+	{
+		const CHEST_FORMSPEC: &[u8] = b"size[8,9]\
+			list[current_name;main;0,0;8,4;]\
+			list[current_player;main;0,5;8,4;]";
+		const FURNACE_FORMSPEC: &[u8] = b"size[8,9]\
+			list[current_name;fuel;2,3;1,1;]\
+			list[current_name;src;2,1;1,1;]\
+			list[current_name;dst;5,1;2,2;]\
+			list[current_player;main;0,5;8,4;]";
+
+		let mut vars = HashMap::new();
+		let mut set_var = |name: &[u8], val: Vec<u8>| {
+			vars.insert(name.to_vec(), (val, false));
+		};
+		let mut needs_timer = false;
+
+		let inv = match id {
+			1 => { // Generic
+				let inv = read_inventory(src)?;
+				read_string32(src)?; // Text (unused)
+				read_string16(src)?; // Owner (unused)
+				set_var(b"infotext", read_string16(src)?);
+				set_var(b"formspec", read_string16(src)?);
+				// Allow text input, allow removal, enforce owner (unused)
+				try_read_n(src, 3)?;
+				let var_count = src.read_u32::<BigEndian>()?;
+				for _ in 0..var_count {
+					let name = read_string16(src)?;
+					set_var(&name, read_string32(src)?);
+				}
+				inv
+			},
+			14 => { // Sign
+				set_var(b"text", read_string16(src)?);
+				set_var(b"formspec", b"field[text;;${text}]".to_vec());
+				set_var(b"infotext", b"\"${text}\"".to_vec());
+				END_STR.to_vec()
+			},
+			15 => { // Chest
+				set_var(b"formspec", CHEST_FORMSPEC.to_vec());
+				rename_legacy_chest_list(read_inventory(src)?)
+			},
+			16 => { // Furnace
+				let inv = read_inventory(src)?;
+				// Fuel total time, fuel time, source total time, source time,
+				// all stored as whitespace-separated tenths of seconds.
+				let rest = &src.get_ref()[src.position() as usize ..];
+				let mut times = rest.split(|c| c.is_ascii_whitespace())
+					.filter(|t| !t.is_empty())
+					.map(|t| std::str::from_utf8(t).ok()
+						.and_then(|t| t.parse::<i32>().ok()).unwrap_or(0));
+				let mut next_time = || {
+					let t = times.next().unwrap_or(0) as f32 / 10.;
+					t.to_string().into_bytes()
+				};
+				set_var(b"fuel_totaltime", next_time());
+				set_var(b"fuel_time", next_time());
+				next_time(); // Source total time (unused)
+				set_var(b"src_time", next_time());
+				set_var(b"formspec", FURNACE_FORMSPEC.to_vec());
+				needs_timer = true;
+				inv
+			},
+			17 => { // Locking chest
+				set_var(b"owner", read_string16(src)?);
+				set_var(b"formspec", CHEST_FORMSPEC.to_vec());
+				rename_legacy_chest_list(read_inventory(src)?)
+			},
+			_ => return Err(MapBlockError::InvalidFeature)
+		};
+
+		Ok((Self { vars, inv }, needs_timer))
+	}
+
 	fn serialize<T: Write>(&self, dst: &mut T, version: u8) {
 		dst.write_u32::<BigEndian>(self.vars.len() as u32).unwrap();
 
@@ -69,13 +145,377 @@ impl NodeMetadata {
 }
 
 
+/// A single stack of items, as stored in an inventory list.
+///
+/// An empty stack has an empty name. `meta` holds the item's serialized
+/// metadata, exactly as it appears in the inventory.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ItemStack {
+	pub name: Vec<u8>,
+	pub count: u16,
+	pub wear: u16,
+	pub meta: Vec<u8>
+}
+
+impl ItemStack {
+	pub fn new(name: &[u8], count: u16) -> Self {
+		Self { name: name.to_vec(), count, wear: 0, meta: Vec::new() }
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.name.is_empty()
+	}
+
+	/// Deserialize an item string, e.g. `default:pick_wood 1 2000`.
+	pub fn deserialize(src: &[u8]) -> Result<Self, MapBlockError> {
+		fn parse_num(num: &[u8]) -> Result<u16, MapBlockError> {
+			std::str::from_utf8(num).ok()
+				.and_then(|n| n.parse().ok())
+				.ok_or(MapBlockError::BadData)
+		}
+
+		// Max 4 parts: <name> <count> <wear> <metadata>
+		let mut parts = src.splitn(4, |&c| c == b' ');
+		let name = parts.next().unwrap().to_vec();
+		if name.is_empty() {
+			return Err(MapBlockError::BadData);
+		}
+		let count = parts.next().map_or(Ok(1), parse_num)?;
+		let wear = parts.next().map_or(Ok(0), parse_num)?;
+		let meta = parts.next().unwrap_or_default().to_vec();
+
+		Ok(Self { name, count, wear, meta })
+	}
+
+	/// Serialize the item string, omitting default values like Minetest
+	/// does.
+	pub fn serialize(&self) -> Vec<u8> {
+		let mut res = self.name.clone();
+		if self.count != 1 || self.wear != 0 || !self.meta.is_empty() {
+			res.extend_from_slice(format!(" {}", self.count).as_bytes());
+		}
+		if self.wear != 0 || !self.meta.is_empty() {
+			res.extend_from_slice(format!(" {}", self.wear).as_bytes());
+		}
+		if !self.meta.is_empty() {
+			res.push(b' ');
+			res.extend_from_slice(&self.meta);
+		}
+		res
+	}
+}
+
+
+/// Item metadata, as stored in the last field of an item string.
+///
+/// Relevant Minetest source file: src/itemstackmetadata.cpp
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ItemMeta {
+	pub vars: BTreeMap<Vec<u8>, Vec<u8>>
+}
+
+impl ItemMeta {
+	const START: u8 = 0x01;
+	const KV_DELIM: u8 = 0x02;
+	const PAIR_DELIM: u8 = 0x03;
+
+	/// Deserialize item metadata, which is usually JSON-quoted, e.g.
+	/// `"\u0001key\u0002value\u0003"`, but may also be unquoted.
+	pub fn deserialize(src: &[u8]) -> Result<Self, MapBlockError> {
+		let data = if src.first() == Some(&b'"') {
+			unquote_json(src)?
+		} else {
+			src.to_vec()
+		};
+		let mut vars = BTreeMap::new();
+
+		if data.first() == Some(&Self::START) {
+			let mut rest = &data[1..];
+			while !rest.is_empty() {
+				let (pair, next) = split_once(rest, Self::PAIR_DELIM);
+				let (key, value) = split_once(pair, Self::KV_DELIM);
+				vars.insert(key.to_vec(), value.to_vec());
+				rest = next;
+			}
+		} else if !data.is_empty() {
+			// Legacy metadata: a single unnamed string.
+			vars.insert(Vec::new(), data);
+		}
+
+		Ok(Self { vars })
+	}
+
+	/// Serialize the metadata in JSON-quoted form, as newer versions of
+	/// Minetest do. Returns an empty string if there are no variables.
+	pub fn serialize(&self) -> Vec<u8> {
+		if self.vars.is_empty() {
+			return Vec::new();
+		}
+		let mut data = vec![Self::START];
+		for (key, value) in &self.vars {
+			data.extend_from_slice(key);
+			data.push(Self::KV_DELIM);
+			data.extend_from_slice(value);
+			data.push(Self::PAIR_DELIM);
+		}
+		quote_json(&data)
+	}
+}
+
+
+/// Split `src` at the first occurrence of `delim`, excluding the delimiter.
+fn split_once(src: &[u8], delim: u8) -> (&[u8], &[u8]) {
+	match src.iter().position(|&c| c == delim) {
+		Some(i) => (&src[..i], &src[i + 1 ..]),
+		None => (src, &[])
+	}
+}
+
+
+/// Quote and escape a byte string like Minetest's `serializeJsonString`.
+fn quote_json(src: &[u8]) -> Vec<u8> {
+	let mut res = vec![b'"'];
+	for &c in src {
+		match c {
+			b'"' => res.extend_from_slice(b"\\\""),
+			b'\\' => res.extend_from_slice(b"\\\\"),
+			0x08 => res.extend_from_slice(b"\\b"),
+			0x0C => res.extend_from_slice(b"\\f"),
+			b'\n' => res.extend_from_slice(b"\\n"),
+			b'\r' => res.extend_from_slice(b"\\r"),
+			b'\t' => res.extend_from_slice(b"\\t"),
+			0x20 ..= 0x7E => res.push(c),
+			_ => res.extend_from_slice(format!("\\u{:04x}", c).as_bytes())
+		}
+	}
+	res.push(b'"');
+	res
+}
+
+
+/// Unquote and unescape a string serialized with `quote_json`. Anything
+/// after the closing quote is ignored.
+fn unquote_json(src: &[u8]) -> Result<Vec<u8>, MapBlockError> {
+	let mut res = Vec::new();
+	let mut chars = src.iter().skip(1);
+
+	while let Some(&c) = chars.next() {
+		match c {
+			b'"' => return Ok(res),
+			b'\\' => {
+				let esc = *chars.next().ok_or(MapBlockError::BadData)?;
+				res.push(match esc {
+					b'b' => 0x08,
+					b'f' => 0x0C,
+					b'n' => b'\n',
+					b'r' => b'\r',
+					b't' => b'\t',
+					b'u' => {
+						let hex: Vec<u8> = chars.by_ref().take(4).copied()
+							.collect();
+						std::str::from_utf8(&hex).ok()
+							.filter(|h| h.len() == 4)
+							.and_then(|h| u8::from_str_radix(h, 16).ok())
+							.ok_or(MapBlockError::BadData)?
+					},
+					_ => esc
+				});
+			},
+			_ => res.push(c)
+		}
+	}
+
+	// Missing closing quote.
+	Err(MapBlockError::BadData)
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InventoryList {
+	pub name: Vec<u8>,
+	pub size: u32,
+	pub width: u32,
+	pub items: Vec<ItemStack>
+}
+
+impl InventoryList {
+	/// Create an empty list with `size` slots.
+	pub fn new(name: &[u8], size: u32) -> Self {
+		Self {
+			name: name.to_vec(),
+			size,
+			width: 0,
+			items: vec![ItemStack::default(); size as usize]
+		}
+	}
+}
+
+
+/// A node inventory, made up of any number of named lists.
+///
+/// Relevant Minetest source file: src/inventory.cpp
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Inventory {
+	pub lists: Vec<InventoryList>
+}
+
+impl Inventory {
+	/// Deserialize an inventory, including the final `EndInventory` line.
+	/// Like Minetest, `end` is accepted in place of `EndInventoryList` and
+	/// `EndInventory`, and lines with unknown keywords are ignored.
+	pub fn deserialize(src: &[u8]) -> Result<Self, MapBlockError> {
+		fn parse_num(num: Option<&[u8]>) -> Result<u32, MapBlockError> {
+			num.and_then(|n| std::str::from_utf8(n).ok())
+				.and_then(|n| n.parse().ok())
+				.ok_or(MapBlockError::BadData)
+		}
+
+		let mut lists = Vec::new();
+		let mut cur_list: Option<InventoryList> = None;
+
+		for line in src.split(|&c| c == b'\n') {
+			let mut parts = line.splitn(2, |&c| c == b' ');
+			let keyword = parts.next().unwrap();
+			let rest = parts.next();
+
+			if let Some(list) = &mut cur_list {
+				match keyword {
+					b"Width" => list.width = parse_num(rest)?,
+					b"Empty" => list.items.push(ItemStack::default()),
+					b"Item" => list.items.push(ItemStack::deserialize(
+						rest.ok_or(MapBlockError::BadData)?)?),
+					b"EndInventoryList" | b"end" =>
+						lists.push(cur_list.take().unwrap()),
+					_ => {}
+				}
+			} else {
+				match keyword {
+					b"List" => {
+						let mut args = rest.unwrap_or_default()
+							.splitn(2, |&c| c == b' ');
+						let name = args.next().unwrap().to_vec();
+						let size = parse_num(args.next())?;
+						cur_list = Some(InventoryList {
+							name, size, width: 0, items: Vec::new()
+						});
+					},
+					b"EndInventory" | b"end" => return Ok(Self { lists }),
+					_ => {}
+				}
+			}
+		}
+
+		// Missing EndInventory line.
+		Err(MapBlockError::BadData)
+	}
+
+	/// Serialize the inventory in the format written by Minetest, which
+	/// always includes the `Width` line of each list.
+	pub fn serialize(&self) -> Vec<u8> {
+		let mut res = Vec::new();
+		for list in &self.lists {
+			res.extend_from_slice(b"List ");
+			res.extend_from_slice(&list.name);
+			res.extend_from_slice(
+				format!(" {}\nWidth {}\n", list.size, list.width).as_bytes());
+			for item in &list.items {
+				if item.is_empty() {
+					res.extend_from_slice(b"Empty\n");
+				} else {
+					res.extend_from_slice(b"Item ");
+					res.extend_from_slice(&item.serialize());
+					res.push(b'\n');
+				}
+			}
+			res.extend_from_slice(b"EndInventoryList\n");
+		}
+		res.extend_from_slice(END_STR);
+		res
+	}
+
+	pub fn get_list(&self, name: &[u8]) -> Option<&InventoryList> {
+		self.lists.iter().find(|l| l.name == name)
+	}
+
+	pub fn get_list_mut(&mut self, name: &[u8]) -> Option<&mut InventoryList> {
+		self.lists.iter_mut().find(|l| l.name == name)
+	}
+
+	/// Iterate over all non-empty item stacks in all lists.
+	pub fn items_mut(&mut self) -> impl Iterator<Item = &mut ItemStack> {
+		self.lists.iter_mut()
+			.flat_map(|l| l.items.iter_mut())
+			.filter(|i| !i.is_empty())
+	}
+}
+
+
+/// Read a serialized inventory, including the final `EndInventory` line.
+fn read_inventory(src: &mut Cursor<&[u8]>) -> Result<Vec<u8>, MapBlockError> {
+	let end_finder = TwoWaySearcher::new(END_STR);
+	// This should be safe; EndInventory\n cannot appear in item metadata
+	// since newlines are escaped.
+	let end = end_finder
+		.search_in(&src.get_ref()[src.position() as usize ..])
+		.ok_or(MapBlockError::BadData)?;
+
+	try_read_n(src, end + END_STR.len())
+}
+
+
+/// Rename the inventory list "0" of a legacy chest to "main", unless the
+/// inventory already has a "main" list.
+///
+/// Only the list header is changed, as legacy item strings such as
+/// `CraftItem default:coal_lump 5` can't be parsed as item stacks.
+fn rename_legacy_chest_list(inv: Vec<u8>) -> Vec<u8> {
+	const OLD: &[u8] = b"List 0 ";
+	const NEW: &[u8] = b"List main ";
+	let mut start = 0;
+	let mut found = None;
+
+	for line in inv.split(|&c| c == b'\n') {
+		if line.starts_with(NEW) {
+			return inv;
+		} else if found.is_none() && line.starts_with(OLD) {
+			found = Some(start);
+		}
+		start += line.len() + 1;
+	}
+
+	match found {
+		Some(i) => [&inv[..i], NEW, &inv[i + OLD.len() ..]].concat(),
+		None => inv
+	}
+}
+
+
+/// Read a zlib-compressed stream, leaving `src` at the end of the stream.
+fn read_zlib(src: &mut Cursor<&[u8]>) -> Result<Vec<u8>, MapBlockError> {
+	let start = src.position();
+	let mut decoder = ZlibDecoder::new(src);
+	let mut buf = Vec::new();
+	decoder.read_to_end(&mut buf)?;
+
+	let total_in = decoder.total_in();
+	let src = decoder.into_inner();
+	src.set_position(start + total_in);
+
+	Ok(buf)
+}
+
+
 pub trait NodeMetadataListExt {
 	fn deserialize(src: &mut Cursor<&[u8]>) -> Result<Self, MapBlockError>
 		where Self: std::marker::Sized;
 	fn decompress(src: &mut Cursor<&[u8]>) -> Result<Self, MapBlockError>
 		where Self: std::marker::Sized;
+	fn decompress_legacy(src: &mut Cursor<&[u8]>)
+		-> Result<(Self, NodeTimerList), MapBlockError>
+		where Self: std::marker::Sized;
 	fn serialize<T: Write>(&self, dst: &mut T, block_version: u8);
-	fn compress<T: Write>(&self, dst: &mut T, block_version: u8);
+	fn compress<T: Write>(&self, dst: &mut T, block_version: u8,
+		level: CompressionLevel);
 }
 
 
@@ -104,24 +544,44 @@ impl NodeMetadataListExt for NodeMetadataList {
 	}
 
 	fn decompress(src: &mut Cursor<&[u8]>) -> Result<Self, MapBlockError> {
-		let start = src.position();
-		let mut decoder = ZlibDecoder::new(src);
-		let mut buf = Vec::new();
-		decoder.read_to_end(&mut buf)?;
+		let buf = read_zlib(src)?;
+		Self::deserialize(&mut Cursor::new(buf.as_slice()))
+	}
 
-		let mut cursor = Cursor::new(buf.as_slice());
-		let metadata = Self::deserialize(&mut cursor)?;
+	/// Decompress and deserialize legacy node metadata, as used in version 22
+	/// mapblocks. Also returns any node timers needed by the metadata.
+	fn decompress_legacy(src: &mut Cursor<&[u8]>)
+		-> Result<(Self, NodeTimerList), MapBlockError>
+	{
+		let buf = read_zlib(src)?;
+		let mut src = Cursor::new(buf.as_slice());
 
-		// Fail if there is leftover compressed data.
-		if decoder.read(&mut [0])? > 0 {
-			return Err(MapBlockError::BadData);
+		let version = src.read_u16::<BigEndian>()?;
+		if version > 1 {
+			return Err(MapBlockError::InvalidSubVersion);
 		}
 
-		let total_in = decoder.total_in();
-		let src = decoder.into_inner();
-		src.set_position(start + total_in);
+		let count = src.read_u16::<BigEndian>()?;
+		let mut list = BTreeMap::new();
+		let mut timers = NodeTimerList::new();
+
+		for _ in 0..count {
+			let pos = src.read_u16::<BigEndian>()?;
+			let id = src.read_u16::<BigEndian>()?;
+			let body = read_string16(&mut src)?;
 
-		Ok(metadata)
+			if list.contains_key(&pos) {
+				continue; // Minetest ignores duplicate metadata.
+			}
+			let (meta, needs_timer) = NodeMetadata::deserialize_legacy(
+				&mut Cursor::new(body.as_slice()), id)?;
+			list.insert(pos, meta);
+			if needs_timer {
+				timers.push(NodeTimer {pos, timeout: 1000, elapsed: 0});
+			}
+		}
+
+		Ok((list, timers))
 	}
 
 	fn serialize<T: Write>(&self, dst: &mut T, block_version: u8) {
@@ -143,8 +603,10 @@ impl NodeMetadataListExt for NodeMetadataList {
 		}
 	}
 
-	fn compress<T: Write>(&self, dst: &mut T, block_version: u8) {
-		let mut encoder = ZlibEncoder::new(dst, Compression::default());
+	fn compress<T: Write>(&self, dst: &mut T, block_version: u8,
+		level: CompressionLevel)
+	{
+		let mut encoder = ZlibEncoder::new(dst, level.zlib());
 		self.serialize(&mut encoder, block_version);
 		encoder.finish().unwrap();
 	}
@@ -238,4 +700,131 @@ mod tests {
 		assert_eq!(meta_deserialize_slice(missing_inv).unwrap_err(),
 			MapBlockError::BadData);
 	}
+
+	#[test]
+	fn test_inventory() {
+		let inv_data = b"List main 4\n\
+			Width 2\n\
+			Item default:pick_wood 1 2000\n\
+			Empty\n\
+			Item default:stone 99\n\
+			Item basenodes:cobble 1 0 \"\\u0001check\\u0002\
+				EndInventory\\n\\u0003\"\n\
+			EndInventoryList\n\
+			List fuel 1\n\
+			Width 0\n\
+			Item default:coal_lump\n\
+			EndInventoryList\n\
+			EndInventory\n";
+
+		let mut inv = Inventory::deserialize(inv_data).unwrap();
+		assert_eq!(inv.lists.len(), 2);
+		let main = inv.get_list(b"main").unwrap();
+		assert_eq!((main.size, main.width, main.items.len()), (4, 2, 4));
+		assert_eq!(main.items[0].wear, 2000);
+		assert!(main.items[1].is_empty());
+		assert_eq!(main.items[2].count, 99);
+		assert_eq!(main.items[3].meta,
+			b"\"\\u0001check\\u0002EndInventory\\n\\u0003\"");
+		assert_eq!(inv.get_list(b"fuel").unwrap().items[0],
+			ItemStack::new(b"default:coal_lump", 1));
+		assert_eq!(inv.serialize(), inv_data);
+
+		for item in inv.items_mut() {
+			item.count = 5;
+			item.wear = 0;
+			item.meta.clear();
+		}
+		inv.lists.remove(1);
+		assert_eq!(inv.serialize(), b"List main 4\n\
+			Width 2\n\
+			Item default:pick_wood 5\n\
+			Empty\n\
+			Item default:stone 5\n\
+			Item basenodes:cobble 5\n\
+			EndInventoryList\n\
+			EndInventory\n");
+
+		// Unknown keywords are ignored, and "end" can end lists and
+		// inventories. Serializing writes the canonical form.
+		let loose = Inventory::deserialize(b"List main 2\n\
+			Item default:stone 5\n\
+			Unknown 1 2 3\n\
+			Empty\n\
+			end\n\
+			KeepList craft\n\
+			end\n").unwrap();
+		let canonical = b"List main 2\n\
+			Width 0\n\
+			Item default:stone 5\n\
+			Empty\n\
+			EndInventoryList\n\
+			EndInventory\n";
+		assert_eq!(loose.serialize(), canonical);
+		assert_eq!(Inventory::deserialize(canonical).unwrap(), loose);
+
+		assert_eq!(Inventory::deserialize(END_STR).unwrap(),
+			Inventory::default());
+		assert_eq!(Inventory::default().serialize(), END_STR);
+
+		let bad_invs: &[&[u8]] = &[
+			b"",
+			b"List main 1\nEmpty\nEndInventoryList\n",
+			b"List main\nEndInventoryList\nEndInventory\n",
+			b"List main 1\nItem foo bar\nEndInventoryList\nEndInventory\n",
+			b"List main 1\nItem foo 1 70000\nEndInventoryList\n\
+				EndInventory\n",
+			b"List main 1\nEmpty\nEndInventory\n",
+		];
+		for &bad in bad_invs {
+			assert_eq!(Inventory::deserialize(bad),
+				Err(MapBlockError::BadData));
+		}
+	}
+
+	#[test]
+	fn test_item_meta() {
+		let quoted = b"\"\\u0001description\\u0002Caf\\u00e9 \\\"sign\\\"\
+			\\nline 2\\u0003owner\\u0002singleplayer\\u0003\"";
+		let meta = ItemMeta::deserialize(quoted).unwrap();
+		assert_eq!(meta.vars.len(), 2);
+		assert_eq!(meta.vars[&b"description"[..]],
+			b"Caf\xe9 \"sign\"\nline 2");
+		assert_eq!(meta.vars[&b"owner"[..]], b"singleplayer");
+		assert_eq!(meta.serialize(), quoted);
+
+		// Unquoted metadata, as used in the old replaceininv tests.
+		let raw = b"\x01color\x02#FF00FF\x03\x01some var\x02a\x03";
+		let meta = ItemMeta::deserialize(raw).unwrap();
+		assert_eq!(meta.vars[&b"color"[..]], b"#FF00FF");
+		assert_eq!(meta.vars[&b"\x01some var"[..]], b"a");
+
+		// Legacy metadata without any keys.
+		let legacy = ItemMeta::deserialize(b"hello").unwrap();
+		assert_eq!(legacy.vars[&b""[..]], b"hello");
+		assert_eq!(legacy.serialize(), b"\"\\u0001\\u0002hello\\u0003\"");
+
+		assert_eq!(ItemMeta::deserialize(b"").unwrap(), ItemMeta::default());
+		assert!(ItemMeta::default().serialize().is_empty());
+
+		for &bad in &[&b"\"abc"[..], b"\"\\u00\"", b"\"\\u0100\"", b"\"\\"] {
+			assert_eq!(ItemMeta::deserialize(bad),
+				Err(MapBlockError::BadData));
+		}
+	}
+
+	#[test]
+	fn test_rename_legacy_chest_list() {
+		let rename = |inv: &[u8]| rename_legacy_chest_list(inv.to_vec());
+		assert_eq!(rename(b"List 0 1\nWidth 0\nItem CraftItem a 5\n\
+				EndInventoryList\nEndInventory\n"),
+			b"List main 1\nWidth 0\nItem CraftItem a 5\n\
+				EndInventoryList\nEndInventory\n");
+		// An existing "main" list is kept.
+		let both = b"List 0 0\nEndInventoryList\n\
+			List main 0\nEndInventoryList\nEndInventory\n";
+		assert_eq!(rename(both), both);
+		assert_eq!(rename(b"List 00 0\nEndInventoryList\nEndInventory\n"),
+			b"List 00 0\nEndInventoryList\nEndInventory\n");
+	}
 }
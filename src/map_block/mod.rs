@@ -11,16 +11,47 @@ mod static_object;
 mod node_timer;
 mod name_id_map;
 
-pub use map_block::{MapBlock, is_valid_generated};
+pub use map_block::{MapBlock, is_valid_generated, is_legacy,
+	read_node_names};
 pub use node_data::NodeData;
-pub use metadata::{NodeMetadataList, NodeMetadataListExt};
+pub use metadata::{NodeMetadata, NodeMetadataList, NodeMetadataListExt,
+	Inventory, InventoryList, ItemStack, ItemMeta};
 pub use static_object::{StaticObject, StaticObjectList, LuaEntityData};
 use static_object::{serialize_objects, deserialize_objects};
 pub use node_timer::{NodeTimer, NodeTimerList};
-use node_timer::{serialize_timers, deserialize_timers};
+use node_timer::{serialize_timers, deserialize_timers,
+	deserialize_legacy_timers};
 pub use name_id_map::NameIdMap;
 
 
+/// Compression level to use when serializing mapblocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CompressionLevel {
+	Fast,
+	#[default]
+	Default,
+	Best,
+}
+
+impl CompressionLevel {
+	fn zlib(self) -> flate2::Compression {
+		match self {
+			Self::Fast => flate2::Compression::fast(),
+			Self::Default => flate2::Compression::default(),
+			Self::Best => flate2::Compression::best(),
+		}
+	}
+
+	fn zstd(self) -> i32 {
+		match self {
+			Self::Fast => 1,
+			Self::Default => 0, // zstd's default level
+			// Levels 20-22 ("ultra") are far slower for little gain.
+			Self::Best => 19,
+		}
+	}
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum MapBlockError {
 	/// Block data is malformed or missing.
@@ -2,7 +2,6 @@ use super::*;
 
 use flate2::write::ZlibEncoder;
 use flate2::read::ZlibDecoder;
-use flate2::Compression;
 
 
 const BLOCK_SIZE: usize = 16;
@@ -17,11 +16,15 @@ pub struct NodeData {
 }
 
 impl NodeData {
-	pub fn deserialize<T: Read>(src: &mut T) -> Result<Self, MapBlockError> {
-		let mut node_bytes = vec_with_len(NODE_COUNT * 2);
+	/// Read node data with the given content width (1 or 2 bytes per node).
+	///
+	/// 8-bit content IDs are converted to 16-bit IDs in the same way as
+	/// Minetest: IDs above 0x7F are extended using the high nibble of param2.
+	pub fn deserialize<T: Read>(src: &mut T, content_width: u8)
+		-> Result<Self, MapBlockError>
+	{
+		let mut node_bytes = vec_with_len(NODE_COUNT * content_width as usize);
 		src.read_exact(&mut node_bytes)?;
-		let mut nodes = vec_with_len(NODE_COUNT);
-		BigEndian::read_u16_into(&node_bytes, &mut nodes);
 
 		let mut param1 = vec_with_len(NODE_COUNT);
 		src.read_exact(&mut param1)?;
@@ -29,6 +32,24 @@ impl NodeData {
 		let mut param2 = vec_with_len(NODE_COUNT);
 		src.read_exact(&mut param2)?;
 
+		let nodes = match content_width {
+			1 => node_bytes.iter().zip(param2.iter_mut()).map(|(&id, p2)| {
+				if id > 0x7F {
+					let ext_id = (id as u16) << 4 | (*p2 >> 4) as u16;
+					*p2 &= 0x0F;
+					ext_id
+				} else {
+					id as u16
+				}
+			}).collect(),
+			2 => {
+				let mut nodes = vec_with_len(NODE_COUNT);
+				BigEndian::read_u16_into(&node_bytes, &mut nodes);
+				nodes
+			},
+			_ => return Err(MapBlockError::InvalidFeature)
+		};
+
 		Ok(Self {
 			nodes,
 			param1,
@@ -36,11 +57,13 @@ impl NodeData {
 		})
 	}
 
-	pub fn decompress(src: &mut Cursor<&[u8]>) -> Result<Self, MapBlockError> {
+	pub fn decompress(src: &mut Cursor<&[u8]>, content_width: u8)
+		-> Result<Self, MapBlockError>
+	{
 		let start = src.position();
 		let mut decoder = ZlibDecoder::new(src);
 
-		let node_data = Self::deserialize(&mut decoder)?;
+		let node_data = Self::deserialize(&mut decoder, content_width)?;
 
 		// Fail if there is leftover compressed data.
 		if decoder.read(&mut [0])? > 0 {
@@ -54,6 +77,7 @@ impl NodeData {
 		Ok(node_data)
 	}
 
+	/// Write node data with a content width of 2.
 	pub fn serialize<T: Write>(&self, dst: &mut T) {
 		// This allocation seems slow, but writing u16s iteratively is slower.
 		let mut node_bytes = vec_with_len(NODE_COUNT * 2);
@@ -65,8 +89,8 @@ impl NodeData {
 		dst.write_all(&self.param2).unwrap();
 	}
 
-	pub fn compress<T: Write>(&self, dst: &mut T) {
-		let mut encoder = ZlibEncoder::new(dst, Compression::default());
+	pub fn compress<T: Write>(&self, dst: &mut T, level: CompressionLevel) {
+		let mut encoder = ZlibEncoder::new(dst, level.zlib());
 		self.serialize(&mut encoder);
 		encoder.finish().unwrap();
 	}
@@ -13,14 +13,9 @@ pub struct NodeTimer {
 pub type NodeTimerList = Vec<NodeTimer>;
 
 
-pub fn deserialize_timers<T: Read>(src: &mut T)
+fn deserialize_timer_list<T: Read>(src: &mut T)
 	-> Result<NodeTimerList, MapBlockError>
 {
-	let data_len = src.read_u8()?;
-	if data_len != 10 {
-		return Err(MapBlockError::InvalidFeature);
-	}
-
 	let count = src.read_u16::<BigEndian>()?;
 	// Limit allocation to number of nodes (bad data handling).
 	let mut timers = Vec::with_capacity(min(count, 4096) as usize);
@@ -36,6 +31,30 @@ pub fn deserialize_timers<T: Read>(src: &mut T)
 }
 
 
+pub fn deserialize_timers<T: Read>(src: &mut T)
+	-> Result<NodeTimerList, MapBlockError>
+{
+	let data_len = src.read_u8()?;
+	if data_len != 10 {
+		return Err(MapBlockError::InvalidFeature);
+	}
+
+	deserialize_timer_list(src)
+}
+
+
+/// Deserialize node timers from a version 24 mapblock.
+pub fn deserialize_legacy_timers<T: Read>(src: &mut T)
+	-> Result<NodeTimerList, MapBlockError>
+{
+	match src.read_u8()? {
+		0 => Ok(NodeTimerList::new()),
+		1 => deserialize_timer_list(src),
+		_ => Err(MapBlockError::InvalidSubVersion)
+	}
+}
+
+
 pub fn serialize_timers<T: Write>(timers: &NodeTimerList, dst: &mut T) {
 	dst.write_u8(10).unwrap();
 	dst.write_u16::<BigEndian>(timers.len() as u16).unwrap();
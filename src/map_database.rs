@@ -1,3 +1,6 @@
+use std::collections::VecDeque;
+
+
 #[derive(Debug, thiserror::Error)]
 pub enum DBError {
 	#[error("database operation failed")]
@@ -38,23 +41,145 @@ fn verify_database(conn: &sqlite::Connection) -> Result<(), DBError> {
 }
 
 
-pub struct MapDatabaseRows<'a> {
-	stmt_get: sqlite::Statement<'a>
+/// Check that a table exists and has at least the given columns.
+pub(crate) fn verify_columns(
+	conn: &sqlite::Connection,
+	table: &str,
+	columns: &[&str]
+) -> Result<(), DBError> {
+	let mut stmt = conn.prepare(format!("PRAGMA table_info({})", table))?;
+	let mut names = Vec::new();
+	while let sqlite::State::Row = stmt.next()? {
+		names.push(stmt.read::<String>(1)?);
+	}
+	match columns.iter().all(|c| names.iter().any(|n| n == c)) {
+		true => Ok(()),
+		false => Err(DBError::InvalidDatabase)
+	}
+}
+
+
+/// A connection to one of the world's other databases, e.g. players.sqlite,
+/// which keeps a transaction open until it is committed.
+pub(crate) struct WorldDatabase<'a> {
+	conn: &'a sqlite::Connection,
+	in_transaction: bool,
+}
+
+impl<'a> WorldDatabase<'a> {
+	/// Begin a transaction, then check the database with `verify`.
+	pub fn new(
+		conn: &'a sqlite::Connection,
+		verify: impl FnOnce(&sqlite::Connection) -> Result<(), DBError>
+	) -> Result<Self, DBError> {
+		conn.execute("BEGIN")?;
+		verify(conn)?;
+		Ok(Self {conn, in_transaction: true})
+	}
+
+	pub fn is_in_transaction(&self) -> bool {
+		self.in_transaction
+	}
+
+	/// Get the connection, beginning a new transaction if needed.
+	#[inline]
+	pub fn conn(&mut self) -> Result<&'a sqlite::Connection, DBError> {
+		if !self.in_transaction {
+			self.conn.execute("BEGIN")?;
+			self.in_transaction = true;
+		}
+		Ok(self.conn)
+	}
+
+	pub fn commit_if_needed(&mut self) -> Result<(), DBError> {
+		if self.in_transaction {
+			self.conn.execute("COMMIT")?;
+			self.in_transaction = false;
+		}
+		Ok(())
+	}
 }
 
-impl Iterator for MapDatabaseRows<'_> {
-	type Item = (i64, Vec<u8>);
+
+/// Iterates over mapblocks in a list of key ranges, in order of their keys.
+///
+/// Rows are read in small batches, and no statement is left running between
+/// batches, so the database can be safely modified during iteration.
+pub struct MapDatabaseIter<'a, T> {
+	stmt_get: sqlite::Statement<'a>,
+	read_row: fn(&sqlite::Statement) -> sqlite::Result<(i64, T)>,
+	buffer: VecDeque<T>,
+	ranges: std::vec::IntoIter<(i64, i64)>,
+	/// Last key read (exclusive lower bound) and upper bound of the current
+	/// range.
+	current: Option<(i64, i64)>,
+}
+
+/// Iterates over the keys and data of mapblocks.
+pub type MapDatabaseRows<'a> = MapDatabaseIter<'a, (i64, Vec<u8>)>;
+/// Iterates over the keys of mapblocks.
+pub type MapDatabaseKeys<'a> = MapDatabaseIter<'a, i64>;
+
+impl<'a, T> MapDatabaseIter<'a, T> {
+	const BATCH_SIZE: i64 = 1024;
+
+	fn new(
+		stmt_get: sqlite::Statement<'a>,
+		read_row: fn(&sqlite::Statement) -> sqlite::Result<(i64, T)>,
+		ranges: Vec<(i64, i64)>
+	) -> Self {
+		let mut iter = Self {
+			stmt_get,
+			read_row,
+			buffer: VecDeque::new(),
+			ranges: ranges.into_iter(),
+			current: None,
+		};
+		iter.next_range();
+		iter
+	}
+
+	fn next_range(&mut self) {
+		// The lower bound is exclusive; no block key is ever i64::MIN.
+		self.current = self.ranges.next()
+			.map(|(min, max)| (min.saturating_sub(1), max));
+	}
+
+	fn read_batch(&mut self) -> Result<(), DBError> {
+		while self.buffer.is_empty() {
+			let (mut last_key, max_key) = match self.current {
+				Some(r) => r,
+				None => break
+			};
+			self.stmt_get.bind(1, last_key)?;
+			self.stmt_get.bind(2, max_key)?;
+			self.stmt_get.bind(3, Self::BATCH_SIZE)?;
+
+			while let sqlite::State::Row = self.stmt_get.next()? {
+				let (key, item) = (self.read_row)(&self.stmt_get)?;
+				self.buffer.push_back(item);
+				last_key = key;
+			}
+			self.stmt_get.reset()?;
+
+			if (self.buffer.len() as i64) < Self::BATCH_SIZE {
+				self.next_range();
+			} else {
+				self.current = Some((last_key, max_key));
+			}
+		}
+		Ok(())
+	}
+}
+
+impl<T> Iterator for MapDatabaseIter<'_, T> {
+	type Item = T;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		match self.stmt_get.next().unwrap() {
-			sqlite::State::Row => {
-				Some((
-					self.stmt_get.read(0).unwrap(),
-					self.stmt_get.read(1).unwrap()
-				))
-			},
-			sqlite::State::Done => None
+		if self.buffer.is_empty() {
+			self.read_batch().unwrap();
 		}
+		self.buffer.pop_front()
 	}
 }
 
@@ -100,10 +225,45 @@ impl<'a> MapDatabase<'a> {
 		Ok(())
 	}
 
-	pub fn iter_rows(&self) -> MapDatabaseRows {
+	/// Iterate over all mapblocks with keys in the given ranges. Each range is
+	/// a pair of minimum and maximum keys, inclusive. Ranges must be sorted
+	/// and must not overlap.
+	pub fn iter_rows(&self, ranges: Vec<(i64, i64)>) -> MapDatabaseRows<'a> {
 		self.begin_if_needed().unwrap();
-		let stmt = self.conn.prepare("SELECT pos, data FROM blocks").unwrap();
-		MapDatabaseRows {stmt_get: stmt}
+		let stmt = self.conn.prepare("SELECT pos, data FROM blocks \
+			WHERE pos > ? AND pos <= ? ORDER BY pos LIMIT ?").unwrap();
+		MapDatabaseIter::new(stmt, |stmt| {
+			let key = stmt.read(0)?;
+			Ok((key, (key, stmt.read(1)?)))
+		}, ranges)
+	}
+
+	/// Like `iter_rows`, but only reads the keys of mapblocks.
+	pub fn iter_keys(&self, ranges: Vec<(i64, i64)>) -> MapDatabaseKeys<'a> {
+		self.begin_if_needed().unwrap();
+		let stmt = self.conn.prepare("SELECT pos FROM blocks \
+			WHERE pos > ? AND pos <= ? ORDER BY pos LIMIT ?").unwrap();
+		MapDatabaseIter::new(stmt, |stmt| {
+			let key = stmt.read(0)?;
+			Ok((key, key))
+		}, ranges)
+	}
+
+	/// Count the mapblocks with keys in the given ranges.
+	pub fn count_rows(&self, ranges: &[(i64, i64)]) -> Result<u64, DBError> {
+		self.begin_if_needed()?;
+		let mut stmt = self.conn.prepare(
+			"SELECT COUNT(*) FROM blocks WHERE pos BETWEEN ? AND ?")?;
+		let mut count = 0;
+
+		for &(min_key, max_key) in ranges {
+			stmt.bind(1, min_key)?;
+			stmt.bind(2, max_key)?;
+			stmt.next()?;
+			count += stmt.read::<i64>(0)? as u64;
+			stmt.reset()?;
+		}
+		Ok(count)
 	}
 
 	pub fn get_block(&mut self, map_key: i64) -> Result<Vec<u8>, DBError> {
@@ -144,3 +304,44 @@ impl<'a> MapDatabase<'a> {
 		Ok(())
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_modify_while_iterating() {
+		let conn = sqlite::open(":memory:").unwrap();
+		conn.execute("CREATE TABLE blocks (pos INT PRIMARY KEY, data BLOB)")
+			.unwrap();
+		let mut db = MapDatabase::new(&conn).unwrap();
+		for key in -1500..1500 {
+			db.set_block(key, b"old").unwrap();
+		}
+
+		// Rewritten rows must not be returned again.
+		let mut expected = -1000;
+		for (key, data) in db.iter_rows(vec![(-1000, 1000)]) {
+			assert_eq!((key, data.as_slice()), (expected, &b"old"[..]));
+			db.set_block(key, b"new").unwrap();
+			expected += 1;
+		}
+		assert_eq!(expected, 1001);
+
+		db.delete_block(0).unwrap();
+		assert_eq!(db.count_rows(&[(-1000, 1000)]).unwrap(), 2000);
+		assert_eq!(db.count_rows(&[]).unwrap(), 0);
+		assert_eq!(db.iter_rows(Vec::new()).count(), 0);
+
+		let ranges = vec![(-1600, -1499), (-10, 10), (1499, 1600)];
+		let keys: Vec<_> = db.iter_rows(ranges.clone()).map(|r| r.0).collect();
+		let expected: Vec<_> = [-1500, -1499].iter().copied()
+			.chain((-10..=10).filter(|&k| k != 0))
+			.chain(std::iter::once(1499))
+			.collect();
+		assert_eq!(keys, expected);
+		assert_eq!(db.iter_keys(ranges.clone()).collect::<Vec<_>>(), expected);
+		assert_eq!(db.count_rows(&ranges).unwrap(), expected.len() as u64);
+	}
+}
@@ -0,0 +1,151 @@
+use crate::map_database::{DBError, WorldDatabase, verify_columns};
+
+
+fn verify_database(conn: &sqlite::Connection) -> Result<(), DBError> {
+	verify_columns(conn, "entries", &["modname", "key", "value"])
+}
+
+
+/// One key-value pair stored by a mod.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StorageEntry {
+	pub mod_name: String,
+	pub key: Vec<u8>,
+	pub value: Vec<u8>,
+}
+
+
+/// A Minetest mod storage database (mod_storage.sqlite), used by the
+/// `sqlite3` mod storage backend since Minetest 5.7.
+pub struct ModStorageDatabase<'a> {
+	db: WorldDatabase<'a>,
+}
+
+impl<'a> ModStorageDatabase<'a> {
+	pub fn new(conn: &'a sqlite::Connection) -> Result<Self, DBError> {
+		Ok(Self {db: WorldDatabase::new(conn, verify_database)?})
+	}
+
+	pub fn is_in_transaction(&self) -> bool {
+		self.db.is_in_transaction()
+	}
+
+	pub fn commit_if_needed(&mut self) -> Result<(), DBError> {
+		self.db.commit_if_needed()
+	}
+
+	/// Get the names of all mods with stored entries, along with the number
+	/// of entries of each, sorted by name.
+	pub fn get_mods(&mut self) -> Result<Vec<(String, u64)>, DBError> {
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare("SELECT modname, COUNT(*) \
+			FROM entries GROUP BY modname ORDER BY modname")?;
+		let mut mods = Vec::new();
+		while let sqlite::State::Row = stmt.next()? {
+			mods.push((stmt.read(0)?, stmt.read::<i64>(1)? as u64));
+		}
+		Ok(mods)
+	}
+
+	/// Get all entries of the mod `mod_name`, or of all mods if `None`,
+	/// sorted by mod name and key.
+	pub fn get_entries(&mut self, mod_name: Option<&str>)
+		-> Result<Vec<StorageEntry>, DBError>
+	{
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare("SELECT modname, key, value \
+			FROM entries WHERE ?1 IS NULL OR modname = ?1 \
+			ORDER BY modname, key")?;
+		stmt.bind(1, mod_name)?;
+		let mut entries = Vec::new();
+		while let sqlite::State::Row = stmt.next()? {
+			entries.push(StorageEntry {
+				mod_name: stmt.read(0)?,
+				key: stmt.read(1)?,
+				value: stmt.read(2)?,
+			});
+		}
+		Ok(entries)
+	}
+
+	/// Move all entries of the mod `old` to the mod `new`. Returns the number
+	/// of entries moved.
+	pub fn rename_mod(&mut self, old: &str, new: &str)
+		-> Result<u64, DBError>
+	{
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare(
+			"UPDATE entries SET modname = ? WHERE modname = ?")?;
+		stmt.bind(1, new)?;
+		stmt.bind(2, old)?;
+		stmt.next()?;
+		Ok(conn.change_count() as u64)
+	}
+
+	/// Delete all entries of a mod, or only the entry `key` if given.
+	/// Returns the number of entries deleted.
+	pub fn delete_entries(&mut self, mod_name: &str, key: Option<&[u8]>)
+		-> Result<u64, DBError>
+	{
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare("DELETE FROM entries \
+			WHERE modname = ?1 AND (?2 IS NULL OR key = ?2)")?;
+		stmt.bind(1, mod_name)?;
+		stmt.bind(2, key)?;
+		stmt.next()?;
+		Ok(conn.change_count() as u64)
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_mod_storage_database() {
+		let conn = sqlite::open(":memory:").unwrap();
+		conn.execute("CREATE TABLE entries (modname TEXT NOT NULL, \
+				key BLOB NOT NULL, value BLOB NOT NULL, \
+				PRIMARY KEY (modname, key))")
+			.unwrap();
+		let mut db = ModStorageDatabase::new(&conn).unwrap();
+		{
+			let mut stmt = conn.prepare("INSERT INTO entries VALUES (?, ?, ?)")
+				.unwrap();
+			for (m, k, v) in &[("mod_b", "x", "1"), ("mod_a", "y", "2"),
+				("mod_a", "x", "3")]
+			{
+				stmt.bind(1, *m).unwrap();
+				stmt.bind(2, k.as_bytes()).unwrap();
+				stmt.bind(3, v.as_bytes()).unwrap();
+				stmt.next().unwrap();
+				stmt.reset().unwrap();
+			}
+		}
+
+		assert_eq!(db.get_mods().unwrap(),
+			[("mod_a".to_string(), 2), ("mod_b".to_string(), 1)]);
+		let entries = db.get_entries(Some("mod_a")).unwrap();
+		assert_eq!(entries, [
+			StorageEntry {
+				mod_name: "mod_a".to_string(),
+				key: b"x".to_vec(),
+				value: b"3".to_vec(),
+			},
+			StorageEntry {
+				mod_name: "mod_a".to_string(),
+				key: b"y".to_vec(),
+				value: b"2".to_vec(),
+			},
+		]);
+		assert_eq!(db.get_entries(None).unwrap().len(), 3);
+
+		assert_eq!(db.rename_mod("mod_a", "mod_c").unwrap(), 2);
+		assert_eq!(db.rename_mod("mod_a", "mod_c").unwrap(), 0);
+		assert_eq!(db.delete_entries("mod_c", Some(b"y")).unwrap(), 1);
+		assert_eq!(db.delete_entries("mod_b", None).unwrap(), 1);
+		db.commit_if_needed().unwrap();
+		assert_eq!(db.get_mods().unwrap(), [("mod_c".to_string(), 1)]);
+	}
+}
@@ -0,0 +1,240 @@
+use crate::map_database::{DBError, WorldDatabase, verify_columns};
+
+
+/// Minetest stores player positions in units of 1/10 node (BS).
+const BS: f64 = 10.0;
+
+
+fn verify_database(conn: &sqlite::Connection) -> Result<(), DBError> {
+	verify_columns(conn, "player", &["name", "posX", "posY", "posZ", "hp",
+		"breath", "modification_date"])?;
+	verify_columns(conn, "player_inventories", &["player"])?;
+	verify_columns(conn, "player_inventory_items",
+		&["player", "inv_id", "slot_id", "item"])?;
+	verify_columns(conn, "player_metadata", &["player"])?;
+	Ok(())
+}
+
+
+/// A player's saved state.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayerInfo {
+	pub name: String,
+	/// Position of the player's feet, in nodes.
+	pub pos: [f64; 3],
+	pub hp: i64,
+	pub breath: i64,
+	/// Time the player was last saved, e.g. `2021-03-14 15:09:26`.
+	pub modification_date: String,
+}
+
+
+/// One slot of a player inventory. Minetest saves every slot of each list,
+/// so empty slots have an empty item string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayerItem {
+	pub player: String,
+	pub inv_id: i64,
+	pub slot_id: i64,
+	/// Serialized item string, e.g. `default:pick_wood 1 2000`, or an empty
+	/// string if the slot is empty.
+	pub item: String,
+}
+
+
+/// A Minetest player database (players.sqlite), as used by the `sqlite3`
+/// player backend.
+pub struct PlayerDatabase<'a> {
+	db: WorldDatabase<'a>,
+}
+
+impl<'a> PlayerDatabase<'a> {
+	pub fn new(conn: &'a sqlite::Connection) -> Result<Self, DBError> {
+		Ok(Self {db: WorldDatabase::new(conn, verify_database)?})
+	}
+
+	pub fn is_in_transaction(&self) -> bool {
+		self.db.is_in_transaction()
+	}
+
+	pub fn commit_if_needed(&mut self) -> Result<(), DBError> {
+		self.db.commit_if_needed()
+	}
+
+	/// Get all players, sorted by name.
+	pub fn get_players(&mut self) -> Result<Vec<PlayerInfo>, DBError> {
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare("SELECT name, posX, posY, posZ, hp, \
+			breath, modification_date FROM player ORDER BY name")?;
+		let mut players = Vec::new();
+
+		while let sqlite::State::Row = stmt.next()? {
+			players.push(PlayerInfo {
+				name: stmt.read(0)?,
+				pos: [
+					stmt.read::<f64>(1)? / BS,
+					stmt.read::<f64>(2)? / BS,
+					stmt.read::<f64>(3)? / BS,
+				],
+				hp: stmt.read(4)?,
+				breath: stmt.read(5)?,
+				modification_date: stmt.read(6)?,
+			});
+		}
+		Ok(players)
+	}
+
+	/// Set the saved position of a player, in nodes. Returns `false` if the
+	/// player doesn't exist.
+	pub fn set_position(&mut self, name: &str, pos: [f64; 3])
+		-> Result<bool, DBError>
+	{
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare(
+			"UPDATE player SET posX = ?, posY = ?, posZ = ? WHERE name = ?")?;
+		for (i, &coord) in pos.iter().enumerate() {
+			stmt.bind(i + 1, coord * BS)?;
+		}
+		stmt.bind(4, name)?;
+		stmt.next()?;
+		Ok(conn.change_count() > 0)
+	}
+
+	/// Get the items in all player inventories, sorted by player, inventory
+	/// list and slot.
+	pub fn get_items(&mut self) -> Result<Vec<PlayerItem>, DBError> {
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare("SELECT player, inv_id, slot_id, \
+			item FROM player_inventory_items \
+			ORDER BY player, inv_id, slot_id")?;
+		let mut items = Vec::new();
+
+		while let sqlite::State::Row = stmt.next()? {
+			items.push(PlayerItem {
+				player: stmt.read(0)?,
+				inv_id: stmt.read(1)?,
+				slot_id: stmt.read(2)?,
+				item: stmt.read(3)?,
+			});
+		}
+		Ok(items)
+	}
+
+	/// Delete a player's saved state, inventories and metadata. Returns
+	/// `false` if the player doesn't exist.
+	pub fn delete_player(&mut self, name: &str) -> Result<bool, DBError> {
+		let conn = self.db.conn()?;
+		let mut exists = false;
+		for query in &["DELETE FROM player_metadata WHERE player = ?",
+			"DELETE FROM player_inventory_items WHERE player = ?",
+			"DELETE FROM player_inventories WHERE player = ?",
+			"DELETE FROM player WHERE name = ?"]
+		{
+			let mut stmt = conn.prepare(query)?;
+			stmt.bind(1, name)?;
+			stmt.next()?;
+			exists = conn.change_count() > 0;
+		}
+		Ok(exists)
+	}
+
+	/// Update the item string of an inventory slot. Set it to an empty string
+	/// to empty the slot.
+	pub fn set_item(&mut self, item: &PlayerItem) -> Result<(), DBError> {
+		let conn = self.db.conn()?;
+		let mut stmt = conn.prepare("UPDATE player_inventory_items \
+			SET item = ? WHERE player = ? AND inv_id = ? AND slot_id = ?")?;
+		stmt.bind(1, item.item.as_str())?;
+		stmt.bind(2, item.player.as_str())?;
+		stmt.bind(3, item.inv_id)?;
+		stmt.bind(4, item.slot_id)?;
+		stmt.next()?;
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_player_database() {
+		let conn = sqlite::open(":memory:").unwrap();
+		conn.execute("CREATE TABLE player (name VARCHAR(50) NOT NULL, \
+				pitch NUMERIC(11, 4) NOT NULL, yaw NUMERIC(11, 4) NOT NULL, \
+				posX NUMERIC(11, 4) NOT NULL, posY NUMERIC(11, 4) NOT NULL, \
+				posZ NUMERIC(11, 4) NOT NULL, hp INT NOT NULL, \
+				breath INT NOT NULL, creation_date DATETIME NOT NULL \
+				DEFAULT CURRENT_TIMESTAMP, modification_date DATETIME \
+				NOT NULL DEFAULT CURRENT_TIMESTAMP, PRIMARY KEY (name));
+			CREATE TABLE player_inventory_items (\
+				player VARCHAR(50) NOT NULL, inv_id INT NOT NULL, \
+				slot_id INT NOT NULL, item TEXT NOT NULL DEFAULT '', \
+				PRIMARY KEY(player, inv_id, slot_id));
+			CREATE TABLE player_inventories (player VARCHAR(50) NOT NULL, \
+				inv_id INT NOT NULL, inv_width INT NOT NULL, \
+				inv_name TEXT NOT NULL DEFAULT '', inv_size INT NOT NULL, \
+				PRIMARY KEY(player, inv_id));
+			CREATE TABLE player_metadata (player VARCHAR(50) NOT NULL, \
+				metadata VARCHAR(256) NOT NULL, value TEXT, \
+				PRIMARY KEY(player, metadata));
+			INSERT INTO player_inventories VALUES ('bob', 0, 0, 'main', 32), \
+				('alice', 1, 0, 'craft', 9);
+			INSERT INTO player_metadata VALUES ('bob', 'stamina', '20');
+			INSERT INTO player VALUES ('bob', 0, 0, 15, -5, 1000.5, 20, 10, \
+				'2021-01-01 00:00:00', '2021-02-03 04:05:06');
+			INSERT INTO player VALUES ('alice', 0, 0, 0, 0, 0, 0, 10, \
+				'2021-01-01 00:00:00', '2021-01-01 00:00:00');
+			INSERT INTO player_inventory_items VALUES \
+				('bob', 0, 2, ''), ('bob', 0, 3, 'default:dirt 5'), \
+				('alice', 1, 0, 'default:stone');")
+			.unwrap();
+		let mut db = PlayerDatabase::new(&conn).unwrap();
+
+		let players = db.get_players().unwrap();
+		assert_eq!(players.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+			["alice", "bob"]);
+		assert_eq!(players[1], PlayerInfo {
+			name: "bob".to_string(),
+			pos: [1.5, -0.5, 100.05],
+			hp: 20,
+			breath: 10,
+			modification_date: "2021-02-03 04:05:06".to_string(),
+		});
+
+		assert!(db.set_position("alice", [-10.0, 20.0, 30.5]).unwrap());
+		assert!(!db.set_position("carol", [0.0, 0.0, 0.0]).unwrap());
+		assert_eq!(db.get_players().unwrap()[0].pos, [-10.0, 20.0, 30.5]);
+
+		let mut items = db.get_items().unwrap();
+		assert_eq!(items.len(), 3);
+		assert_eq!(items[1].item, "");
+		assert_eq!(items[2].item, "default:dirt 5");
+		items[2].item = "default:dirt 10".to_string();
+		db.set_item(&items[2]).unwrap();
+		items[0].item = String::new();
+		db.set_item(&items[0]).unwrap();
+		db.commit_if_needed().unwrap();
+		assert!(!db.is_in_transaction());
+		assert_eq!(db.get_items().unwrap(), items);
+
+		assert!(db.delete_player("bob").unwrap());
+		assert!(!db.delete_player("bob").unwrap());
+		db.commit_if_needed().unwrap();
+		assert_eq!(db.get_players().unwrap().len(), 1);
+		assert_eq!(db.get_items().unwrap(), &items[..1]);
+		for table in ["player_inventories", "player_metadata"] {
+			let mut stmt = conn.prepare(format!(
+				"SELECT COUNT(*) FROM {} WHERE player = 'bob'", table))
+				.unwrap();
+			stmt.next().unwrap();
+			assert_eq!(stmt.read::<i64>(0).unwrap(), 0);
+		}
+		assert!(db.is_in_transaction());
+
+		let conn = sqlite::open(":memory:").unwrap();
+		conn.execute("CREATE TABLE player (name VARCHAR(50))").unwrap();
+		assert!(PlayerDatabase::new(&conn).is_err());
+	}
+}
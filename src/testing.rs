@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::time::{Instant, Duration};
 
-use crate::instance::StatusServer;
+use crate::instance::Progress;
 
 
 pub struct Timer<'a> {
@@ -40,7 +40,7 @@ impl TimeKeeper {
 		Timer {parent: self, name: name.to_string(), start: Instant::now()}
 	}
 
-	pub fn print(&mut self, status: &StatusServer) {
+	pub fn print(&mut self, status: &dyn Progress) {
 		let mut msg = String::new();
 		for (name, (duration, count)) in &self.times {
 			msg += &format!("{}: {} x {:?} each; {:?} total\n",
@@ -2,18 +2,149 @@ use std::time::Duration;
 use std::collections::{HashMap, VecDeque};
 
 use memmem::{Searcher, TwoWaySearcher};
-use byteorder::{WriteBytesExt, BigEndian};
+use byteorder::{ByteOrder, BigEndian};
 
-use crate::instance::{InstState, StatusServer};
-use crate::map_database::MapDatabase;
+use crate::instance::{InstState, Progress};
+use crate::map_database::{MapDatabase, MapDatabaseRows};
+use crate::block_index::BlockIndex;
 use crate::spatial::{Area, Vec3};
 
 
+/// Area of block positions to include when querying mapblocks, or `None` if
+/// all mapblocks are included. If `invert == true`, only blocks *outside*
+/// this area are included. Returns `Err(())` if no blocks can be included.
+fn get_block_area(area: Option<Area>, invert: bool, include_partial: bool)
+	-> Result<Option<Area>, ()>
+{
+	let a = match area {
+		Some(a) => a,
+		None => return Ok(None)
+	};
+	let block_area = if invert == include_partial {
+		a.to_contained_block_area()
+	} else {
+		Some(a.to_touching_block_area())
+	};
+
+	match block_area {
+		Some(ba) => Ok(Some(ba)),
+		// The area contains no blocks, so inverting it includes every block.
+		None if invert => Ok(None),
+		None => Err(())
+	}
+}
+
+
+/// Maximum number of key ranges to query before falling back to a single range
+/// covering the whole area.
+const MAX_KEY_RANGES: u64 = 65536;
+
+
+/// Number of rows of blocks along the X axis in a block area.
+fn num_rows(ba: Area) -> u64 {
+	(ba.max.y - ba.min.y + 1) as u64 * (ba.max.z - ba.min.z + 1) as u64
+}
+
+
+/// Sorted ranges of block keys which may contain the blocks in `block_area`.
+///
+/// Block keys are ordered by Z, then Y, then X, so each row of blocks along
+/// the X axis has a contiguous range of keys.
+fn get_key_ranges(block_area: Option<Area>, invert: bool) -> Vec<(i64, i64)> {
+	let ba = match block_area {
+		Some(ba) if !invert => ba,
+		_ => return vec![(i64::MIN, i64::MAX)]
+	};
+	if num_rows(ba) > MAX_KEY_RANGES {
+		return vec![(ba.min.to_block_key(), ba.max.to_block_key())];
+	}
+
+	let mut ranges: Vec<(i64, i64)> =
+		Vec::with_capacity(num_rows(ba) as usize);
+	for z in ba.min.z ..= ba.max.z {
+		for y in ba.min.y ..= ba.max.y {
+			let start = Vec3::new(ba.min.x, y, z).to_block_key();
+			let end = Vec3::new(ba.max.x, y, z).to_block_key();
+			match ranges.last_mut() {
+				// Merge adjacent rows, e.g. if the area spans the entire X axis.
+				Some(last) if last.1 + 1 == start => last.1 = end,
+				_ => ranges.push((start, end))
+			}
+		}
+	}
+	ranges
+}
+
+
+/// Build searchers for the given search strings.
+fn make_searchers(search_strs: &[Vec<u8>]) -> Vec<TwoWaySearcher<'_>> {
+	search_strs.iter().map(|s| TwoWaySearcher::new(s)).collect()
+}
+
+
+/// Check whether `data` contains `search_str`, prefixed by its 16-bit length
+/// to reduce false positives. This will break if the name-ID map format
+/// changes.
+fn contains_string16(data: &[u8], search_str: &[u8],
+	searcher: &TwoWaySearcher) -> bool
+{
+	let mut start = 0;
+	while let Some(i) = searcher.search_in(&data[start..]) {
+		let pos = start + i;
+		if pos >= 2 && BigEndian::read_u16(&data[pos - 2 .. pos]) as usize
+			== search_str.len()
+		{
+			return true;
+		}
+		start = pos + 1;
+	}
+	false
+}
+
+
+fn in_block_area(key: i64, block_area: Option<Area>, invert: bool) -> bool {
+	match block_area {
+		Some(a) => a.contains(Vec3::from_block_key(key)) != invert,
+		None => true
+	}
+}
+
+
+fn data_matches(data: &[u8], search_strs: &[Vec<u8>],
+	searchers: &[TwoWaySearcher]) -> bool
+{
+	if let Some(&block_version) = data.first() {
+		// If block version <= 28, data must match at least one search
+		// string. This optimization doesn't work for new mapblocks, as
+		// all block data is now compressed.
+		// TODO: Remove this legacy optimization?
+		if block_version <= 28 && !searchers.is_empty()
+			&& !search_strs.iter().zip(searchers)
+				.any(|(s, sr)| contains_string16(data, s, sr))
+		{
+			return false;
+		}
+	}
+	true
+}
+
+
+/// Check whether a mapblock contains any of the nodes in `names`, using a
+/// node name index. Returns `None` if the index can't be used for it, e.g.
+/// because the mapblock is too old or the index can't be read.
+fn index_matches(index: &mut BlockIndex, key: i64, data: &[u8],
+	names: &[Vec<u8>]) -> Option<bool>
+{
+	let block_names = index.get_node_names(key, data).ok()??;
+	Some(block_names.iter().any(|n| names.contains(n)))
+}
+
+
 /// Note: For mapblock version 29 onwards, all block data is compressed, so
 /// the `search_strs` argument is ignored.
 pub fn query_keys(
 	db: &mut MapDatabase,
-	status: &StatusServer,
+	status: &dyn Progress,
 	search_strs: &[Vec<u8>],
 	area: Option<Area>,
 	invert: bool,
@@ -21,57 +152,28 @@ pub fn query_keys(
 ) -> Vec<i64> {
 	status.set_state(InstState::Querying);
 
-	// Prepend 16-bit search string length to reduce false positives.
-	// This will break if the name-ID map format changes.
-	let string16s: Vec<Vec<u8>> = search_strs.iter().map(|s| {
-		let mut res = Vec::new();
-		res.write_u16::<BigEndian>(s.len() as u16).unwrap();
-		res.extend(s);
-		res
-	}).collect();
-	let data_searchers: Vec<TwoWaySearcher> = string16s.iter().map(|b| {
-		TwoWaySearcher::new(b)
-	}).collect();
+	let searchers = make_searchers(search_strs);
 	let mut keys = Vec::new();
 
-	// Area of included block positions.
-	// If invert == true, the function returns only blocks outside this area.
-	let block_area = area.map(|a| {
-		if invert == include_partial {
-			a.to_contained_block_area()
+	if let Ok(block_area) = get_block_area(area, invert, include_partial) {
+		let ranges = get_key_ranges(block_area, invert);
+
+		if searchers.is_empty() {
+			// Block data isn't needed, so only read keys.
+			keys.extend(db.iter_keys(ranges)
+				.filter(|&key| in_block_area(key, block_area, invert)));
 		} else {
-			Some(a.to_touching_block_area())
-		}
-	}).flatten();
-	// True if the given area contains no blocks.
-	let empty_area = area.is_some() && block_area.is_none();
-
-	if !empty_area || invert {
-		for (i, (key, data)) in db.iter_rows().enumerate() {
-			if !empty_area {
-				if let Some(a) = &block_area {
-					let block_pos = Vec3::from_block_key(key);
-					if a.contains(block_pos) == invert {
-						continue;
-					}
-				}
-			}
-			if let Some(&block_version) = data.get(0) {
-				// If block version <= 28, data must match at least one search
-				// string. This optimization doesn't work for new mapblocks, as
-				// all block data is now compressed.
-				// TODO: Remove this legacy optimization?
-				if block_version <= 28 && !data_searchers.is_empty()
-					&& !data_searchers.iter().any(|s| s.search_in(&data).is_some())
+			for (i, (key, data)) in db.iter_rows(ranges).enumerate() {
+				if in_block_area(key, block_area, invert)
+					&& data_matches(&data, search_strs, &searchers)
 				{
-					continue;
+					keys.push(key);
 				}
-			}
-			keys.push(key);
 
-			// Update total every 1024 iterations.
-			if i & 1023 == 0 {
-				status.set_total(keys.len())
+				// Update total every 1024 iterations.
+				if i & 1023 == 0 {
+					status.set_total(keys.len())
+				}
 			}
 		}
 	}
@@ -82,6 +184,101 @@ pub fn query_keys(
 }
 
 
+/// Streams the keys and data of mapblocks matching a query.
+///
+/// Mapblocks which are in the queried area but don't match the search strings
+/// are counted as done.
+pub struct BlockStream<'a, 'b> {
+	rows: MapDatabaseRows<'a>,
+	index: Option<&'b mut BlockIndex<'a>>,
+	status: &'b dyn Progress,
+	block_area: Option<Area>,
+	invert: bool,
+	search_strs: &'b [Vec<u8>],
+	searchers: Vec<TwoWaySearcher<'b>>,
+}
+
+impl Iterator for BlockStream<'_, '_> {
+	type Item = (i64, Vec<u8>);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		for (key, data) in self.rows.by_ref() {
+			if !in_block_area(key, self.block_area, self.invert) {
+				continue; // Not included in the status total.
+			}
+			let indexed = match &mut self.index {
+				Some(index) if !self.search_strs.is_empty() =>
+					index_matches(index, key, &data, self.search_strs),
+				_ => None
+			};
+			let matches = match indexed {
+				Some(m) => m,
+				None => data_matches(&data, self.search_strs, &self.searchers)
+			};
+			if matches {
+				return Some((key, data));
+			}
+			self.status.inc_done();
+		}
+		None
+	}
+}
+
+
+/// Like `query_keys`, but returns the data of each mapblock along with its
+/// key, reading each mapblock only once. The status total is set to the
+/// number of mapblocks in the queried area, which is exact unless search
+/// strings are given.
+///
+/// As with `query_keys`, version 29 mapblocks are not searched unless
+/// `index` is given, so commands must check them for the searched nodes
+/// after decompressing them. If `index` is given, `search_strs` must be node
+/// names.
+pub fn query_blocks<'a, 'b>(
+	db: &MapDatabase<'a>,
+	index: Option<&'b mut BlockIndex<'a>>,
+	status: &'b dyn Progress,
+	search_strs: &'b [Vec<u8>],
+	area: Option<Area>,
+	invert: bool,
+	include_partial: bool
+) -> BlockStream<'a, 'b> {
+	status.set_state(InstState::Querying);
+
+	let block_area = get_block_area(area, invert, include_partial);
+	let ranges = match block_area {
+		Ok(ba) => get_key_ranges(ba, invert),
+		Err(()) => Vec::new()
+	};
+	let block_area = block_area.unwrap_or(None);
+
+	// Counting keys is quick, as block data is not read. If the key ranges
+	// include blocks outside the area, each key must be checked.
+	let exact_ranges = match block_area {
+		Some(ba) => !invert && num_rows(ba) <= MAX_KEY_RANGES,
+		None => true
+	};
+	let total = if !exact_ranges {
+		db.iter_keys(ranges.clone())
+			.filter(|&key| in_block_area(key, block_area, invert)).count()
+	} else {
+		db.count_rows(&ranges).unwrap() as usize
+	};
+
+	status.set_total(total);
+	status.set_state(InstState::Ignore);
+	BlockStream {
+		rows: db.iter_rows(ranges),
+		index,
+		status,
+		block_area,
+		invert,
+		search_strs,
+		searchers: make_searchers(search_strs),
+	}
+}
+
+
 pub struct CacheMap<K, V> {
 	key_queue: VecDeque<K>,
 	map: HashMap<K, V>,
@@ -114,8 +311,8 @@ impl<K: Eq + std::hash::Hash + Clone, V> CacheMap<K, V> {
 }
 
 
-pub fn to_bytes(s: &String) -> Vec<u8> {
-	s.as_bytes().to_vec()
+pub fn to_bytes<S: AsRef<str>>(s: S) -> Vec<u8> {
+	s.as_ref().as_bytes().to_vec()
 }
 
 
@@ -181,9 +378,132 @@ pub fn fmt_big_num(num: u64) -> String {
 }
 
 
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+	// See http://howardhinnant.github.io/date_algorithms.html
+	let y = if month <= 2 { year - 1 } else { year };
+	let era = y.div_euclid(400);
+	let yoe = y - era * 400;
+	let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5
+		+ day - 1;
+	let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+	era * 146_097 + doe - 719_468
+}
+
+
+/// Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+	let z = days + 719_468;
+	let era = z.div_euclid(146_097);
+	let doe = z - era * 146_097;
+	let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	(yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+
+/// Convert a date in the form `YYYY-MM-DD` to a Unix timestamp, at midnight
+/// UTC.
+pub fn parse_date(date: &str) -> Option<i64> {
+	let parts = date.split('-')
+		.map(|p| p.parse().ok())
+		.collect::<Option<Vec<i64>>>()?;
+	let (year, month, day) = match parts.as_slice() {
+		&[y, m, d] if (1..=12).contains(&m) && (1..=31).contains(&d) =>
+			(y, m, d),
+		_ => return None
+	};
+	let days = days_from_civil(year, month, day);
+	// Reject days past the end of the month, e.g. 2021-02-30.
+	if civil_from_days(days) != (year, month, day) {
+		return None;
+	}
+	Some(days * 86_400)
+}
+
+
+/// Format a Unix timestamp as a UTC date and time, e.g.
+/// `2021-03-14 15:09:26`.
+pub fn fmt_timestamp(ts: i64) -> String {
+	let (year, month, day) = civil_from_days(ts.div_euclid(86_400));
+	let secs = ts.rem_euclid(86_400);
+	format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day,
+		secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+
+/// Read a file from the `testing` directory, for use in tests.
+#[cfg(test)]
+pub(crate) fn read_test_file(filename: &str) -> Vec<u8> {
+	let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+		.join("testing").join(filename);
+	std::fs::read(path).unwrap()
+}
+
+
+/// Create an empty in-memory map database, for use in tests.
+#[cfg(test)]
+pub(crate) fn create_test_map() -> sqlite::Connection {
+	let conn = sqlite::open(":memory:").unwrap();
+	conn.execute("CREATE TABLE blocks (pos INT PRIMARY KEY, data BLOB)")
+		.unwrap();
+	conn
+}
+
+
+/// Records progress and log messages, for use in tests.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct TestProgress {
+	pub total: std::sync::atomic::AtomicUsize,
+	pub done: std::sync::atomic::AtomicUsize,
+	/// Log messages, prefixed with their level, e.g. "warning: ...".
+	pub logs: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl Progress for TestProgress {
+	fn set_total(&self, total: usize) {
+		self.total.store(total, std::sync::atomic::Ordering::Relaxed);
+	}
+
+	fn inc_done(&self) {
+		self.done.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+	}
+
+	fn log(&self, lt: crate::instance::LogType, msg: &str) {
+		self.logs.lock().unwrap().push(format!("{}: {}", lt, msg));
+	}
+}
+
+
+/// Assert that two mapblocks have the same contents, regardless of their
+/// versions and compression, for use in tests.
+#[cfg(test)]
+pub(crate) fn assert_same_contents(a: &crate::map_block::MapBlock,
+	b: &crate::map_block::MapBlock)
+{
+	assert_eq!(a.timestamp, b.timestamp);
+	assert_eq!(a.nimap.0, b.nimap.0);
+	assert_eq!(a.node_data.nodes, b.node_data.nodes);
+	assert_eq!(a.node_data.param1, b.node_data.param1);
+	assert_eq!(a.node_data.param2, b.node_data.param2);
+	assert_eq!(a.metadata.len(), b.metadata.len());
+	for (pos, meta) in &a.metadata {
+		assert_eq!(b.metadata[pos].vars, meta.vars);
+		assert_eq!(b.metadata[pos].inv, meta.inv);
+	}
+}
+
+
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use std::sync::atomic::Ordering;
+	use crate::map_block::{MapBlock, CompressionLevel};
 
 	#[test]
 	fn test_nums() {
@@ -203,4 +523,118 @@ mod tests {
 			assert_eq!(fmt_big_num(pair.0), pair.1.to_string());
 		}
 	}
+
+	#[test]
+	fn test_dates() {
+		assert_eq!(parse_date("1970-01-01"), Some(0));
+		assert_eq!(parse_date("2000-02-29"), Some(951_782_400));
+		assert_eq!(parse_date("2023-12-31"), Some(1_703_980_800));
+		for bad in &["2023-02-29", "2023-13-01", "2023-1", "2023-01-01x", ""] {
+			assert_eq!(parse_date(bad), None);
+		}
+
+		assert_eq!(fmt_timestamp(0), "1970-01-01 00:00:00");
+		assert_eq!(fmt_timestamp(1_615_734_566), "2021-03-14 15:09:26");
+		assert_eq!(fmt_timestamp(-1), "1969-12-31 23:59:59");
+	}
+
+	#[test]
+	fn test_key_ranges() {
+		let key = |x, y, z| Vec3::new(x, y, z).to_block_key();
+
+		let area = Area::new(Vec3::new(-1, 5, -3), Vec3::new(2, 6, -2));
+		assert_eq!(get_key_ranges(Some(area), false), vec![
+			(key(-1, 5, -3), key(2, 5, -3)),
+			(key(-1, 6, -3), key(2, 6, -3)),
+			(key(-1, 5, -2), key(2, 5, -2)),
+			(key(-1, 6, -2), key(2, 6, -2)),
+		]);
+
+		// Rows spanning the entire X axis are merged.
+		let area = Area::new(Vec3::new(-2048, 0, 0), Vec3::new(2047, 2, 0));
+		assert_eq!(get_key_ranges(Some(area), false),
+			vec![(key(-2048, 0, 0), key(2047, 2, 0))]);
+
+		// Too many rows; fall back to a single range.
+		let area =
+			Area::new(Vec3::new(0, -1000, -1000), Vec3::new(0, 1000, 1000));
+		assert_eq!(get_key_ranges(Some(area), false),
+			vec![(key(0, -1000, -1000), key(0, 1000, 1000))]);
+
+		let full = vec![(i64::MIN, i64::MAX)];
+		assert_eq!(get_key_ranges(Some(area), true), full);
+		assert_eq!(get_key_ranges(None, false), full);
+	}
+
+	#[test]
+	fn test_query_blocks() {
+		let conn = create_test_map();
+		let mut db = MapDatabase::new(&conn).unwrap();
+		let v28 = read_test_file("mapblock_v28.bin");
+		let v29 = read_test_file("mapblock_v29.bin");
+		// (1, 0, 0) is outside the area but inside the fallback key range.
+		for (pos, data) in &[((0, 0, 0), &v28), ((0, 1, 1), &v29),
+			((1, 0, 0), &v28), ((0, 0, 2000), &v28)]
+		{
+			let key = Vec3::new(pos.0, pos.1, pos.2).to_block_key();
+			db.set_block(key, data).unwrap();
+		}
+		// Too many rows for exact key ranges.
+		let area = Area::new(Vec3::new(0, -16000, -16000),
+			Vec3::new(15, 16015, 16015));
+
+		let progress = TestProgress::default();
+		let count = |search_strs: &[Vec<u8>], invert| {
+			let blocks: Vec<_> = query_blocks(&db, None, &progress,
+				search_strs, Some(area), invert, true).collect();
+			let total = progress.total.load(Ordering::Relaxed);
+			let done = progress.done.swap(0, Ordering::Relaxed);
+			// Skipped blocks are counted as done.
+			assert_eq!(blocks.len() + done, total);
+			blocks.len()
+		};
+
+		assert_eq!(count(&[], false), 2);
+		assert_eq!(count(&[], true), 2);
+		// The v28 block doesn't contain the node, but v29 blocks aren't
+		// searched.
+		assert_eq!(count(&[b"test_mod:metadata".to_vec()], false), 1);
+		assert_eq!(count(&[b"test_mod:timer".to_vec()], false), 2);
+		// Search strings must be prefixed by their length.
+		assert_eq!(count(&[b"mod:timer".to_vec()], false), 1);
+	}
+
+	#[test]
+	fn test_query_blocks_index() {
+		let map_conn = create_test_map();
+		let mut db = MapDatabase::new(&map_conn).unwrap();
+		let index_conn = sqlite::open(":memory:").unwrap();
+		let mut index = BlockIndex::new(&index_conn).unwrap();
+		let v28 = read_test_file("mapblock_v28.bin");
+		let v29 = read_test_file("mapblock_v29.bin");
+		db.set_block(0, &v28).unwrap();
+		db.set_block(1, &v29).unwrap();
+
+		fn query<'a>(db: &MapDatabase<'a>, index: &mut BlockIndex<'a>,
+			name: &[u8]) -> Vec<i64>
+		{
+			let progress = TestProgress::default();
+			let names = [name.to_vec()];
+			query_blocks(db, Some(index), &progress, &names, None, false, true)
+				.map(|(key, _)| key).collect()
+		}
+
+		assert_eq!(query(&db, &mut index, b"test_mod:timer"), [0, 1]);
+		assert_eq!(query(&db, &mut index, b"test_mod:metadata"), [1]);
+		// The v29 block is skipped without being decompressed.
+		assert!(query(&db, &mut index, b"test_mod:stone").is_empty());
+
+		// A changed mapblock has a stale entry, which is replaced.
+		let mut block = MapBlock::deserialize(&v29).unwrap();
+		let id = block.nimap.get_id(b"test_mod:timer").unwrap();
+		block.nimap.0.insert(id, b"test_mod:stone".to_vec());
+		db.set_block(1, &block.serialize(CompressionLevel::Default)).unwrap();
+		assert_eq!(query(&db, &mut index, b"test_mod:stone"), [1]);
+		assert_eq!(query(&db, &mut index, b"test_mod:timer"), [0]);
+	}
 }
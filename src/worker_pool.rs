@@ -0,0 +1,155 @@
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use crate::instance::Progress;
+use crate::map_database::{MapDatabase, DBError};
+
+
+/// What to do with a mapblock after a worker has processed it.
+pub enum BlockAction {
+	/// Leave the mapblock unchanged.
+	Keep,
+	/// Replace the mapblock with new data.
+	Write(Vec<u8>),
+	/// Delete the mapblock.
+	Delete,
+	/// The mapblock is invalid or unsupported and was skipped.
+	Fail,
+}
+
+
+fn apply_action(
+	db: &mut MapDatabase,
+	status: &dyn Progress,
+	key: i64,
+	action: BlockAction
+) -> Result<(), DBError> {
+	status.inc_done();
+	match action {
+		BlockAction::Keep => {},
+		BlockAction::Write(data) => db.set_block(key, &data)?,
+		BlockAction::Delete => db.delete_block(key)?,
+		BlockAction::Fail => status.inc_failed(),
+	}
+	Ok(())
+}
+
+
+/// Process mapblocks in parallel.
+///
+/// The calling thread takes each mapblock from `blocks` and hands it to one
+/// of `threads` workers, which run `func` on the mapblock's key and data. The
+/// resulting actions are applied to the database back on the calling thread,
+/// so the database is only ever accessed from one thread. Mapblocks may finish
+/// in any order. If writing to the database fails, no more mapblocks are
+/// processed and the error is returned.
+pub fn process_blocks<I, F>(
+	db: &mut MapDatabase,
+	status: &dyn Progress,
+	threads: usize,
+	blocks: I,
+	func: F
+) -> Result<(), DBError>
+	where I: IntoIterator<Item = (i64, Vec<u8>)>,
+	F: Fn(i64, &[u8]) -> BlockAction + Sync
+{
+	let threads = threads.max(1);
+	// Limit the number of mapblocks held in memory at once.
+	let max_pending = threads * 16;
+
+	let (job_tx, job_rx) = mpsc::channel::<(i64, Vec<u8>)>();
+	let job_rx = Mutex::new(job_rx);
+	let (result_tx, result_rx) = mpsc::channel();
+
+	thread::scope(|scope| {
+		for _ in 0..threads {
+			let job_rx = &job_rx;
+			let result_tx = result_tx.clone();
+			let func = &func;
+
+			scope.spawn(move || loop {
+				let job = job_rx.lock().unwrap().recv();
+				let (key, data) = match job {
+					Ok(j) => j,
+					Err(_) => break // No more mapblocks.
+				};
+				result_tx.send((key, func(key, &data))).unwrap();
+			});
+		}
+		drop(result_tx);
+
+		let mut pending = 0;
+		let mut res = Ok(());
+		for (key, data) in blocks {
+			if pending >= max_pending {
+				let (k, action) = result_rx.recv().unwrap();
+				res = apply_action(db, status, k, action);
+				pending -= 1;
+			}
+			while res.is_ok() {
+				let (k, action) = match result_rx.try_recv() {
+					Ok(r) => r,
+					Err(_) => break
+				};
+				res = apply_action(db, status, k, action);
+				pending -= 1;
+			}
+			if res.is_err() {
+				break;
+			}
+
+			job_tx.send((key, data)).unwrap();
+			pending += 1;
+		}
+		drop(job_tx);
+
+		for (k, action) in result_rx {
+			if res.is_ok() {
+				res = apply_action(db, status, k, action);
+			}
+		}
+		res
+	})
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_process_blocks() {
+		let conn = sqlite::open(":memory:").unwrap();
+		conn.execute("CREATE TABLE blocks (pos INT PRIMARY KEY, data BLOB)")
+			.unwrap();
+		let mut db = MapDatabase::new(&conn).unwrap();
+		for key in 0..1000 {
+			db.set_block(key, &[(key % 256) as u8]).unwrap();
+		}
+
+		let (status, client) = crate::instance::status_link();
+		let blocks: Vec<_> = (0..1000)
+			.map(|key| (key, db.get_block(key).unwrap())).collect();
+		process_blocks(&mut db, &status, 4, blocks, |key, data| {
+			assert_eq!(data, &[(key % 256) as u8]);
+			match key % 4 {
+				0 => BlockAction::Keep,
+				1 => BlockAction::Write(vec![0xFF]),
+				2 => BlockAction::Delete,
+				_ => BlockAction::Fail,
+			}
+		}).unwrap();
+
+		let result = client.get_status();
+		assert_eq!(result.blocks_done, 1000);
+		assert_eq!(result.blocks_failed, 250);
+		for key in 0..1000 {
+			let expected = match key % 4 {
+				1 => Some(vec![0xFF]),
+				2 => None,
+				_ => Some(vec![(key % 256) as u8]),
+			};
+			assert_eq!(db.get_block(key).ok(), expected);
+		}
+	}
+}